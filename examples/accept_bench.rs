@@ -0,0 +1,61 @@
+// Compares the accept modes: starts a server in each, has |clients| threads
+// send requests to it for a few seconds, each over a fresh connection, and
+// prints the requests served per second. Run with
+//
+//     cargo run --release --example accept_bench -- [workers] [clients] [secs]
+
+use std::{
+    env,
+    io::{Read, Write},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use codecrafters_http_server::{AcceptMode, Config, Context, Request, Response, Server};
+
+fn run(mode: AcceptMode, workers: usize, clients: usize, duration: Duration) -> f64 {
+    let config = Config { accept_mode: mode, workers, ..Config::default() };
+    let server = Arc::new(Server::start(config, |_ctx: &Context, _req: Request| {
+        Ok(Response::plain_text("ok".to_string()))
+    }));
+    let server2 = Arc::clone(&server);
+    let listening = thread::spawn(move || server2.listen_forever());
+
+    let (done, served) = (AtomicBool::new(false), AtomicUsize::new(0));
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for _ in 0..clients {
+            scope.spawn(|| {
+                let mut buf = Vec::new();
+                while !done.load(Ordering::Relaxed) {
+                    let Ok(mut stream) = TcpStream::connect(server.addr()) else { continue };
+                    let req = b"GET / HTTP/1.1\r\nconnection: close\r\n\r\n";
+                    buf.clear();
+                    if stream.write_all(req).and_then(|_| stream.read_to_end(&mut buf)).is_ok() {
+                        served.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+        thread::sleep(duration);
+        done.store(true, Ordering::Relaxed);
+    });
+    let rate = served.into_inner() as f64 / start.elapsed().as_secs_f64();
+    server.stop();
+    listening.join().unwrap().unwrap();
+    rate
+}
+
+fn main() {
+    let arg = |i: usize, default: usize| env::args().nth(i).map_or(default, |a| a.parse().unwrap());
+    let (workers, clients, secs) = (arg(1, 4), arg(2, 8), arg(3, 5));
+    for mode in [AcceptMode::Shared, AcceptMode::PerWorker] {
+        let rate = run(mode, workers, clients, Duration::from_secs(secs as u64));
+        println!("{:?}: {:.0} requests/s", mode, rate);
+    }
+}
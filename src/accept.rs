@@ -0,0 +1,141 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::chaos::roll;
+
+const MIN_DELAY: Duration = Duration::from_millis(5);
+const MAX_DELAY: Duration = Duration::from_secs(1);
+
+// Spaces out accepts after failures such as running out of file descriptors,
+// which otherwise repeat as fast as every acceptor can spin and starve the
+// workers of CPU. The backoff is shared, and while it lasts only |probes|
+// acceptors at a time try again, so a burst of failures doesn't wake them
+// all. A probe only takes a connection that's already queued, so one whose
+// listener is idle gives its turn up rather than blocking while the others'
+// queues fill. Sleeps are jittered so acceptors don't retry in lockstep, and
+// each success halves the backoff rather than ending it, so the acceptors
+// come back gradually instead of all at once.
+pub(crate) struct AcceptBackoff {
+    // zero while accepts succeed
+    delay: Mutex<Duration>,
+    probes: usize,
+    probing: AtomicUsize,
+}
+
+// Held by an acceptor retrying during a backoff.
+pub(crate) struct Probe<'b>(&'b AcceptBackoff);
+
+impl Drop for Probe<'_> {
+    fn drop(&mut self) {
+        self.0.probing.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// somewhere between half and all of |delay|
+fn jitter(delay: Duration) -> Duration {
+    delay.mul_f64(0.5 + roll() / 2.0)
+}
+
+impl AcceptBackoff {
+    pub(crate) fn new(probes: usize) -> Self {
+        Self {
+            delay: Mutex::new(Duration::ZERO),
+            probes: probes.max(1),
+            probing: AtomicUsize::new(0),
+        }
+    }
+
+    fn delay(&self) -> Duration {
+        *self.delay.lock().unwrap()
+    }
+
+    fn try_probe(&self) -> Option<Probe<'_>> {
+        self.probing
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                (n < self.probes).then_some(n + 1)
+            })
+            .ok()?;
+        Some(Probe(self))
+    }
+
+    // Waits until the caller may accept; during a backoff it must hold on to
+    // the returned probe until its accept returns.
+    pub(crate) fn wait(&self) -> Option<Probe<'_>> {
+        loop {
+            let delay = self.delay();
+            if delay.is_zero() {
+                return None;
+            }
+            if let Some(probe) = self.try_probe() {
+                return Some(probe);
+            }
+            thread::sleep(jitter(delay));
+        }
+    }
+
+    // Sleeps for the backoff after a probe found no connection waiting.
+    pub(crate) fn idle(&self) {
+        thread::sleep(jitter(self.delay()));
+    }
+
+    // Records a failed accept and sleeps for the lengthened backoff.
+    pub(crate) fn failed(&self) {
+        let delay = {
+            let mut delay = self.delay.lock().unwrap();
+            *delay = (*delay * 2).clamp(MIN_DELAY, MAX_DELAY);
+            *delay
+        };
+        thread::sleep(jitter(delay));
+    }
+
+    pub(crate) fn succeeded(&self) {
+        let mut delay = self.delay.lock().unwrap();
+        *delay /= 2;
+        if *delay < MIN_DELAY {
+            *delay = Duration::ZERO;
+        }
+    }
+
+    // Ends any backoff, e.g. when the server restarts.
+    pub(crate) fn reset(&self) {
+        *self.delay.lock().unwrap() = Duration::ZERO;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let backoff = AcceptBackoff::new(1);
+        assert!(backoff.wait().is_none());
+        backoff.failed();
+        assert_eq!(backoff.delay(), MIN_DELAY);
+        backoff.failed();
+        assert_eq!(backoff.delay(), MIN_DELAY * 2);
+
+        // one acceptor probes, the rest wait for it
+        let probe = backoff.wait().unwrap();
+        assert!(backoff.try_probe().is_none());
+        drop(probe);
+        assert!(backoff.try_probe().is_some());
+
+        // successes wind the backoff down a step at a time
+        backoff.succeeded();
+        assert_eq!(backoff.delay(), MIN_DELAY);
+        assert!(backoff.wait().is_some());
+        backoff.succeeded();
+        assert!(backoff.wait().is_none());
+        backoff.failed();
+        backoff.reset();
+        assert!(backoff.wait().is_none());
+        assert!(jitter(MAX_DELAY) <= MAX_DELAY && jitter(MAX_DELAY) >= MAX_DELAY / 2);
+    }
+}
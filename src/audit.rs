@@ -0,0 +1,155 @@
+use std::{
+    cell::RefCell,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::{Mutex, PoisonError},
+    time::SystemTime,
+};
+
+use crate::{http_date, Authorization, Context, Handler, HttpError, HttpStatus, Request, Response};
+
+// a changed file's path and size, if it still has one
+type Change = (String, Option<u64>);
+
+thread_local! {
+    // the files changed by the audited request this thread is handling, see
+    // file_changed
+    static CHANGED: RefCell<Option<Vec<Change>>> = const { RefCell::new(None) };
+}
+
+// Append-only record of operations that change files, kept apart from the
+// access log. Each line is a set of key="value" pairs.
+pub struct AuditLog {
+    file: Mutex<File>,
+    // flush every entry to disk before the response goes out
+    fsync: bool,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path, fsync: bool) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file: Mutex::new(file), fsync })
+    }
+
+    pub fn record(&self, req: &AuditedRequest, status: HttpStatus) {
+        let mut line = format!(
+            "time={:?} method={:?} path={:?}",
+            http_date(SystemTime::now()),
+            req.method,
+            req.path
+        );
+        if let Some(client) = &req.client {
+            line.push_str(&format!(" client={:?}", client));
+        }
+        if let Some(user) = &req.user {
+            line.push_str(&format!(" user={:?}", user));
+        }
+        if let Some(size) = req.size {
+            line.push_str(&format!(" size={}", size));
+        }
+        line.push_str(&format!(" status={}", status.code()));
+        // a panic mid-write loses at most part of a line
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut result = writeln!(file, "{}", line);
+        if self.fsync {
+            result = result.and_then(|_| file.sync_data());
+        }
+        if let Err(err) = result {
+            eprintln!("failed to write audit log: {}", err);
+        }
+    }
+}
+
+// What the audit log records about a request, taken before the handler
+// consumes it.
+#[derive(Debug, Clone)]
+pub struct AuditedRequest {
+    pub method: String,
+    pub path: String,
+    pub client: Option<String>,
+    // the Basic auth user, if any
+    pub user: Option<String>,
+    // the declared body size; chunked uploads have none
+    pub size: Option<u64>,
+}
+
+impl AuditedRequest {
+    pub fn new(req: &Request) -> Self {
+        let user = match req.authorization() {
+            Some(Authorization::Basic { user, .. }) => Some(user),
+            _ => None,
+        };
+        Self {
+            method: req.method.to_string(),
+            path: req.path.clone(),
+            client: req.peer_addr.map(|addr| addr.ip().to_string()),
+            user,
+            size: req.get_header("content-length").and_then(|v| v.parse().ok()),
+        }
+    }
+}
+
+struct Audited(Box<dyn Handler>);
+
+impl Handler for Audited {
+    fn handle(&self, ctx: &Context, req: Request) -> Result<Response, HttpError> {
+        let Some(audit) = &ctx.audit else {
+            return self.0.handle(ctx, req);
+        };
+        let audited = AuditedRequest::new(&req);
+        let changes = Changes::start();
+        let result = self.0.handle(ctx, req);
+        let status = match &result {
+            Ok(resp) => resp.status,
+            Err(HttpError(status)) => *status,
+        };
+        let changed = changes.take();
+        if changed.is_empty() {
+            audit.record(&audited, status);
+        }
+        for (path, size) in changed {
+            audit.record(&AuditedRequest { path, size, ..audited.clone() }, status);
+        }
+        result
+    }
+}
+
+// Collects the files changed on this thread until dropped, even if the
+// handler panics.
+struct Changes;
+
+impl Changes {
+    fn start() -> Self {
+        CHANGED.with(|changed| *changed.borrow_mut() = Some(Vec::new()));
+        Self
+    }
+
+    fn take(self) -> Vec<Change> {
+        CHANGED.with(|changed| changed.borrow_mut().take()).unwrap_or_default()
+    }
+}
+
+impl Drop for Changes {
+    fn drop(&mut self) {
+        CHANGED.with(|changed| changed.borrow_mut().take());
+    }
+}
+
+// Notes that the audited request being handled on this thread, if any,
+// changed the file at |path|, see Context::file_changed.
+pub(crate) fn file_changed(path: &str, size: Option<u64>) {
+    CHANGED.with(|changed| {
+        if let Some(changed) = changed.borrow_mut().as_mut() {
+            changed.push((path.to_owned(), size));
+        }
+    });
+}
+
+// Wraps |handler| so that every request it serves, successful or not, is
+// written to the audit log when one is configured. A request that reports
+// changing files through Context::file_changed gets a record for each, with
+// the file's path and size, e.g. every part of a multipart upload.
+pub fn audited<H: Into<Box<dyn Handler>>>(handler: H) -> impl Handler {
+    Audited(handler.into())
+}
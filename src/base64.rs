@@ -0,0 +1,56 @@
+// Base64 with the standard alphabet, for Basic credentials and binary bodies
+// in the debug routes' JSON.
+
+const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// padded with =
+pub fn encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().fold(0u32, |n, b| n << 8 | *b as u32) << (8 * (3 - chunk.len()));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+// padding optional
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+    let digit = |c: u8| ALPHABET.iter().position(|&a| a == c).map(|i| i as u8);
+    let s = s.trim_end_matches('=');
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in s.bytes() {
+        acc = acc << 6 | digit(c)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(encode(b""), "");
+        assert_eq!(encode(b"f"), "Zg==");
+        assert_eq!(encode(b"fo"), "Zm8=");
+        assert_eq!(encode(b"foo"), "Zm9v");
+        assert_eq!(encode(&[0xff, 0xfe, 0x00, 0x01]), "//4AAQ==");
+        for data in [&b""[..], b"f", b"fo", b"foo", &[0xff, 0xfe, 0x00, 0x01]] {
+            assert_eq!(decode(&encode(data)).unwrap(), data);
+        }
+        assert_eq!(decode("Zm8").unwrap(), b"fo");
+        assert!(decode("Zm8*").is_none());
+    }
+}
@@ -0,0 +1,83 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::Duration,
+};
+
+use regex::Regex;
+
+use crate::{HttpStatus, Middleware, MiddlewareError, MiddlewareFactory, Request, Response};
+
+// 64 unpredictable bits from the std hasher's random keys, without pulling
+// in rand; not for cryptography
+pub(crate) fn random() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut h = RandomState::new().build_hasher();
+    h.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    h.finish()
+}
+
+// uniform in [0, 1), good enough for picking victims
+pub(crate) fn roll() -> f64 {
+    (random() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Fault {
+    // only delay the request
+    None,
+    // answer with a 500 instead of the handler's response
+    Error,
+    // close the connection without responding
+    Drop,
+}
+
+// Injects faults into a fraction of the requests whose path matches |routes|,
+// for testing how clients deal with slow or failing servers.
+pub struct ChaosFactory {
+    rate: f64,
+    routes: Regex,
+    delay: Duration,
+    fault: Fault,
+}
+
+impl ChaosFactory {
+    pub fn new(rate: f64, routes: Regex, delay: Duration, fault: Fault) -> Self {
+        Self { rate, routes, delay, fault }
+    }
+}
+
+impl MiddlewareFactory for ChaosFactory {
+    fn new(&self, req: &Request) -> Option<Box<dyn Middleware>> {
+        if !self.routes.is_match(&req.path) || roll() >= self.rate {
+            return None;
+        }
+        Some(Box::new(Chaos { delay: self.delay, fault: self.fault }))
+    }
+}
+
+pub struct Chaos {
+    delay: Duration,
+    fault: Fault,
+}
+
+impl Middleware for Chaos {
+    fn apply_before(&self, _req: &mut Request) -> Result<(), MiddlewareError> {
+        thread::sleep(self.delay);
+        if self.fault == Fault::Drop {
+            Err(io::Error::new(io::ErrorKind::ConnectionAborted, "chaos: dropping connection"))?;
+        }
+        Ok(())
+    }
+
+    fn apply_after(&self, resp: &mut Response) -> Result<(), MiddlewareError> {
+        if self.fault == Fault::Error {
+            resp.status = HttpStatus::ServerError;
+            resp.body = None;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use reqwest::{
+    blocking::{Request, RequestBuilder, Response},
+    Method, Url,
+};
+
+use crate::{ForwardPolicy, MiddlewareError, Request as ServerRequest};
+
+// Hooks around outbound requests, like Middleware for the server's own:
+// apply_before can add headers or sign a request before it's sent, and
+// apply_after sees how it went, e.g. to record metrics.
+pub trait ClientMiddleware: Send + Sync {
+    fn apply_before(&self, req: &mut Request) -> Result<(), MiddlewareError>;
+    fn apply_after(&self, exchange: &Exchange);
+}
+
+// An outbound request that was sent, and its response or why there's none.
+pub struct Exchange<'a> {
+    pub method: &'a Method,
+    pub url: &'a Url,
+    pub elapsed: Duration,
+    pub result: Result<&'a Response, &'a reqwest::Error>,
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    // a middleware refused or failed to prepare the request
+    Middleware(MiddlewareError),
+    Send(reqwest::Error),
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Middleware(err) => write!(f, "{}", err),
+            Self::Send(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+// The client for the server's own outbound traffic, such as webhook
+// deliveries, which runs each request through its middleware in order.
+pub struct Client {
+    inner: reqwest::blocking::Client,
+    middleware: Vec<Box<dyn ClientMiddleware>>,
+    forward: ForwardPolicy,
+}
+
+impl Client {
+    pub fn new(inner: reqwest::blocking::Client) -> Self {
+        Self { inner, middleware: Vec::new(), forward: ForwardPolicy::default() }
+    }
+
+    // Which headers requests made with request_for copy from the inbound
+    // request; none unless allowed.
+    pub fn forward(mut self, policy: ForwardPolicy) -> Self {
+        self.forward = policy;
+        self
+    }
+
+    pub fn middleware<M: ClientMiddleware + 'static>(mut self, middleware: M) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    pub fn extend(mut self, middleware: Vec<Box<dyn ClientMiddleware>>) -> Self {
+        self.middleware.extend(middleware);
+        self
+    }
+
+    // The underlying client, for building requests to send.
+    pub fn inner(&self) -> &reqwest::blocking::Client {
+        &self.inner
+    }
+
+    // Starts a request made on behalf of |inbound|, e.g. by a proxy, with
+    // the inbound headers the forward policy allows.
+    pub fn request_for(
+        &self,
+        method: Method,
+        url: &str,
+        inbound: &ServerRequest,
+    ) -> RequestBuilder {
+        let mut builder = self.inner.request(method, url);
+        for (name, value) in self.forward.headers(inbound) {
+            builder = builder.header(name, value);
+        }
+        builder
+    }
+
+    pub fn send(&self, mut req: Request) -> Result<Response, ClientError> {
+        for m in &self.middleware {
+            m.apply_before(&mut req).map_err(ClientError::Middleware)?;
+        }
+        let (method, url) = (req.method().clone(), req.url().clone());
+        let start = Instant::now();
+        let result = self.inner.execute(req);
+        let exchange = Exchange {
+            method: &method,
+            url: &url,
+            elapsed: start.elapsed(),
+            result: result.as_ref(),
+        };
+        for m in &self.middleware {
+            m.apply_after(&exchange);
+        }
+        result.map_err(ClientError::Send)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{HttpStatus, MockUpstream, Reply};
+
+    struct Auth(&'static str);
+
+    impl ClientMiddleware for Auth {
+        fn apply_before(&self, req: &mut Request) -> Result<(), MiddlewareError> {
+            if req.url().path() == "/forbidden" {
+                return Err(MiddlewareError::reject(HttpStatus::Forbidden));
+            }
+            req.headers_mut().insert("authorization", format!("Bearer {}", self.0).parse()?);
+            Ok(())
+        }
+
+        fn apply_after(&self, _exchange: &Exchange) {}
+    }
+
+    #[derive(Default)]
+    struct Record(Arc<Mutex<Vec<String>>>);
+
+    impl ClientMiddleware for Record {
+        fn apply_before(&self, _req: &mut Request) -> Result<(), MiddlewareError> {
+            Ok(())
+        }
+
+        fn apply_after(&self, exchange: &Exchange) {
+            let status = exchange.result.map(|resp| resp.status().as_u16());
+            let line = format!("{} {} {:?}", exchange.method, exchange.url.path(), status.ok());
+            self.0.lock().unwrap().push(line);
+        }
+    }
+
+    #[test]
+    fn test_client_middleware() {
+        let upstream =
+            MockUpstream::start(vec![Reply::ok("hi"), Reply::status(HttpStatus::NotFound)]);
+        let record = Record::default();
+        let log = Arc::clone(&record.0);
+        let client = Client::new(reqwest::blocking::Client::new())
+            .middleware(Auth("t0k"))
+            .middleware(record);
+        let get = |path: &str| client.inner().get(upstream.url(path)).build().unwrap();
+
+        assert_eq!(client.send(get("/a")).unwrap().text().unwrap(), "hi");
+        assert_eq!(client.send(get("/b")).unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+        assert!(matches!(client.send(get("/forbidden")), Err(ClientError::Middleware(_))));
+        let requests = upstream.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].get_header("authorization"), Some("Bearer t0k"));
+        // the refused request was never sent, so isn't recorded
+        assert_eq!(*log.lock().unwrap(), ["GET /a Some(200)", "GET /b Some(404)"]);
+    }
+
+    #[test]
+    fn test_connection_reuse() {
+        let upstream = MockUpstream::start((0..3).map(|_| Reply::ok("")).collect());
+        let client = Client::new(reqwest::blocking::Client::new());
+        for _ in 0..3 {
+            let resp = client.send(client.inner().get(upstream.url("/")).build().unwrap());
+            resp.unwrap().text().unwrap();
+        }
+        assert_eq!(upstream.connections(), 1);
+    }
+
+    #[test]
+    fn test_request_for() {
+        let upstream = MockUpstream::start(vec![Reply::ok("")]);
+        let raw = "GET / HTTP/1.1\r\nAuthorization: secret\r\nX-Request-Id: 7\r\n\
+                   Connection: x-request-id\r\nAccept: text/plain\r\n\r\n";
+        let mut reader = std::io::Cursor::new(raw.as_bytes().to_vec());
+        let inbound = crate::parse_request(&mut reader).unwrap();
+        let policy = ForwardPolicy::default().allow("accept").allow("x-request-id");
+        let client = Client::new(reqwest::blocking::Client::new()).forward(policy);
+        let req = client.request_for(Method::GET, &upstream.url("/"), &inbound).build().unwrap();
+        client.send(req).unwrap();
+
+        let sent = &upstream.requests()[0];
+        assert_eq!(sent.get_header("accept"), Some("text/plain"));
+        // listed in Connection, so for the inbound hop only
+        assert_eq!(sent.get_header("x-request-id"), None);
+        assert_eq!(sent.get_header("authorization"), None);
+    }
+}
@@ -1,40 +1,136 @@
 use std::{
-    collections::HashSet,
-    error::Error,
-    fmt::Display,
-    io::{Cursor, Read},
+    io::{self, Cursor, Read},
+    sync::Arc,
 };
 
-use flate2::read::GzEncoder;
+use flate2::{
+    bufread::{MultiGzDecoder, ZlibDecoder},
+    read, write,
+};
+
+use crate::{
+    quality, BodyWriter, HttpStatus, Middleware, MiddlewareError, MiddlewareFactory, Request,
+    Response, ResponseBody,
+};
 
-use crate::{Middleware, MiddlewareFactory, Request};
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Encoding {
+    Zstd,
+    Gzip,
+    // zlib-wrapped deflate, as RFC 9110 defines it
+    Deflate,
+}
 
-#[derive(Debug)]
-pub struct DecompressionError;
+impl Encoding {
+    // the default order of preference when the client accepts several equally
+    const ALL: [Encoding; 3] = [Encoding::Zstd, Encoding::Gzip, Encoding::Deflate];
 
-impl Display for DecompressionError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "failed to decompress")
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
     }
 }
 
-impl Error for DecompressionError {}
+// Already-compressed formats, which would only grow.
+pub const INCOMPRESSIBLE_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "audio/*",
+    "video/*",
+    "font/woff",
+    "font/woff2",
+    "application/gzip",
+    "application/zip",
+    "application/zstd",
+];
 
-pub struct CompressionFactory;
+// Which Content-Types get compressed: any in |allow|, or any at all when
+// it's empty, unless they're in |deny|. Entries are exact types or type/*,
+// compared ignoring case as configured patterns may not be lowercase.
+// Responses without a Content-Type are compressed unless |allow| is set.
+#[derive(Debug, Clone, Default)]
+pub struct TypePolicy {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+impl TypePolicy {
+    pub fn compresses(&self, content_type: Option<&str>) -> bool {
+        let Some(content_type) = content_type else {
+            return self.allow.is_empty();
+        };
+        let (mime, _params) = content_type.split_once(';').unwrap_or((content_type, ""));
+        let mime = mime.trim();
+        let matches = |pattern: &String| match pattern.trim().strip_suffix("/*") {
+            Some(kind) => mime.split_once('/').is_some_and(|(k, _)| k.eq_ignore_ascii_case(kind)),
+            None => pattern.trim().eq_ignore_ascii_case(mime),
+        };
+        (self.allow.is_empty() || self.allow.iter().any(matches)) && !self.deny.iter().any(matches)
+    }
+}
+
+// Compresses responses with the encoding the client prefers: gzip or deflate
+// at |level| from 0 (none) to 9 (smallest), or zstd at |zstd_level| from 1 to
+// 22. Only |encodings| are offered, with earlier ones preferred when the
+// client accepts several equally. Responses known to be shorter than
+// |min_bytes|, or whose type |types| excludes, are sent as they are.
+pub struct CompressionFactory {
+    pub encodings: Vec<Encoding>,
+    pub level: u32,
+    pub zstd_level: i32,
+    pub min_bytes: u64,
+    pub types: Arc<TypePolicy>,
+}
+
+impl Default for CompressionFactory {
+    fn default() -> Self {
+        Self {
+            encodings: Encoding::ALL.to_vec(),
+            level: flate2::Compression::fast().level(),
+            zstd_level: zstd::DEFAULT_COMPRESSION_LEVEL,
+            min_bytes: 0,
+            types: Arc::new(TypePolicy {
+                allow: Vec::new(),
+                deny: INCOMPRESSIBLE_TYPES.iter().map(|t| t.to_string()).collect(),
+            }),
+        }
+    }
+}
 
 impl MiddlewareFactory for CompressionFactory {
     fn new(&self, req: &Request) -> Option<Box<dyn Middleware>> {
-        let schemes: HashSet<&str> = req.get_header("accept-encoding")?.split(", ").collect();
-        if schemes.contains("gzip") {
-            println!("enabling gzip");
-            Some(Box::new(Compression))
-        } else {
-            None
+        let accepted = req.accept_encoding();
+        let mut best: Option<(Encoding, f32)> = None;
+        for &encoding in &self.encodings {
+            let q = quality(&accepted, encoding.name());
+            if q > best.map_or(0.0, |(_, best)| best) {
+                best = Some((encoding, q));
+            }
         }
+        let (encoding, _) = best?;
+        println!("enabling {}", encoding.name());
+        Some(Box::new(Compression {
+            encoding,
+            level: self.level,
+            zstd_level: self.zstd_level,
+            min_bytes: self.min_bytes,
+            types: Arc::clone(&self.types),
+        }))
     }
 }
 
-pub struct Compression;
+pub struct Compression {
+    encoding: Encoding,
+    level: u32,
+    zstd_level: i32,
+    min_bytes: u64,
+    types: Arc<TypePolicy>,
+}
 
 impl Middleware for Compression {
     fn apply_before(&self, _req: &mut Request) -> Result<(), crate::MiddlewareError> {
@@ -43,14 +139,401 @@ impl Middleware for Compression {
     }
 
     fn apply_after(&self, resp: &mut crate::Response) -> Result<(), crate::MiddlewareError> {
-        resp.set_header("content-encoding".to_string(), "gzip".to_string());
-        if let Some(data) = resp.body.take() {
-            let mut e = GzEncoder::new(data, flate2::Compression::fast());
-            let mut buf = Vec::new();
-            let size = e.read_to_end(&mut buf)?;
-            resp.set_header("content-length".to_string(), size.to_string());
-            resp.body = Some(Box::new(Cursor::new(buf)));
+        // encoding tiny bodies costs more than it saves, and can grow them;
+        // streamed ones of unknown length are always compressed
+        let size = resp.get_header("content-length").and_then(|size| size.parse::<u64>().ok());
+        // a range is a slice of the unencoded content, which it must stay; a
+        // 304 has no body and must match the headers of what the client has;
+        // and a body that's already encoded would be encoded twice
+        if resp.status == HttpStatus::PartialContent
+            || resp.status == HttpStatus::NotModified
+            || resp.get_header("content-encoding").is_some()
+            || size.is_some_and(|size| size < self.min_bytes)
+            || !self.types.compresses(resp.get_header("content-type"))
+        {
+            return Ok(());
         }
+        resp.set_header("content-encoding".to_string(), self.encoding.name().to_string());
+        vary_on_encoding(resp);
+        resp.body = resp.body.take().map(|body| self.encode(body)).transpose()?;
+        if resp.body.is_some() {
+            // encoded as it's sent, so the length isn't known up front
+            resp.remove_header("content-length");
+        }
+        Ok(())
+    }
+}
+
+// Tells caches the response depends on Accept-Encoding, so they don't hand
+// an encoded body to a client that can't decode it.
+fn vary_on_encoding(resp: &mut Response) {
+    let vary = resp.get_header("vary").unwrap_or_default();
+    if vary.split(',').any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case("accept-encoding"))
+    {
+        return;
+    }
+    let vary = match vary {
+        "" => "Accept-Encoding".to_string(),
+        vary => format!("{}, Accept-Encoding", vary),
+    };
+    resp.set_header("vary".to_string(), vary);
+}
+
+impl Compression {
+    // A body that encodes |body| while it's written out, so it's never held
+    // in memory whole.
+    fn encode(&self, body: ResponseBody) -> io::Result<ResponseBody> {
+        let data: Box<dyn Read> = match body {
+            ResponseBody::Bytes(data) => Box::new(Cursor::new(data)),
+            ResponseBody::File(file) => Box::new(file),
+            ResponseBody::Reader(data) => data,
+            ResponseBody::Writer(write) => return Ok(self.encode_writer(write)),
+        };
+        let level = flate2::Compression::new(self.level);
+        Ok(ResponseBody::Reader(match self.encoding {
+            Encoding::Zstd => Box::new(zstd::stream::read::Encoder::new(data, self.zstd_level)?),
+            Encoding::Gzip => Box::new(read::GzEncoder::new(data, level)),
+            Encoding::Deflate => Box::new(read::ZlibEncoder::new(data, level)),
+        }))
+    }
+
+    fn encode_writer(&self, write: BodyWriter) -> ResponseBody {
+        let (encoding, zstd_level) = (self.encoding, self.zstd_level);
+        let level = flate2::Compression::new(self.level);
+        ResponseBody::Writer(Box::new(move |w| match encoding {
+            Encoding::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(w, zstd_level)?;
+                write(&mut encoder)?;
+                encoder.finish().map(|_| ())
+            }
+            Encoding::Gzip => {
+                let mut encoder = write::GzEncoder::new(w, level);
+                write(&mut encoder)?;
+                encoder.finish().map(|_| ())
+            }
+            Encoding::Deflate => {
+                let mut encoder = write::ZlibEncoder::new(w, level);
+                write(&mut encoder)?;
+                encoder.finish().map(|_| ())
+            }
+        }))
+    }
+}
+
+// Decodes request bodies sent with Content-Encoding gzip, deflate or zstd, so
+// handlers read them as the client meant them. Decoded bodies may be at most
+// |max_bytes|, since a tiny upload can inflate enormously; other codings are
+// answered with 415.
+pub struct DecompressionFactory {
+    pub max_bytes: u64,
+}
+
+impl MiddlewareFactory for DecompressionFactory {
+    fn new(&self, req: &Request) -> Option<Box<dyn Middleware>> {
+        req.get_header("content-encoding")?;
+        Some(Box::new(Decompression { max_bytes: self.max_bytes }))
+    }
+}
+
+pub struct Decompression {
+    max_bytes: u64,
+}
+
+impl Middleware for Decompression {
+    fn apply_before(&self, req: &mut Request) -> Result<(), MiddlewareError> {
+        let coding = req.get_header("content-encoding").unwrap_or_default().trim();
+        let coding = coding.to_ascii_lowercase();
+        let limit = self.max_bytes;
+        let decoded = match coding.as_str() {
+            "gzip" | "x-gzip" => req.body.decode(|body| Ok(MultiGzDecoder::new(body)), limit),
+            "deflate" => req.body.decode(|body| Ok(ZlibDecoder::new(body)), limit),
+            "zstd" => req.body.decode(zstd::stream::read::Decoder::with_buffer, limit),
+            "identity" => Ok(()),
+            _ => return Err(MiddlewareError::reject(HttpStatus::UnsupportedMediaType)),
+        };
+        // the body is still framed, so the connection can go on after a 400
+        if let Err(err) = decoded {
+            eprintln!("can't decode {} request body: {}", coding, err);
+            return Err(MiddlewareError::reject(HttpStatus::BadRequest));
+        }
+        // the declared length was the encoded one
+        req.remove_header("content-encoding");
+        req.remove_header("content-length");
+        Ok(())
+    }
+
+    fn apply_after(&self, _resp: &mut crate::Response) -> Result<(), crate::MiddlewareError> {
+        // do nothing
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+    use crate::parse_request;
+
+    #[test]
+    fn test_compression() {
+        let raw = b"GET / HTTP/1.1\r\nAccept-Encoding: br, gzip;q=0.5\r\n\r\n";
+        let mut reader = Cursor::new(raw.to_vec());
+        let mut req = parse_request(&mut reader).unwrap();
+        let middleware = CompressionFactory::default().new(&req).unwrap();
+        middleware.apply_before(&mut req).unwrap();
+
+        let mut resp = Response::plain_text("hello hello hello".to_string());
+        middleware.apply_after(&mut resp).unwrap();
+        assert_eq!(resp.get_header("content-encoding"), Some("gzip"));
+        assert_eq!(resp.get_header("vary"), Some("Accept-Encoding"));
+        // encoded as it's sent, so the length isn't known up front
+        assert_eq!(resp.get_header("content-length"), None);
+        let compressed = resp.body.take().unwrap().into_bytes().unwrap();
+        let mut body = String::new();
+        GzDecoder::new(&compressed[..]).read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hello hello hello");
+
+        let mut reader = Cursor::new(b"GET / HTTP/1.1\r\nAccept-Encoding: br\r\n\r\n".to_vec());
+        assert!(CompressionFactory::default().new(&parse_request(&mut reader).unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_zstd() {
+        let negotiate = |accept: &str| {
+            let raw = format!("GET / HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n", accept);
+            let mut reader = Cursor::new(raw.into_bytes());
+            let req = parse_request(&mut reader).unwrap();
+            let middleware = CompressionFactory::default().new(&req)?;
+            let mut resp = Response::plain_text("hello hello hello".to_string());
+            middleware.apply_after(&mut resp).unwrap();
+            Some(resp)
+        };
+        assert_eq!(
+            negotiate("gzip, zstd;q=0.5").unwrap().get_header("content-encoding"),
+            Some("gzip")
+        );
+        assert!(negotiate("br").is_none());
+
+        let mut resp = negotiate("gzip, zstd").unwrap();
+        assert_eq!(resp.get_header("content-encoding"), Some("zstd"));
+        assert_eq!(resp.get_header("content-length"), None);
+        let encoded = resp.body.take().unwrap().into_bytes().unwrap();
+        assert_eq!(zstd::decode_all(&encoded[..]).unwrap(), b"hello hello hello");
+
+        let mut resp = Response::from_writer(|w| w.write_all(b"written"));
+        let zstd = Compression {
+            encoding: Encoding::Zstd,
+            level: 1,
+            zstd_level: 3,
+            min_bytes: 0,
+            types: Arc::default(),
+        };
+        zstd.apply_after(&mut resp).unwrap();
+        let encoded = resp.body.unwrap().into_bytes().unwrap();
+        assert_eq!(zstd::decode_all(&encoded[..]).unwrap(), b"written");
+
+        for encoding in [Encoding::Gzip, Encoding::Deflate] {
+            let mut resp = Response::from_writer(|w| w.write_all(b"written"));
+            let compression = Compression { encoding, types: Arc::default(), ..zstd };
+            compression.apply_after(&mut resp).unwrap();
+            let encoded = resp.body.unwrap().into_bytes().unwrap();
+            let mut body = String::new();
+            match encoding {
+                Encoding::Gzip => GzDecoder::new(&encoded[..]).read_to_string(&mut body),
+                _ => flate2::read::ZlibDecoder::new(&encoded[..]).read_to_string(&mut body),
+            }
+            .unwrap();
+            assert_eq!(body, "written");
+        }
+    }
+
+    #[test]
+    fn test_deflate() {
+        let negotiate = |accept: &str| {
+            let raw = format!("GET / HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n", accept);
+            let mut reader = Cursor::new(raw.into_bytes());
+            let req = parse_request(&mut reader).unwrap();
+            let mut resp = Response::plain_text("hello hello hello".to_string());
+            CompressionFactory::default().new(&req).unwrap().apply_after(&mut resp).unwrap();
+            resp
+        };
+        assert_eq!(negotiate("deflate, gzip").get_header("content-encoding"), Some("gzip"));
+        assert_eq!(
+            negotiate("deflate, gzip;q=0.5").get_header("content-encoding"),
+            Some("deflate")
+        );
+
+        let raw = b"GET / HTTP/1.1\r\nAccept-Encoding: zstd, gzip, deflate\r\n\r\n";
+        let mut reader = Cursor::new(raw.to_vec());
+        let req = parse_request(&mut reader).unwrap();
+        let factory =
+            |encodings: Vec<Encoding>| CompressionFactory { encodings, ..Default::default() };
+        let chosen = |factory: CompressionFactory| {
+            let mut resp = Response::plain_text("hello hello hello");
+            factory.new(&req).unwrap().apply_after(&mut resp).unwrap();
+            resp.get_header("content-encoding").map(String::from)
+        };
+        assert_eq!(
+            chosen(factory(vec![Encoding::Deflate, Encoding::Gzip])).as_deref(),
+            Some("deflate")
+        );
+        assert_eq!(chosen(factory(vec![Encoding::Gzip])).as_deref(), Some("gzip"));
+        assert!(factory(Vec::new()).new(&req).is_none());
+
+        let mut resp = negotiate("deflate");
+        assert_eq!(resp.get_header("content-encoding"), Some("deflate"));
+        assert_eq!(resp.get_header("vary"), Some("Accept-Encoding"));
+        assert_eq!(resp.get_header("content-length"), None);
+        let encoded = resp.body.take().unwrap().into_bytes().unwrap();
+        let mut body = String::new();
+        flate2::read::ZlibDecoder::new(&encoded[..]).read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hello hello hello");
+    }
+
+    #[test]
+    fn test_min_bytes() {
+        let mut reader = Cursor::new(b"GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n".to_vec());
+        let req = parse_request(&mut reader).unwrap();
+        let factory = CompressionFactory { min_bytes: 1024, ..CompressionFactory::default() };
+        let compress = |resp: &mut Response| factory.new(&req).unwrap().apply_after(resp).unwrap();
+
+        let mut resp = Response::plain_text("tiny");
+        compress(&mut resp);
+        assert_eq!(resp.get_header("content-encoding"), None);
+        assert_eq!(resp.body.unwrap().into_bytes().unwrap(), b"tiny");
+
+        let mut resp = Response::plain_text("a".repeat(1024));
+        compress(&mut resp);
+        assert_eq!(resp.get_header("content-encoding"), Some("gzip"));
+
+        let mut resp = Response::from_writer(|w| w.write_all(b"tiny"));
+        compress(&mut resp);
+        assert_eq!(resp.get_header("content-encoding"), Some("gzip"));
+    }
+
+    #[test]
+    fn test_skipped_responses() {
+        let mut reader = Cursor::new(b"GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n".to_vec());
+        let req = parse_request(&mut reader).unwrap();
+        let middleware = CompressionFactory::default().new(&req).unwrap();
+        let compress = |mut resp: Response| {
+            middleware.apply_after(&mut resp).unwrap();
+            resp
+        };
+
+        let resp = compress(Response::builder().header("content-encoding", "br").body("encoded"));
+        assert_eq!(resp.get_header("content-encoding"), Some("br"));
+        assert_eq!(resp.body.unwrap().into_bytes().unwrap(), b"encoded");
+        let resp = compress(Response::builder().status(HttpStatus::NotModified).build());
+        assert_eq!(resp.get_header("content-encoding"), None);
+        assert_eq!(resp.get_header("vary"), None);
+        let resp = compress(Response::builder().status(HttpStatus::PartialContent).body("slice"));
+        assert_eq!(resp.get_header("content-encoding"), None);
+
+        let resp = compress(Response::builder().header("vary", "Origin").body("hello"));
+        assert_eq!(resp.get_header("vary"), Some("Origin, Accept-Encoding"));
+        let resp = compress(Response::builder().header("vary", "accept-encoding").body("hello"));
+        assert_eq!(resp.get_header("vary"), Some("accept-encoding"));
+    }
+
+    #[test]
+    fn test_type_policy() {
+        let policy = CompressionFactory::default().types;
+        assert!(policy.compresses(Some("text/html; charset=utf-8")));
+        assert!(policy.compresses(Some("image/svg+xml")));
+        assert!(policy.compresses(None));
+        assert!(!policy.compresses(Some("image/PNG")));
+        assert!(!policy.compresses(Some("video/mp4")));
+
+        let policy = TypePolicy { allow: vec!["Text/*".into()], deny: vec![" TEXT/CSV".into()] };
+        assert!(policy.compresses(Some("text/plain")));
+        assert!(!policy.compresses(Some("text/csv")));
+        assert!(!policy.compresses(Some("Text/CSV; charset=utf-8")));
+        assert!(!policy.compresses(Some("application/json")));
+        assert!(!policy.compresses(None));
+
+        let mut reader = Cursor::new(b"GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n".to_vec());
+        let req = parse_request(&mut reader).unwrap();
+        let mut resp = Response::builder().header("content-type", "image/png").body(&b"png"[..]);
+        CompressionFactory::default().new(&req).unwrap().apply_after(&mut resp).unwrap();
+        assert_eq!(resp.get_header("content-encoding"), None);
+        assert_eq!(resp.body.unwrap().into_bytes().unwrap(), b"png");
+    }
+
+    #[test]
+    fn test_decompression() {
+        let mut e = write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        e.write_all(&[b'a'; 5000]).unwrap();
+        let gzipped = e.finish().unwrap();
+        let upload = |coding: &str, next: &str| {
+            let mut raw = format!(
+                "POST / HTTP/1.1\r\nContent-Encoding: {}\r\nContent-Length: {}\r\n\r\n",
+                coding,
+                gzipped.len()
+            )
+            .into_bytes();
+            raw.extend_from_slice(&gzipped);
+            raw.extend_from_slice(next.as_bytes());
+            Cursor::new(raw)
+        };
+        let decompress = |req: &mut Request, max_bytes| {
+            let factory = DecompressionFactory { max_bytes };
+            factory.new(req).unwrap().apply_before(req).map_err(|err| err.status())
+        };
+
+        let mut reader = upload("gzip", "GET /next HTTP/1.1\r\n\r\n");
+        let mut req = parse_request(&mut reader).unwrap();
+        decompress(&mut req, 1 << 20).unwrap();
+        assert_eq!(req.get_header("content-encoding"), None);
+        assert_eq!(req.get_header("content-length"), None);
+        let mut start = [0; 10];
+        req.body.read_exact(&mut start).unwrap();
+        assert_eq!(start, [b'a'; 10]);
+        // the rest of the encoded body is skipped for the next request
+        drop(req);
+        assert_eq!(parse_request(&mut reader).unwrap().path, "/next");
+
+        let mut reader = upload("gzip", "");
+        let mut req = parse_request(&mut reader).unwrap();
+        decompress(&mut req, 4096).unwrap();
+        assert!(matches!(req.bytes(), Err(crate::HttpError(HttpStatus::ContentTooLarge))));
+
+        let mut reader = upload("br", "");
+        let mut req = parse_request(&mut reader).unwrap();
+        assert_eq!(
+            decompress(&mut req, 1 << 20).unwrap_err(),
+            Some(HttpStatus::UnsupportedMediaType)
+        );
+
+        let mut reader = Cursor::new(b"POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi".to_vec());
+        let req = parse_request(&mut reader).unwrap();
+        assert!(DecompressionFactory { max_bytes: 1 }.new(&req).is_none());
+    }
+
+    #[test]
+    fn test_decompression_codings() {
+        let text = b"hello hello hello";
+        let mut e = write::ZlibEncoder::new(Vec::new(), flate2::Compression::fast());
+        e.write_all(text).unwrap();
+        for (coding, body) in
+            [("deflate", e.finish().unwrap()), ("zstd", zstd::encode_all(&text[..], 3).unwrap())]
+        {
+            let mut raw = format!(
+                "POST / HTTP/1.1\r\nContent-Encoding: {}\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n",
+                coding,
+                body.len()
+            )
+            .into_bytes();
+            raw.extend_from_slice(&body);
+            raw.extend_from_slice(b"\r\n0\r\n\r\n");
+            let mut reader = Cursor::new(raw);
+            let mut req = parse_request(&mut reader).unwrap();
+            let middleware = DecompressionFactory { max_bytes: 1 << 20 }.new(&req).unwrap();
+            middleware.apply_before(&mut req).unwrap();
+            assert_eq!(&req.bytes().unwrap()[..], text, "{}", coding);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use crate::{
+    EntityTag, HttpStatus, IfNoneMatch, Method, Middleware, MiddlewareError, MiddlewareFactory,
+    Request, Response,
+};
+
+pub struct ConditionalFactory;
+
+impl MiddlewareFactory for ConditionalFactory {
+    fn new(&self, req: &Request) -> Option<Box<dyn Middleware>> {
+        if req.method != Method::Get && req.method != Method::Head {
+            return None;
+        }
+        Some(Box::new(Conditional(req.if_none_match()?)))
+    }
+}
+
+// Answers GET and HEAD requests with 304 Not Modified when the response's
+// ETag matches If-None-Match, which takes precedence over any Range.
+pub struct Conditional(IfNoneMatch);
+
+impl Middleware for Conditional {
+    fn apply_before(&self, _req: &mut Request) -> Result<(), MiddlewareError> {
+        // do nothing
+        Ok(())
+    }
+
+    fn apply_after(&self, resp: &mut Response) -> Result<(), MiddlewareError> {
+        if resp.status != HttpStatus::OK {
+            return Ok(());
+        }
+        let Some(etag) = resp.get_header("etag").and_then(EntityTag::parse) else {
+            return Ok(());
+        };
+        if self.0.matches(&etag) {
+            resp.status = HttpStatus::NotModified;
+            resp.body = None;
+            resp.remove_header("content-length");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,229 @@
+use std::{error::Error, fmt::Display, fs, ops::RangeInclusive, path::Path};
+
+use clap::ValueEnum;
+use toml::{Table, Value};
+
+use crate::mime::mime_override;
+
+// What a --config file declares, in TOML. Each [middleware.NAME] section
+// adds that middleware to the chain, in the order they're written, with its
+// settings:
+//
+//     [middleware.trace]
+//
+//     [middleware.compression]
+//     level = 6
+//     algos = ["zstd", "gzip"]
+//
+// Settings a section leaves out keep their flag's value, e.g.
+// --compression-level. Without any middleware sections, --middleware picks
+// the chain. The [mime_types] section adds to the content types files are
+// served with, after any --mime-type flags:
+//
+//     [mime_types]
+//     mjs = "text/javascript"
+//     txt = "text/plain; charset=utf-8"
+#[derive(Debug, Clone, Default)]
+pub struct ConfigFile {
+    pub middleware: Vec<Settings>,
+    // extensions without the dot, and their content types
+    pub mime_types: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError(pub String);
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid config: {}", self.0)
+    }
+}
+
+impl Error for ConfigError {}
+
+impl ConfigFile {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| ConfigError(format!("{}: {}", path.display(), err)))?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let mut table: Table =
+            text.parse().map_err(|err: toml::de::Error| ConfigError(err.message().to_string()))?;
+        let mut file = Self::default();
+        match table.remove("middleware") {
+            None => {}
+            Some(Value::Table(sections)) => {
+                for (name, settings) in sections {
+                    let Value::Table(table) = settings else {
+                        return Err(ConfigError(format!("middleware.{} isn't a section", name)));
+                    };
+                    file.middleware.push(Settings { name, table });
+                }
+            }
+            Some(_) => return Err(ConfigError("middleware isn't a section".to_string())),
+        }
+        match table.remove("mime_types") {
+            None => {}
+            Some(Value::Table(types)) => {
+                for (ext, mime) in types {
+                    let mime = mime.as_str().unwrap_or_default();
+                    file.mime_types.push(mime_override(&ext, mime).map_err(ConfigError)?);
+                }
+            }
+            Some(_) => return Err(ConfigError("mime_types isn't a section".to_string())),
+        }
+        if let Some(key) = table.keys().next() {
+            return Err(ConfigError(format!("unknown setting {}", key)));
+        }
+        Ok(file)
+    }
+}
+
+// One middleware's section of the config file. The getters return None for
+// settings that aren't given, and an error for ones of the wrong type.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub name: String,
+    table: Table,
+}
+
+impl Settings {
+    // No settings, for a middleware named with --middleware.
+    pub fn new(name: &str) -> Self {
+        Self { name: name.to_string(), table: Table::new() }
+    }
+
+    // Refuses any setting besides |known|, which is likely a typo.
+    pub fn only(&self, known: &[&str]) -> Result<(), ConfigError> {
+        match self.table.keys().find(|key| !known.contains(&key.as_str())) {
+            Some(key) => Err(self.error(key, "a setting it takes")),
+            None => Ok(()),
+        }
+    }
+
+    pub fn integer(
+        &self,
+        key: &str,
+        range: RangeInclusive<i64>,
+    ) -> Result<Option<i64>, ConfigError> {
+        let expected = format!("an integer from {} to {}", range.start(), range.end());
+        self.get(key, &expected, |v| v.as_integer().filter(|n| range.contains(n)))
+    }
+
+    // Integers are taken too, so rate = 1 works.
+    pub fn float(&self, key: &str) -> Result<Option<f64>, ConfigError> {
+        self.get(key, "a number", |v| v.as_float().or(v.as_integer().map(|n| n as f64)))
+    }
+
+    pub fn string(&self, key: &str) -> Result<Option<String>, ConfigError> {
+        self.get(key, "a string", |v| v.as_str().map(String::from))
+    }
+
+    pub fn strings(&self, key: &str) -> Result<Option<Vec<String>>, ConfigError> {
+        self.get(key, "a list of strings", |v| {
+            v.as_array()?.iter().map(|v| v.as_str().map(String::from)).collect()
+        })
+    }
+
+    // A list of values of a flag's enum, e.g. algos = ["gzip"].
+    pub fn values<T: ValueEnum>(&self, key: &str) -> Result<Option<Vec<T>>, ConfigError> {
+        let expected = T::value_variants()
+            .iter()
+            .filter_map(|v| v.to_possible_value())
+            .map(|v| v.get_name().to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.get(key, &format!("a list of {}", expected), |v| {
+            v.as_array()?.iter().map(|v| T::from_str(v.as_str()?, true).ok()).collect()
+        })
+    }
+
+    // A single value of a flag's enum, e.g. fault = "drop".
+    pub fn value<T: ValueEnum>(&self, key: &str) -> Result<Option<T>, ConfigError> {
+        let value = self.string(key)?;
+        value
+            .map(|v| T::from_str(&v, true).map_err(|_| self.error(key, "a known value")))
+            .transpose()
+    }
+
+    fn get<T>(
+        &self,
+        key: &str,
+        expected: &str,
+        convert: impl FnOnce(&Value) -> Option<T>,
+    ) -> Result<Option<T>, ConfigError> {
+        match self.table.get(key) {
+            Some(value) => convert(value).map(Some).ok_or_else(|| self.error(key, expected)),
+            None => Ok(None),
+        }
+    }
+
+    fn error(&self, key: &str, expected: &str) -> ConfigError {
+        ConfigError(format!("middleware.{}.{} isn't {}", self.name, key, expected))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Encoding;
+
+    #[test]
+    fn test_config_file() {
+        let file = ConfigFile::parse(
+            r#"
+            [middleware.range]
+            [middleware.compression]
+            level = 6
+            algos = ["gzip", "Deflate"]
+            [middleware.chaos]
+            rate = 1
+            "#,
+        )
+        .unwrap();
+        let names: Vec<_> = file.middleware.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["range", "compression", "chaos"]);
+        let compression = &file.middleware[1];
+        assert_eq!(compression.integer("level", 0..=9), Ok(Some(6)));
+        assert!(compression.integer("level", 0..=5).is_err());
+        assert_eq!(compression.integer("zstd_level", 1..=22), Ok(None));
+        assert_eq!(compression.values("algos"), Ok(Some(vec![Encoding::Gzip, Encoding::Deflate])));
+        assert!(compression.string("algos").is_err());
+        assert!(compression.only(&["level", "algos"]).is_ok());
+        assert_eq!(
+            compression.only(&["level"]),
+            Err(ConfigError("middleware.compression.algos isn't a setting it takes".into()))
+        );
+        assert_eq!(file.middleware[2].float("rate"), Ok(Some(1.0)));
+
+        assert!(ConfigFile::parse("").unwrap().middleware.is_empty());
+        for bad in ["middleware = 1", "[middleware]\nrange = 1", "[mime]", "[middleware.range"] {
+            assert!(ConfigFile::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_mime_types() {
+        let file = ConfigFile::parse(
+            r#"
+            [mime_types]
+            mjs = "text/javascript"
+            ".TXT" = "text/plain; charset=utf-8"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            file.mime_types,
+            [
+                ("mjs".to_string(), "text/javascript".to_string()),
+                ("TXT".to_string(), "text/plain; charset=utf-8".to_string())
+            ]
+        );
+        assert!(file.middleware.is_empty());
+        for bad in ["mime_types = 1", "[mime_types]\nmjs = 1", "[mime_types]\nmjs = \"js\""] {
+            assert!(ConfigFile::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use std::{error::Error, fmt::Display, time::Duration};
+
+use crate::{is_token, split_quoted, HttpError, HttpStatus, Request, Response, ResponseBuilder};
+
+// Parses a Cookie header value into name/value pairs. Pairs without a name or
+// an = are skipped, and values may be wrapped in double quotes (RFC 6265),
+// which can hold a ; that doesn't end the cookie.
+fn parse_cookies(value: &str) -> impl Iterator<Item = (String, String)> + '_ {
+    split_quoted(value, ';').filter_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+        let value = value.trim();
+        let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            Some(unquoted) => unquoted,
+            None => value,
+        };
+        Some((name.to_owned(), value.to_owned()))
+    })
+}
+
+impl Request<'_> {
+    // Cookies from every Cookie header, in the order sent. A name may repeat
+    // when the client has cookies for several paths.
+    pub fn cookies(&self) -> Vec<(String, String)> {
+        self.headers()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("cookie"))
+            .flat_map(|(_, v)| parse_cookies(v))
+            .collect()
+    }
+
+    // the first cookie named |name|
+    pub fn cookie(&self, name: &str) -> Option<String> {
+        self.cookies().into_iter().find(|(k, _)| k == name).map(|(_, v)| v)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    // browsers only accept this on Secure cookies
+    None,
+}
+
+// A cookie name, value, path or domain that can't be sent in a Set-Cookie
+// header, since it would add attributes or break the header line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidCookie(String);
+
+impl Display for InvalidCookie {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid cookie {}", self.0)
+    }
+}
+
+impl Error for InvalidCookie {}
+
+// a cookie the handler built from bad input is the server's fault
+impl From<InvalidCookie> for HttpError {
+    fn from(err: InvalidCookie) -> Self {
+        eprintln!("{}", err);
+        HttpError(HttpStatus::ServerError)
+    }
+}
+
+// cookie-octet from RFC 6265 section 4.1.1
+fn is_cookie_octet(b: u8) -> bool {
+    matches!(b, 0x21 | 0x23..=0x2b | 0x2d..=0x3a | 0x3c..=0x5b | 0x5d..=0x7e)
+}
+
+fn is_cookie_value(value: &str) -> bool {
+    let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+    value.bytes().all(is_cookie_octet)
+}
+
+// Path and Domain may hold anything but controls and the ; ending them.
+fn is_attribute_value(value: &str) -> bool {
+    !value.bytes().any(|b| b.is_ascii_control() || b == b';')
+}
+
+// A Set-Cookie header value, e.g.
+// Cookie::new("session", "abc")?.path("/")?.http_only(true). The name must be
+// a token and the value may not contain spaces, quotes, commas, semicolons or
+// backslashes (RFC 6265 section 4.1.1), though it may be wrapped in double
+// quotes. Anything else is refused with InvalidCookie rather than sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    max_age: Option<Duration>,
+    path: Option<String>,
+    domain: Option<String>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Result<Self, InvalidCookie> {
+        let (name, value) = (name.into(), value.into());
+        if name.is_empty() || !name.bytes().all(is_token) {
+            return Err(InvalidCookie(format!("name {:?}", name)));
+        }
+        if !is_cookie_value(&value) {
+            return Err(InvalidCookie(format!("value {:?}", value)));
+        }
+        Ok(Self {
+            name,
+            value,
+            max_age: None,
+            path: None,
+            domain: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        })
+    }
+
+    // A cookie telling the client to delete |name| right away. Path and
+    // domain have to match the ones it was set with.
+    pub fn removal(name: impl Into<String>) -> Result<Self, InvalidCookie> {
+        Ok(Self::new(name, "")?.max_age(Duration::ZERO))
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn path(mut self, path: impl Into<String>) -> Result<Self, InvalidCookie> {
+        let path = path.into();
+        if !is_attribute_value(&path) {
+            return Err(InvalidCookie(format!("path {:?}", path)));
+        }
+        self.path = Some(path);
+        Ok(self)
+    }
+
+    pub fn domain(mut self, domain: impl Into<String>) -> Result<Self, InvalidCookie> {
+        let domain = domain.into();
+        if !is_attribute_value(&domain) {
+            return Err(InvalidCookie(format!("domain {:?}", domain)));
+        }
+        self.domain = Some(domain);
+        Ok(self)
+    }
+
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+impl Display for Cookie {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.name, self.value)?;
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", path)?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", domain)?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={:?}", same_site)?;
+        }
+        Ok(())
+    }
+}
+
+impl Response {
+    // Adds a Set-Cookie header; each cookie gets its own, since they can't be
+    // combined into one line like other repeated headers.
+    pub fn add_cookie(&mut self, cookie: Cookie) {
+        self.append_header("set-cookie".to_string(), cookie.to_string());
+    }
+}
+
+impl ResponseBuilder {
+    pub fn cookie(self, cookie: Cookie) -> Self {
+        self.header("set-cookie", cookie.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::parse_request;
+
+    #[test]
+    fn test_cookies() {
+        let raw = "GET / HTTP/1.1\r\n\
+                   Cookie: session=abc123; theme=\"dark mode\"; broken; =nameless; empty=\r\n\
+                   Cookie: session=other; quoted=\"a;b\"\r\n\r\n";
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+        let req = parse_request(&mut reader).unwrap();
+        let cookies = req.cookies();
+        let names: Vec<&str> = cookies.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(names, ["session", "theme", "empty", "session", "quoted"]);
+        assert_eq!(req.cookie("session").as_deref(), Some("abc123"));
+        assert_eq!(req.cookie("theme").as_deref(), Some("dark mode"));
+        assert_eq!(req.cookie("empty").as_deref(), Some(""));
+        assert_eq!(req.cookie("quoted").as_deref(), Some("a;b"));
+        assert_eq!(req.cookie("missing"), None);
+    }
+
+    #[test]
+    fn test_set_cookie() {
+        let session = Cookie::new("session", "abc123")
+            .unwrap()
+            .max_age(Duration::from_secs(3600))
+            .path("/")
+            .unwrap()
+            .domain("example.com")
+            .unwrap()
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Lax);
+        let mut resp = Response::empty();
+        resp.add_cookie(session);
+        resp.add_cookie(Cookie::removal("theme").unwrap());
+        let set: Vec<&str> =
+            resp.headers().filter(|(k, _)| k == "set-cookie").map(|(_, v)| v.as_str()).collect();
+        assert_eq!(
+            set,
+            [
+                "session=abc123; Max-Age=3600; Path=/; Domain=example.com; Secure; HttpOnly; SameSite=Lax",
+                "theme=; Max-Age=0",
+            ]
+        );
+        let resp = Response::builder()
+            .cookie(Cookie::new("a", "1").unwrap())
+            .cookie(Cookie::new("b", "\"2\"").unwrap())
+            .build();
+        assert_eq!(resp.headers().count(), 2);
+    }
+
+    #[test]
+    fn test_invalid_cookie() {
+        assert!(Cookie::new("", "1").is_err());
+        assert!(Cookie::new("a b", "1").is_err());
+        assert!(Cookie::new("a", "1; Domain=evil.example").is_err());
+        assert!(Cookie::new("a", "1\r\nX-Injected: 1").is_err());
+        assert!(Cookie::new("a", "\"1\\2\"").is_err());
+        let cookie = Cookie::new("a", "1").unwrap();
+        assert!(cookie.clone().path("/; Secure").is_err());
+        assert!(cookie.clone().path("/a b").is_ok());
+        assert!(cookie.domain("example.com\r\nX-Injected: 1").is_err());
+        assert_eq!(HttpError::from(InvalidCookie("x".into())).0, HttpStatus::ServerError);
+    }
+}
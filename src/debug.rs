@@ -0,0 +1,161 @@
+use std::{fmt::Write, io::Read, thread, time::Duration};
+
+use serde_json::json;
+
+use crate::{
+    base64, Context, Form, Handler, HttpError, HttpStatus, QualityItem, Request, Response,
+};
+
+// most of the body /__echo reflects
+const ECHO_LIMIT: u64 = 64 << 10;
+// longest /__delay/{ms} and largest /__bytes/{n}, enough to exercise large
+// responses without letting a client keep a worker busy for long
+const MAX_DELAY_MS: u64 = 60_000;
+const MAX_BYTES: u64 = 16 << 20;
+
+// Built-in diagnostic routes under /__, enabled with --debug-routes. Other
+// requests go to the server's handler.
+pub struct DebugRoutes(pub Box<dyn Handler>);
+
+impl Handler for DebugRoutes {
+    fn handle(&self, ctx: &Context, req: Request) -> Result<Response, HttpError> {
+        if req.path == "/__echo" {
+            return echo(req);
+        }
+        if req.path == "/__status" {
+            return Ok(status(ctx));
+        }
+        if let Some(ms) = req.path.strip_prefix("/__delay/") {
+            return delay(parse_param(ms, MAX_DELAY_MS)?);
+        }
+        if let Some(n) = req.path.strip_prefix("/__bytes/") {
+            let query = Form::parse(req.query.as_deref().unwrap_or_default().as_bytes())?;
+            let seed = query.get("seed").and_then(|s| s.parse().ok()).unwrap_or(1);
+            return Ok(random_bytes(parse_param(n, MAX_BYTES)?, seed));
+        }
+        self.0.handle(ctx, req)
+    }
+}
+
+// Reflects the request back, as JSON unless the client prefers plain text.
+// Bodies beyond ECHO_LIMIT are cut off and flagged as truncated.
+fn echo(mut req: Request) -> Result<Response, HttpError> {
+    let accept = req.accept();
+    let (json, text) =
+        (media_quality(&accept, "application/json"), media_quality(&accept, "text/plain"));
+    if !accept.is_empty() && json == 0.0 && text == 0.0 {
+        return Err(HttpError(HttpStatus::NotAcceptable));
+    }
+    let mut body = Vec::new();
+    (&mut req.body)
+        .take(ECHO_LIMIT + 1)
+        .read_to_end(&mut body)
+        .map_err(|_| HttpError(HttpStatus::BadRequest))?;
+    let truncated = body.len() as u64 > ECHO_LIMIT;
+    body.truncate(ECHO_LIMIT as usize);
+    let target = &req.raw_target;
+
+    if accept.is_empty() || json >= text {
+        let headers: Vec<[&str; 2]> =
+            req.original_headers().map(|(name, value)| [name, value]).collect();
+        let mut out = json!({
+            "method": req.method.to_string(),
+            "target": target,
+            "version": req.version.to_string(),
+            "headers": headers,
+        });
+        // bodies that aren't UTF-8 are sent base64-encoded so no byte is lost
+        match std::str::from_utf8(&body) {
+            Ok(body) => out["body"] = body.into(),
+            Err(_) => out["body_base64"] = base64::encode(&body).into(),
+        }
+        out["truncated"] = truncated.into();
+        let out = out.to_string();
+        return Ok(Response::builder().header("content-type", "application/json").body(out));
+    }
+
+    let mut out = format!("{} {} {}\n", req.method, target, req.version);
+    for (name, value) in req.original_headers() {
+        writeln!(out, "{}: {}", name, value).unwrap();
+    }
+    out.push('\n');
+    let mut out = out.into_bytes();
+    out.extend_from_slice(&body);
+    Ok(Response::builder().header("content-type", "text/plain").body(out))
+}
+
+// Like headers::quality, but for media ranges: falls back to type/* and */*.
+fn media_quality(items: &[QualityItem], mime: &str) -> f32 {
+    let (kind, _) = mime.split_once('/').unwrap_or((mime, ""));
+    [mime.to_string(), format!("{}/*", kind), "*/*".to_string()]
+        .iter()
+        .find_map(|range| items.iter().find(|i| &i.value == range).map(|i| i.q))
+        .unwrap_or(0.0)
+}
+
+// What the server is holding, as JSON.
+fn status(ctx: &Context) -> Response {
+    let json =
+        json!({"memory_used_bytes": ctx.memory.used(), "memory_limit_bytes": ctx.memory.limit()});
+    Response::builder().header("content-type", "application/json").body(json.to_string())
+}
+
+fn parse_param(param: &str, max: u64) -> Result<u64, HttpError> {
+    match param.parse() {
+        Ok(n) if n <= max => Ok(n),
+        _ => Err(HttpError(HttpStatus::BadRequest)),
+    }
+}
+
+// Answers after |ms| milliseconds, for exercising client timeouts.
+fn delay(ms: u64) -> Result<Response, HttpError> {
+    thread::sleep(Duration::from_millis(ms));
+    Ok(Response::plain_text(format!("delayed {}ms\n", ms)))
+}
+
+// |n| pseudorandom bytes, the same for the same ?seed=, generated in 8 KiB
+// blocks as they're written. Compression streams them too, so the whole body
+// is never held in memory.
+fn random_bytes(n: u64, seed: u64) -> Response {
+    let mut resp = Response::from_writer(move |w| {
+        // xorshift64*, whose state must be nonzero
+        let mut state = seed.max(1);
+        let mut buf = [0u8; 8192];
+        let mut left = n;
+        while left > 0 {
+            for word in buf.chunks_mut(8) {
+                state ^= state >> 12;
+                state ^= state << 25;
+                state ^= state >> 27;
+                word.copy_from_slice(&state.wrapping_mul(0x2545f4914f6cdd1d).to_le_bytes());
+            }
+            let len = left.min(buf.len() as u64) as usize;
+            w.write_all(&buf[..len])?;
+            left -= len as u64;
+        }
+        Ok(())
+    });
+    resp.set_header("content-length".to_string(), n.to_string());
+    resp
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_random_bytes() {
+        let bytes = |n, seed| random_bytes(n, seed).body.unwrap().into_bytes().unwrap();
+        assert_eq!(random_bytes(10000, 1).get_header("content-length"), Some("10000"));
+        assert_eq!(bytes(10000, 1).len(), 10000);
+        assert_eq!(bytes(10000, 1), bytes(10000, 1));
+        assert_ne!(bytes(100, 1), bytes(100, 2));
+        assert_eq!(bytes(0, 1), b"");
+        assert!(matches!(
+            parse_param("61000", MAX_DELAY_MS),
+            Err(HttpError(HttpStatus::BadRequest))
+        ));
+        assert!(parse_param("-1", MAX_DELAY_MS).is_err() && parse_param("x", 10).is_err());
+        assert!(parse_param(&(32 << 20).to_string(), MAX_BYTES).is_err());
+    }
+}
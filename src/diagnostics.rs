@@ -0,0 +1,184 @@
+use std::{
+    any::Any,
+    backtrace::{Backtrace, BacktraceStatus},
+    cell::RefCell,
+    fmt::{Display, Write},
+    panic::{self, AssertUnwindSafe},
+    sync::Once,
+};
+
+use serde_json::json;
+
+use crate::{html::escape_html, parse_quality_list, quality, HttpStatus, Method, Response};
+
+thread_local! {
+    // where the last panic on this thread happened, recorded by the hook
+    static LAST_PANIC: RefCell<Option<(Option<String>, Option<String>)>> =
+        const { RefCell::new(None) };
+}
+
+// A handler panic caught by catch.
+#[derive(Debug)]
+pub(crate) struct Panic {
+    pub message: String,
+    pub location: Option<String>,
+    // only captured when RUST_BACKTRACE or RUST_LIB_BACKTRACE enables them
+    pub backtrace: Option<String>,
+}
+
+impl Display for Panic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(location) = &self.location {
+            write!(f, " at {}", location)?;
+        }
+        Ok(())
+    }
+}
+
+// Has panics record their location and backtrace for catch, on top of
+// whatever the previous hook does.
+pub(crate) fn install_panic_hook() {
+    static INSTALLED: Once = Once::new();
+    INSTALLED.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let location = info.location().map(|l| l.to_string());
+            let backtrace = Backtrace::capture();
+            let backtrace =
+                (backtrace.status() == BacktraceStatus::Captured).then(|| backtrace.to_string());
+            LAST_PANIC.with(|last| *last.borrow_mut() = Some((location, backtrace)));
+            previous(info);
+        }));
+    });
+}
+
+fn message(payload: &(dyn Any + Send)) -> String {
+    match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(s), _) => s.to_string(),
+        (_, Some(s)) => s.clone(),
+        _ => "Box<dyn Any>".to_string(),
+    }
+}
+
+// Runs |f|, turning a panic into an error so one bad request doesn't take
+// its worker down with it. Panics are caught whether or not --dev-errors is
+// on; that only changes the 500 sent. Any Mutex the handler held when it
+// panicked stays poisoned, so locks shared across requests (metrics, the
+// audit log) recover the data rather than unwrap the PoisonError.
+pub(crate) fn catch<R>(f: impl FnOnce() -> R) -> Result<R, Panic> {
+    LAST_PANIC.with(|last| last.borrow_mut().take());
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+        let (location, backtrace) = LAST_PANIC.with(|last| last.borrow_mut().take()).unzip();
+        Panic {
+            message: message(payload.as_ref()),
+            location: location.flatten(),
+            backtrace: backtrace.flatten(),
+        }
+    })
+}
+
+// Whether a client's Accept header ranks JSON above HTML, so error_page
+// should answer in JSON.
+pub(crate) fn prefers_json(accept: Option<&str>) -> bool {
+    let items = parse_quality_list(accept.unwrap_or_default());
+    let json = quality(&items, "application/json");
+    json > 0.0 && json > quality(&items, "text/html")
+}
+
+// The 500 answered with --dev-errors, describing what went wrong: as JSON if
+// |json|, otherwise as an HTML page.
+pub(crate) fn error_page(
+    status: HttpStatus,
+    method: &Method,
+    target: &str,
+    panic: Option<&Panic>,
+    json: bool,
+) -> Response {
+    let error = match panic {
+        Some(panic) => format!("handler panicked: {}", panic.message),
+        None => format!("handler returned {}", status),
+    };
+    let location = panic.and_then(|p| p.location.as_deref());
+    let backtrace = panic.and_then(|p| p.backtrace.as_deref());
+    let mut resp = if json {
+        let body = json!({
+            "status": status.code(),
+            "method": method.to_string(),
+            "target": target,
+            "error": error,
+            "location": location,
+            "backtrace": backtrace,
+        })
+        .to_string();
+        Response::builder().header("content-type", "application/json").body(body)
+    } else {
+        let mut body = format!(
+            "<!DOCTYPE html>\n<html><head><title>{status}</title></head><body>\n<h1>{status}</h1>\n\
+             <p>{} {}</p>\n<pre>{}</pre>\n",
+            escape_html(&method.to_string()),
+            escape_html(target),
+            escape_html(&error),
+            status = escape_html(&status.to_string()),
+        );
+        if let Some(location) = location {
+            writeln!(body, "<p>at {}</p>", escape_html(location)).unwrap();
+        }
+        if let Some(backtrace) = backtrace {
+            writeln!(body, "<h2>Backtrace</h2>\n<pre>{}</pre>", escape_html(backtrace)).unwrap();
+        }
+        body.push_str("</body></html>\n");
+        Response::builder().header("content-type", "text/html; charset=utf-8").body(body)
+    };
+    resp.status = status;
+    resp
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_catch() {
+        install_panic_hook();
+        assert_eq!(catch(|| 1).unwrap(), 1);
+        let panic = catch(|| panic!("bad {}", "input")).unwrap_err();
+        assert_eq!(panic.message, "bad input");
+        assert!(panic.location.unwrap().contains("diagnostics.rs"));
+        assert_eq!(catch(|| std::panic::panic_any(7)).unwrap_err().message, "Box<dyn Any>");
+    }
+
+    #[test]
+    fn test_error_page() {
+        let panic = Panic {
+            message: "<oops>".into(),
+            location: Some("src/a.rs:1:2".into()),
+            backtrace: None,
+        };
+        let resp =
+            error_page(HttpStatus::ServerError, &Method::Get, "/x?a=<b>", Some(&panic), false);
+        assert_eq!(resp.status, HttpStatus::ServerError);
+        let html = String::from_utf8(resp.body.unwrap().into_bytes().unwrap()).unwrap();
+        assert!(html.contains("<pre>handler panicked: &lt;oops&gt;</pre>"), "{}", html);
+        assert!(html.contains("GET /x?a=&lt;b&gt;"));
+        assert!(!html.contains("Backtrace"));
+
+        let resp = error_page(HttpStatus::ServerError, &Method::Post, "/y", None, true);
+        let json = String::from_utf8(resp.body.unwrap().into_bytes().unwrap()).unwrap();
+        assert_eq!(
+            json,
+            r#"{"status":500,"method":"POST","target":"/y","error":"handler returned 500 Internal Server Error","location":null,"backtrace":null}"#
+        );
+    }
+
+    #[test]
+    fn test_prefers_json() {
+        assert!(prefers_json(Some("application/json")));
+        assert!(prefers_json(Some("text/html;q=0.5, application/json")));
+        assert!(!prefers_json(Some("text/html, application/json;q=0.9")));
+        assert!(!prefers_json(Some("application/json;q=0")));
+        assert!(!prefers_json(Some("application/jsonp")));
+        assert!(!prefers_json(Some("*/*")));
+        assert!(!prefers_json(None));
+    }
+}
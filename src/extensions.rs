@@ -0,0 +1,66 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+};
+
+// Values attached to a request by middleware for handlers to read, such as
+// an authenticated user or a request id. Holds at most one value per type, so
+// wrap shared types like String in a newtype.
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    // Stores |value|, returning any value of the same type it replaces.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        let prev = self.map.insert(TypeId::of::<T>(), Box::new(value))?;
+        prev.downcast().ok().map(|prev| *prev)
+    }
+
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.map.get_mut(&TypeId::of::<T>())?.downcast_mut()
+    }
+
+    pub fn remove<T: 'static>(&mut self) -> Option<T> {
+        let value = self.map.remove(&TypeId::of::<T>())?;
+        value.downcast().ok().map(|value| *value)
+    }
+
+    pub fn contains<T: 'static>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+}
+
+impl std::fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Extensions").field("len", &self.map.len()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct UserId(u32);
+
+    #[test]
+    fn test_extensions() {
+        let mut ext = Extensions::default();
+        assert_eq!(ext.get::<UserId>(), None);
+        assert_eq!(ext.insert(UserId(1)), None);
+        assert_eq!(ext.insert("locale"), None);
+        assert_eq!(ext.insert(UserId(2)), Some(UserId(1)));
+        ext.get_mut::<UserId>().unwrap().0 += 1;
+        assert_eq!(ext.get::<UserId>(), Some(&UserId(3)));
+        assert_eq!(ext.get::<&str>(), Some(&"locale"));
+        assert_eq!(ext.remove::<UserId>(), Some(UserId(3)));
+        assert!(!ext.contains::<UserId>());
+        assert!(ext.contains::<&str>());
+    }
+}
@@ -0,0 +1,203 @@
+use std::{marker::PhantomData, path::PathBuf, str::FromStr};
+
+use bytes::Bytes;
+
+use crate::{Context, Form, Handler, HttpError, HttpStatus, Request, Response};
+
+// A value a handler can take as an argument, built from the request before
+// the handler runs. Extractors reading the body consume it, so a handler
+// should take at most one of those.
+pub trait FromRequest: Sized {
+    fn from_request(ctx: &Context, req: &mut Request) -> Result<Self, HttpError>;
+}
+
+// The route's path parameters, parsed as a T: a single value for a route
+// like /echo/:name, or a tuple with one element per parameter in pattern
+// order, e.g. Path<(String, u32)> for /users/:name/posts/:id. Answers 400 if
+// one doesn't parse.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Path<T>(pub T);
+
+// What Path can extract: the common single values, and tuples of anything
+// FromStr, so other types can be taken as a 1-tuple, e.g. Path<(MyId,)>.
+pub trait FromParams: Sized {
+    // how many parameters the route must have
+    const COUNT: usize;
+
+    fn from_params(values: &[&str]) -> Option<Self>;
+}
+
+macro_rules! single_params {
+    ($($t:ty),*) => {
+        $(impl FromParams for $t {
+            const COUNT: usize = 1;
+
+            fn from_params(values: &[&str]) -> Option<Self> {
+                values[0].parse().ok()
+            }
+        })*
+    };
+}
+
+single_params!(String, PathBuf, bool, char, f32, f64);
+single_params!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+
+macro_rules! tuple_params {
+    ($count:literal: $($t:ident $i:tt),*) => {
+        impl<$($t: FromStr),*> FromParams for ($($t,)*) {
+            const COUNT: usize = $count;
+
+            fn from_params(values: &[&str]) -> Option<Self> {
+                Some(($(values[$i].parse().ok()?,)*))
+            }
+        }
+    };
+}
+
+tuple_params!(1: A 0);
+tuple_params!(2: A 0, B 1);
+tuple_params!(3: A 0, B 1, C 2);
+tuple_params!(4: A 0, B 1, C 2, D 3);
+
+impl<T: FromParams> FromRequest for Path<T> {
+    fn from_request(_ctx: &Context, req: &mut Request) -> Result<Self, HttpError> {
+        let values: Vec<&str> = req.params().iter().map(|(_, v)| v.as_str()).collect();
+        if values.len() != T::COUNT {
+            // the handler doesn't fit its route, which no request can fix
+            eprintln!(
+                "error: Path extractor for {} params used on {} with {}",
+                T::COUNT,
+                req.path,
+                values.len()
+            );
+            return Err(HttpError(HttpStatus::ServerError));
+        }
+        T::from_params(&values).map(Path).ok_or(HttpError(HttpStatus::BadRequest))
+    }
+}
+
+// The decoded query string, empty if there isn't one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query(pub Form);
+
+impl FromRequest for Query {
+    fn from_request(_ctx: &Context, req: &mut Request) -> Result<Self, HttpError> {
+        Form::parse(req.query.as_deref().unwrap_or_default().as_bytes()).map(Query)
+    }
+}
+
+impl FromRequest for Form {
+    fn from_request(_ctx: &Context, req: &mut Request) -> Result<Self, HttpError> {
+        req.form()
+    }
+}
+
+impl FromRequest for Bytes {
+    fn from_request(_ctx: &Context, req: &mut Request) -> Result<Self, HttpError> {
+        req.bytes()
+    }
+}
+
+impl FromRequest for String {
+    fn from_request(_ctx: &Context, req: &mut Request) -> Result<Self, HttpError> {
+        req.text()
+    }
+}
+
+// Anything the router accepts as a handler: a Handler, or a function taking
+// only extractors. |M| tells the two apart and is inferred.
+pub trait IntoHandler<M> {
+    fn into_handler(self) -> Box<dyn Handler>;
+}
+
+// marks handlers taking the context and request directly
+pub struct Raw;
+
+impl<H: Into<Box<dyn Handler>>> IntoHandler<Raw> for H {
+    fn into_handler(self) -> Box<dyn Handler> {
+        self.into()
+    }
+}
+
+// Adapts a function of extractors to Handler.
+struct Extractors<F, Args> {
+    f: F,
+    args: PhantomData<fn() -> Args>,
+}
+
+macro_rules! extractor_handler {
+    ($($arg:ident),*) => {
+        impl<F, $($arg),*> Handler for Extractors<F, ($($arg,)*)>
+        where
+            F: Fn($($arg),*) -> Result<Response, HttpError> + Send + Sync,
+            $($arg: FromRequest + 'static,)*
+        {
+            #[allow(non_snake_case, unused_variables, unused_mut)]
+            fn handle(&self, ctx: &Context, mut req: Request) -> Result<Response, HttpError> {
+                $(let $arg = $arg::from_request(ctx, &mut req)?;)*
+                (self.f)($($arg),*)
+            }
+        }
+
+        impl<F, $($arg),*> IntoHandler<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> Result<Response, HttpError> + Send + Sync + 'static,
+            $($arg: FromRequest + 'static,)*
+        {
+            fn into_handler(self) -> Box<dyn Handler> {
+                Box::new(Extractors { f: self, args: PhantomData })
+            }
+        }
+    };
+}
+
+extractor_handler!();
+extractor_handler!(A);
+extractor_handler!(A, B);
+extractor_handler!(A, B, C);
+extractor_handler!(A, B, C, D);
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, thread};
+
+    use super::*;
+    use crate::{Config, Router, Server};
+
+    fn echo(Path(word): Path<String>, Query(query): Query) -> Result<Response, HttpError> {
+        let times =
+            query.get("times").unwrap_or("1").parse().map_err(|_| HttpStatus::BadRequest)?;
+        Ok(Response::plain_text(word.repeat(times)))
+    }
+
+    #[test]
+    fn test_extractors() {
+        let router = Router::default()
+            .get("/echo/:word", echo)
+            .get("/square/:n", |Path(n): Path<u32>| Ok(Response::plain_text((n * n).to_string())))
+            .get("/users/:name/posts/:id", |Path((name, id)): Path<(String, u32)>| {
+                Ok(Response::plain_text(format!("{} #{}", name, id)))
+            })
+            .get("/pair/:a/:b", |Path(a): Path<String>| Ok(Response::plain_text(a)))
+            .post("/upper", |body: String| Ok(Response::plain_text(body.to_uppercase())))
+            .get("/", || Ok(Response::plain_text("root".to_string())));
+        let server = Arc::new(Server::start(Config::default(), router));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let client = reqwest::blocking::Client::new();
+        let url = |path: &str| format!("http://{}{}", server.addr(), path);
+        let text = |resp: reqwest::blocking::Response| resp.text().unwrap();
+        assert_eq!(text(client.get(url("/echo/ab?times=3")).send().unwrap()), "ababab");
+        assert_eq!(text(client.get(url("/square/12")).send().unwrap()), "144");
+        let resp = client.get(url("/square/twelve")).send().unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+        assert_eq!(text(client.get(url("/users/ada/posts/7")).send().unwrap()), "ada #7");
+        let resp = client.get(url("/users/ada/posts/seven")).send().unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+        let resp = client.get(url("/pair/x/y")).send().unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(text(client.post(url("/upper")).body("hi").send().unwrap()), "HI");
+        assert_eq!(text(client.get(url("/")).send().unwrap()), "root");
+    }
+}
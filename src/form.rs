@@ -0,0 +1,105 @@
+use crate::{HttpError, HttpStatus, Request};
+
+// Decoded application/x-www-form-urlencoded pairs, in the order sent.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Form(Vec<(String, String)>);
+
+impl Form {
+    pub fn parse(data: &[u8]) -> Result<Self, HttpError> {
+        let mut pairs = Vec::new();
+        for pair in data.split(|b| *b == b'&').filter(|p| !p.is_empty()) {
+            let (k, v) = match pair.iter().position(|b| *b == b'=') {
+                Some(i) => (&pair[..i], &pair[i + 1..]),
+                None => (pair, &[][..]),
+            };
+            pairs.push((decode(k)?, decode(v)?));
+        }
+        Ok(Self(pairs))
+    }
+
+    // the first value for |name|
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0.iter().filter(move |(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(String, String)> {
+        self.0.iter()
+    }
+}
+
+fn hex(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+// undoes percent-encoding, with + standing for a space
+pub(crate) fn decode(data: &[u8]) -> Result<String, HttpError> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&b) = bytes.next() {
+        match b {
+            b'+' => out.push(b' '),
+            b'%' => {
+                let hi = bytes.next().and_then(|b| hex(*b));
+                let lo = bytes.next().and_then(|b| hex(*b));
+                let (Some(hi), Some(lo)) = (hi, lo) else {
+                    return Err(HttpError(HttpStatus::BadRequest));
+                };
+                out.push(hi << 4 | lo);
+            }
+            _ => out.push(b),
+        }
+    }
+    String::from_utf8(out).map_err(|_| HttpError(HttpStatus::BadRequest))
+}
+
+impl Request<'_> {
+    // Reads and decodes a form body, answering 415 for other content types.
+    pub fn form(&mut self) -> Result<Form, HttpError> {
+        if !self.content_type_is("application/x-www-form-urlencoded") {
+            return Err(HttpError(HttpStatus::UnsupportedMediaType));
+        }
+        Form::parse(&self.bytes()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::parse_request;
+
+    #[test]
+    fn test_parse() {
+        let form = Form::parse(b"name=J%C3%B6rg+M&tag=a&tag=b&empty=&flag").unwrap();
+        assert_eq!(form.get("name"), Some("Jörg M"));
+        assert_eq!(form.get_all("tag").collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(form.get("empty"), Some(""));
+        assert_eq!(form.get("flag"), Some(""));
+        assert_eq!(form.get("missing"), None);
+        assert_eq!(form.iter().count(), 5);
+
+        assert!(Form::parse(b"a=%zz").is_err());
+        assert!(Form::parse(b"a=%4").is_err());
+        assert!(Form::parse(b"a=%ff").is_err());
+    }
+
+    #[test]
+    fn test_form() {
+        let raw = "POST / HTTP/1.1\r\n\
+                   Content-Type: application/x-www-form-urlencoded; charset=utf-8\r\n\
+                   Content-Length: 7\r\n\r\na=1&b=2";
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+        let mut req = parse_request(&mut reader).unwrap();
+        assert_eq!(req.form().unwrap().get("b"), Some("2"));
+
+        let raw = "POST / HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: 3\r\n\r\na=1";
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+        let mut req = parse_request(&mut reader).unwrap();
+        assert_eq!(req.form().unwrap_err().0, HttpStatus::UnsupportedMediaType);
+    }
+}
@@ -0,0 +1,128 @@
+use crate::{Request, Response};
+
+// Headers describing a single connection, which a proxy must never pass on
+// (RFC 9110 section 7.6.1).
+pub const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+// Whether |name| is hop-by-hop, either always or because the Connection
+// header |connection| lists it.
+pub fn is_hop_by_hop(name: &str, connection: Option<&str>) -> bool {
+    HOP_BY_HOP_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name))
+        || connection.is_some_and(|c| c.split(',').any(|t| t.trim().eq_ignore_ascii_case(name)))
+}
+
+// names of the hop-by-hop headers in |headers|
+fn hop_by_hop<'h>(headers: impl Iterator<Item = &'h (String, String)>) -> Vec<String> {
+    let headers: Vec<_> = headers.collect();
+    let connection = headers.iter().find(|(k, _)| k.eq_ignore_ascii_case("connection"));
+    let connection = connection.map(|(_, v)| v.as_str());
+    headers.iter().filter(|(k, _)| is_hop_by_hop(k, connection)).map(|(k, _)| k.clone()).collect()
+}
+
+impl Request<'_> {
+    // Removes headers meant for this connection only, so handlers and
+    // anything they forward never see them.
+    pub fn strip_hop_by_hop(&mut self) {
+        for name in hop_by_hop(self.headers()) {
+            self.remove_header(&name);
+        }
+    }
+}
+
+impl Response {
+    // Removes connection-level headers a handler set or copied from an
+    // upstream response; the server frames the response itself.
+    pub fn strip_hop_by_hop(&mut self) {
+        for name in hop_by_hop(self.headers()) {
+            self.remove_header(&name);
+        }
+    }
+
+    // Whether the handler's Connection header asks for the connection to be
+    // closed after this response.
+    pub fn closes_connection(&self) -> bool {
+        self.get_header("connection")
+            .is_some_and(|c| c.split(',').any(|t| t.trim().eq_ignore_ascii_case("close")))
+    }
+}
+
+// Which inbound headers a handler acting as a client (a proxy, a webhook
+// caller) copies onto its outbound request. Nothing is forwarded unless
+// allowed, and hop-by-hop headers are dropped even if allowed.
+#[derive(Debug, Clone, Default)]
+pub struct ForwardPolicy {
+    allow: Vec<String>,
+}
+
+impl ForwardPolicy {
+    pub fn allow(mut self, name: &str) -> Self {
+        self.allow.push(name.to_ascii_lowercase());
+        self
+    }
+
+    pub fn allows(&self, name: &str) -> bool {
+        self.allow.iter().any(|a| a.eq_ignore_ascii_case(name))
+    }
+
+    // the headers of |req| to send upstream
+    pub fn headers(&self, req: &Request) -> Vec<(String, String)> {
+        let connection = req.get_header("connection");
+        req.headers()
+            .filter(|(k, _)| self.allows(k) && !is_hop_by_hop(k, connection))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::parse_request;
+
+    #[test]
+    fn test_forward() {
+        let raw = "GET / HTTP/1.1\r\nAccept: */*\r\nAuthorization: secret\r\nX-Trace: 1\r\n\
+                   Connection: x-trace, close\r\nUpgrade: websocket\r\nX-Request-Id: 7\r\n\r\n";
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+        let req = parse_request(&mut reader).unwrap();
+        let policy = ForwardPolicy::default()
+            .allow("accept")
+            .allow("X-Request-Id")
+            .allow("x-trace")
+            .allow("upgrade");
+        let headers = policy.headers(&req);
+        let names: Vec<&str> = headers.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(names, ["Accept", "X-Request-Id"]);
+        assert!(ForwardPolicy::default().headers(&req).is_empty());
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let raw = "GET / HTTP/1.1\r\nAccept: */*\r\nConnection: x-trace, close\r\n\
+                   X-Trace: 1\r\nTE: trailers\r\nKeep-Alive: timeout=5\r\n\r\n";
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+        let mut req = parse_request(&mut reader).unwrap();
+        req.strip_hop_by_hop();
+        let names: Vec<&str> = req.original_headers().map(|(k, _)| k).collect();
+        assert_eq!(names, ["Accept"]);
+
+        let mut resp = Response::empty();
+        resp.set_header("Transfer-Encoding".to_string(), "chunked".to_string());
+        resp.set_header("Upgrade".to_string(), "h2c".to_string());
+        resp.set_header("content-type".to_string(), "text/plain".to_string());
+        resp.strip_hop_by_hop();
+        assert_eq!(resp.headers().count(), 1);
+    }
+}
@@ -1,11 +1,75 @@
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    fs, io,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    time::Instant,
+};
 
-use regex::Regex;
+use regex::{Regex, RegexSet};
 
-use crate::{HttpError, HttpStatus, Method, Request, Response};
+use crate::{
+    html::escape_html, http_date, parse_quality_list, quality, AuditLog, HttpError, HttpStatus,
+    IntoHandler, MemoryBudget, Method, Metrics, Middleware, MiddlewareFactory, MimeTypes,
+    Redaction, Request, Response, Webhook,
+};
 
 pub struct Context {
     pub working_dir: PathBuf,
+    pub metrics: Metrics,
+    // the named routes, filled in when the server is built from a Router
+    pub urls: Urls,
+    // where file changes are recorded, see audited
+    pub audit: Option<AuditLog>,
+    // told about file changes, see file_changed
+    pub webhook: Option<Webhook>,
+    // the Content-Types files are served with
+    pub mime_types: MimeTypes,
+    // what connections hold, see ConnectionHandler
+    pub memory: MemoryBudget,
+    // what to keep out of logs; handlers and plugins that log headers or
+    // targets should pass them through it
+    pub redaction: Redaction,
+    // whether working_dir was missing when last checked, see files_dir
+    pub(crate) dir_missing: AtomicBool,
+}
+
+impl Context {
+    // The metrics in Prometheus text format, with gauges for what's in use
+    // right now, like memory_used_bytes, read at the time of the scrape.
+    pub fn render_metrics(&self) -> String {
+        self.metrics.set_gauge("memory_used_bytes", &[], self.memory.used() as f64);
+        self.metrics.render()
+    }
+
+    // The working directory for file routes, or 503 while it's gone (deleted,
+    // unmounted), so clients see a temporary failure instead of a 404. Logs
+    // once when it disappears and once when it's back.
+    pub fn files_dir(&self) -> Result<&Path, HttpError> {
+        let present = self.working_dir.is_dir();
+        let was_missing = self.dir_missing.swap(!present, Ordering::Relaxed);
+        if present && was_missing {
+            eprintln!("working directory {} is back", self.working_dir.display());
+        } else if !present && !was_missing {
+            eprintln!(
+                "error: working directory {} is missing, file routes will answer 503",
+                self.working_dir.display()
+            );
+        }
+        if !present {
+            return Err(HttpError(HttpStatus::ServiceUnavailable));
+        }
+        Ok(&self.working_dir)
+    }
+
+    // Serves |path| from the working directory, see Response::send_file.
+    pub fn send_file(&self, path: impl AsRef<Path>) -> Result<Response, HttpError> {
+        Response::send_file(self.files_dir()?, path, &self.mime_types)
+    }
 }
 
 pub trait Handler: Send + Sync {
@@ -27,36 +91,982 @@ where
     }
 }
 
+type Params = Vec<(String, String)>;
+
+// How a route receives the request body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BodyPolicy {
+    // the handler reads req.body as it arrives, e.g. to stream uploads
+    #[default]
+    Stream,
+    // the body is read into memory before the handler runs, up to
+    // --max-body-bytes, and is available from req.buffered()
+    Buffer,
+}
+
+struct Route {
+    method: Method,
+    // the pattern as registered, used for metrics labels
+    pat: String,
+    // set by Router::name, for url_for
+    name: Option<String>,
+    re: Regex,
+    // see specificity
+    rank: Vec<u8>,
+    body: BodyPolicy,
+    handler: Box<dyn Handler>,
+    middleware: Vec<Arc<dyn MiddlewareFactory>>,
+}
+
+impl Route {
+    // runs the route's own middleware around the handler; these see the
+    // response before the server-wide middleware does
+    fn handle(&self, ctx: &Context, mut req: Request) -> Result<Response, HttpError> {
+        let middleware: Vec<Box<dyn Middleware>> =
+            self.middleware.iter().flat_map(|m| m.new(&req)).collect();
+        for m in &middleware {
+            m.apply_before(&mut req)?;
+        }
+        if self.body == BodyPolicy::Buffer {
+            req.buffer_body()?;
+        }
+        let mut resp = self.handler.handle(ctx, req)?;
+        for m in &middleware {
+            m.apply_after(&mut resp)?;
+        }
+        Ok(resp)
+    }
+}
+
 #[derive(Default)]
 pub struct Router {
-    routes: Vec<(Method, regex::Regex, Box<dyn Handler>)>,
+    routes: Vec<Route>,
+    // routers handling every path under a prefix, see Router::mount
+    mounts: Vec<(String, Router)>,
+    // handles requests no route matches, instead of a bare 404
+    fallback: Option<Box<dyn Handler>>,
+    // number of routes added by the last registration, which with() applies to
+    last_added: usize,
+    // all route patterns, built on first use
+    set: OnceLock<RegexSet>,
+}
+
+// Lets one handler serve several routes.
+struct Shared(Arc<dyn Handler>);
+
+impl Handler for Shared {
+    fn handle(&self, ctx: &Context, req: Request) -> Result<Response, HttpError> {
+        self.0.handle(ctx, req)
+    }
+}
+
+// Patterns starting with ^ are regexes; anything else uses the route syntax,
+// where a segment like :name captures a single path segment as a parameter
+// and a final segment like *name captures the rest of the path. Parameter
+// names are letters, digits and underscores, not starting with a digit, and
+// each may appear only once.
+fn compile(pat: &str) -> Result<Regex, String> {
+    if pat.starts_with('^') {
+        return Regex::new(pat).map_err(|err| err.to_string());
+    }
+    let mut re = String::from("^");
+    let mut names = Vec::new();
+    let segments: Vec<&str> = pat.split('/').collect();
+    for (i, segment) in segments.iter().enumerate() {
+        if i > 0 {
+            re.push('/');
+        }
+        let (name, capture) = match segment.split_at_checked(1) {
+            Some(("*", name)) if i < segments.len() - 1 => {
+                return Err(format!("wildcard *{} must be the last segment", name));
+            }
+            Some(("*", name)) => (name, ".*"),
+            Some((":", name)) => (name, "[^/]+"),
+            _ => {
+                re.push_str(&regex::escape(segment));
+                continue;
+            }
+        };
+        let valid = name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && name.starts_with(|c: char| !c.is_ascii_digit());
+        if !valid {
+            return Err(format!("invalid parameter name {:?}", name));
+        }
+        if names.contains(&name) {
+            return Err(format!("parameter {} appears twice", name));
+        }
+        names.push(name);
+        re.push_str(&format!("(?P<{}>{})", name, capture));
+    }
+    re.push('$');
+    Regex::new(&re).map_err(|err| err.to_string())
+}
+
+// Routes are registered while building the server, so a bad pattern is a
+// bug to report right away.
+fn compile_route(pat: &str) -> Regex {
+    compile(pat).unwrap_or_else(|err| panic!("invalid route {}: {}", pat, err))
+}
+
+// Orders route patterns when several match a path: compared segment by
+// segment, literal segments beat :params, which beat a *wildcard, and a longer
+// pattern beats its own prefix. Regex patterns rank below everything else.
+fn specificity(pat: &str) -> Vec<u8> {
+    if pat.starts_with('^') {
+        return Vec::new();
+    }
+    pat.split('/')
+        .map(|segment| match segment.chars().next() {
+            Some('*') => 1,
+            Some(':') => 2,
+            _ => 3,
+        })
+        .collect()
 }
 
 impl Router {
-    pub fn route<H: Into<Box<dyn Handler>>>(
+    pub fn route<H: IntoHandler<M>, M>(mut self, method: Method, pat: &str, handler: H) -> Self {
+        let route = Route {
+            method,
+            pat: pat.to_owned(),
+            name: None,
+            re: compile_route(pat),
+            rank: specificity(pat),
+            body: BodyPolicy::Stream,
+            handler: handler.into_handler(),
+            middleware: Vec::new(),
+        };
+        self.routes.push(route);
+        self.set = OnceLock::new();
+        self.last_added = 1;
+        self
+    }
+
+    // Registers |handler| for each of |methods| on the same pattern.
+    pub fn methods<H: IntoHandler<M>, M>(
         mut self,
-        method: Method,
+        methods: &[Method],
         pat: &str,
         handler: H,
     ) -> Self {
-        self.routes.push((method, Regex::new(pat).unwrap(), handler.into()));
+        let handler: Arc<dyn Handler> = Arc::from(handler.into_handler());
+        for method in methods {
+            self = self.route(method.clone(), pat, Shared(Arc::clone(&handler)));
+        }
+        self.last_added = methods.len();
         self
     }
+
+    pub fn get<H: IntoHandler<M>, M>(self, pat: &str, handler: H) -> Self {
+        self.route(Method::Get, pat, handler)
+    }
+
+    pub fn post<H: IntoHandler<M>, M>(self, pat: &str, handler: H) -> Self {
+        self.route(Method::Post, pat, handler)
+    }
+
+    pub fn put<H: IntoHandler<M>, M>(self, pat: &str, handler: H) -> Self {
+        self.route(Method::Put, pat, handler)
+    }
+
+    pub fn delete<H: IntoHandler<M>, M>(self, pat: &str, handler: H) -> Self {
+        self.route(Method::Delete, pat, handler)
+    }
+
+    pub fn patch<H: IntoHandler<M>, M>(self, pat: &str, handler: H) -> Self {
+        self.route(Method::Patch, pat, handler)
+    }
+
+    pub fn options<H: IntoHandler<M>, M>(self, pat: &str, handler: H) -> Self {
+        self.route(Method::Options, pat, handler)
+    }
+
+    // Registers the routes added by |f| under |prefix|, all sharing the
+    // scope's middleware.
+    pub fn scope<F: FnOnce(Scope) -> Scope>(mut self, prefix: &str, f: F) -> Self {
+        let scope = f(Scope { router: Router::default(), middleware: Vec::new() });
+        self.last_added = 0;
+        self.set = OnceLock::new();
+        let prefix = prefix.trim_end_matches('/');
+        for route in scope.router.routes {
+            let pat = match route.pat.strip_prefix('^') {
+                Some(re) => format!("^{}{}", regex::escape(prefix), re),
+                None => format!("{}{}", prefix, route.pat),
+            };
+            let mut middleware = scope.middleware.clone();
+            middleware.extend(route.middleware);
+            let (re, rank) = (compile_route(&pat), specificity(&pat));
+            self.routes.push(Route { pat, re, rank, middleware, ..route });
+            self.last_added += 1;
+        }
+        self
+    }
+
+    pub fn fallback<H: IntoHandler<M>, M>(mut self, handler: H) -> Self {
+        self.fallback = Some(handler.into_handler());
+        self
+    }
+
+    // Names the routes added by the most recent registration, so links to
+    // them can be built with url_for.
+    pub fn name(mut self, name: &str) -> Self {
+        assert!(self.last_added > 0, "name() called before route()");
+        assert!(!self.urls().0.contains_key(name), "duplicate route name: {}", name);
+        let start = self.routes.len() - self.last_added;
+        for route in &mut self.routes[start..] {
+            route.name = Some(name.to_owned());
+        }
+        self
+    }
+
+    // Sets how the routes added by the most recent registration receive
+    // request bodies.
+    pub fn body(mut self, policy: BodyPolicy) -> Self {
+        assert!(self.last_added > 0, "body() called before route()");
+        let start = self.routes.len() - self.last_added;
+        for route in &mut self.routes[start..] {
+            route.body = policy;
+        }
+        self
+    }
+
+    // Adds middleware to the routes added by the most recent registration only.
+    pub fn with<M: MiddlewareFactory + 'static>(mut self, middleware: M) -> Self {
+        assert!(self.last_added > 0, "with() called before route()");
+        let middleware: Arc<dyn MiddlewareFactory> = Arc::new(middleware);
+        let start = self.routes.len() - self.last_added;
+        for route in &mut self.routes[start..] {
+            route.middleware.push(Arc::clone(&middleware));
+        }
+        self
+    }
+}
+
+// Routes sharing a path prefix and middleware, see Router::scope.
+pub struct Scope {
+    router: Router,
+    middleware: Vec<Arc<dyn MiddlewareFactory>>,
 }
 
-fn match_pat(pat: &Regex, str: &str) -> Option<Vec<Option<String>>> {
-    Some(pat.captures(str)?.iter().map(|x| x.map(|m| m.as_str().to_owned())).collect())
+impl Scope {
+    pub fn route<H: IntoHandler<M>, M>(mut self, method: Method, pat: &str, handler: H) -> Self {
+        self.router = self.router.route(method, pat, handler);
+        self
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.router = self.router.name(name);
+        self
+    }
+
+    // Adds middleware to every route in the scope.
+    pub fn with<M: MiddlewareFactory + 'static>(mut self, middleware: M) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+}
+
+impl Router {
+    // Delegates paths under |prefix| to |router|, which sees them with the
+    // prefix stripped. Routes registered directly on this router win.
+    pub fn mount(mut self, prefix: &str, mut router: Router) -> Self {
+        let prefix = prefix.trim_end_matches('/');
+        router.prefix_labels(prefix);
+        self.mounts.push((prefix.to_owned(), router));
+        // so names clashing with the mounted router's fail here
+        self.urls();
+        self
+    }
+
+    // The named routes of this router and everything mounted on it.
+    pub fn urls(&self) -> Urls {
+        let mut urls = Urls::default();
+        self.collect_urls(&mut urls);
+        urls
+    }
+
+    fn collect_urls(&self, urls: &mut Urls) {
+        for route in &self.routes {
+            if let Some(name) = &route.name {
+                urls.0.insert(name.clone(), route.pat.clone());
+            }
+        }
+        // url_for can only link to one of them, so names must be unique
+        // across mounts too
+        for (prefix, router) in &self.mounts {
+            let mut mounted = Urls::default();
+            router.collect_urls(&mut mounted);
+            for (name, pat) in mounted.0 {
+                if urls.0.contains_key(&name) {
+                    panic!("duplicate route name: {} (mounted at {})", name, prefix);
+                }
+                urls.0.insert(name, pat);
+            }
+        }
+    }
+
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Option<String> {
+        self.urls().url_for(name, params)
+    }
+
+    fn prefix_labels(&mut self, prefix: &str) {
+        for route in &mut self.routes {
+            route.pat = format!("{}{}", prefix, route.pat);
+        }
+        for (_, router) in &mut self.mounts {
+            router.prefix_labels(prefix);
+        }
+    }
+
+    // the mounted router responsible for |path| and the path it should see
+    fn find_mount(&self, path: &str) -> Option<(&Router, String)> {
+        self.mounts.iter().find_map(|(prefix, router)| {
+            let rest = path.strip_prefix(prefix.as_str())?;
+            match rest {
+                "" => Some((router, "/".to_string())),
+                _ if rest.starts_with('/') => Some((router, rest.to_string())),
+                _ => None,
+            }
+        })
+    }
+
+    // The pattern of the route that would serve |method| |path|, and the
+    // parameters it would see, following mounts.
+    pub fn lookup(&self, method: Method, path: &str) -> Option<(&str, Params)> {
+        if let Some((_, params, route)) = self.find(&method, path) {
+            return Some((&route.pat, params));
+        }
+        let (router, path) = self.find_mount(path)?;
+        router.lookup(method, &path)
+    }
+
+    // Routes whose pattern matches |path|, in registration order. All patterns
+    // are tested in a single pass, so only the winning route's regex has to be
+    // run again to extract captures.
+    fn matching(&self, path: &str) -> impl Iterator<Item = &Route> {
+        let set = self
+            .set
+            .get_or_init(|| RegexSet::new(self.routes.iter().map(|r| r.re.as_str())).unwrap());
+        set.matches(path).into_iter().map(|i| &self.routes[i])
+    }
+
+    fn find(&self, method: &Method, path: &str) -> Option<(Vec<Option<String>>, Params, &Route)> {
+        // the most specific route wins, ties going to the first registered
+        let route = self.matching(path).filter(|r| r.method == *method).reduce(|best, r| {
+            if r.rank > best.rank {
+                r
+            } else {
+                best
+            }
+        })?;
+        let (caps, params) = match_pat(&route.re, path)?;
+        Some((caps, params, route))
+    }
+
+    // methods registered for any pattern matching |path|, in registration order
+    fn allowed(&self, path: &str) -> Vec<Method> {
+        let mut methods = Vec::new();
+        for route in self.matching(path) {
+            if !methods.contains(&route.method) {
+                methods.push(route.method.clone());
+            }
+        }
+        if methods.contains(&Method::Get) && !methods.contains(&Method::Head) {
+            methods.push(Method::Head);
+        }
+        methods
+    }
+}
+
+// Route patterns by name, see Router::name.
+#[derive(Default, Clone)]
+pub struct Urls(HashMap<String, String>);
+
+impl Urls {
+    // Builds the path for the route |name| by substituting |params| into its
+    // pattern. Returns None for unknown names, missing parameters, and routes
+    // registered as raw regexes, which can't be reversed.
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Option<String> {
+        let pat = self.0.get(name)?;
+        if pat.starts_with('^') {
+            return None;
+        }
+        let param = |name: &str| params.iter().find(|(k, _)| *k == name).map(|(_, v)| *v);
+        let mut url = String::new();
+        for (i, segment) in pat.split('/').enumerate() {
+            if i > 0 {
+                url.push('/');
+            }
+            if let Some(name) = segment.strip_prefix('*') {
+                url.push_str(&encode(param(name)?, true));
+            } else if let Some(name) = segment.strip_prefix(':') {
+                url.push_str(&encode(param(name)?, false));
+            } else {
+                url.push_str(segment);
+            }
+        }
+        Some(url)
+    }
+}
+
+// percent-encodes everything but unreserved characters (and slashes, for
+// wildcard segments)
+fn encode(value: &str, keep_slash: bool) -> String {
+    let mut s = String::new();
+    for b in value.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                s.push(b as char)
+            }
+            b'/' if keep_slash => s.push('/'),
+            _ => write!(s, "%{:02X}", b).unwrap(),
+        }
+    }
+    s
+}
+
+fn match_pat(pat: &Regex, str: &str) -> Option<(Vec<Option<String>>, Params)> {
+    let caps = pat.captures(str)?;
+    let matches = caps.iter().map(|x| x.map(|m| m.as_str().to_owned())).collect();
+    let params = pat
+        .capture_names()
+        .flatten()
+        .filter_map(|name| caps.name(name).map(|m| (name.to_owned(), m.as_str().to_owned())))
+        .collect();
+    Some((matches, params))
+}
+
+fn status_of(result: &Result<Response, HttpError>) -> HttpStatus {
+    match result {
+        Ok(resp) => resp.status,
+        Err(HttpError(status)) => *status,
+    }
+}
+
+// label with the route pattern rather than the path to keep cardinality bounded
+fn record(ctx: &Context, method: &str, route: &str, status: HttpStatus, start: Option<Instant>) {
+    let status = status.code().to_string();
+    let labels = [("method", method), ("route", route), ("status", status.as_str())];
+    ctx.metrics.increment("http_requests_total", &labels);
+    if let Some(start) = start {
+        let labels = [("method", method), ("route", route)];
+        ctx.metrics.observe(
+            "http_request_duration_seconds",
+            &labels,
+            start.elapsed().as_secs_f64(),
+        );
+    }
 }
 
 impl Handler for Router {
+    fn handle(&self, ctx: &Context, mut req: Request) -> Result<Response, HttpError> {
+        let method = req.method.to_string();
+        // HEAD falls back to the GET handler; the server drops the body
+        let found = self.find(&req.method, &req.path).or_else(|| match req.method {
+            Method::Head => self.find(&Method::Get, &req.path),
+            _ => None,
+        });
+        let Some((matches, params, route)) = found else {
+            if let Some((router, path)) = self.find_mount(&req.path) {
+                if !req.extensions.contains::<UnmountedPath>() {
+                    req.extensions.insert(UnmountedPath(req.path.clone()));
+                }
+                req.path = path;
+                return router.handle(ctx, req);
+            }
+            // an extension method no route was registered for
+            if let Method::Other(_) = req.method {
+                record(ctx, &method, "", HttpStatus::NotImplemented, None);
+                return Err(HttpError(HttpStatus::NotImplemented));
+            }
+            let allowed = self.allowed(&req.path);
+            if allowed.is_empty() {
+                let Some(fallback) = &self.fallback else {
+                    record(ctx, &method, "", HttpStatus::NotFound, None);
+                    return Err(HttpError(HttpStatus::NotFound));
+                };
+                let result = fallback.handle(ctx, req);
+                record(ctx, &method, "", status_of(&result), None);
+                return result;
+            }
+            record(ctx, &method, "", HttpStatus::MethodNotAllowed, None);
+            let allow = allowed.iter().map(|m| m.to_string()).collect::<Vec<_>>().join(", ");
+            let mut resp = Response::empty();
+            resp.status = HttpStatus::MethodNotAllowed;
+            resp.set_header("allow".to_string(), allow);
+            return Ok(resp);
+        };
+
+        if let Some(RouteSlot(slot)) = req.extensions.get::<RouteSlot>() {
+            let _ = slot.set(route.pat.clone());
+        }
+        let start = Instant::now();
+        let result = route.handle(ctx, req.with_matches(matches).with_params(params));
+        record(ctx, &method, &route.pat, status_of(&result), Some(start));
+        result
+    }
+}
+
+// Where the Router leaves the pattern of the route it picked, for the server
+// to log once the request, and its extensions with it, are gone.
+#[derive(Clone, Default)]
+pub(crate) struct RouteSlot(pub(crate) Arc<OnceLock<String>>);
+
+// The normalized path before the first mount shortened it, for handlers
+// that need to refer back to the whole of it, like StaticFiles's redirects.
+struct UnmountedPath(String);
+
+// Serves the files under |root| for GET and HEAD, typed by extension, see
+// Response::send_file. The path served is the request's, or the last route
+// parameter when routed to from a pattern like /static/*path. Directories
+// serve their index.html, or a listing if enabled, and are 403s otherwise.
+pub struct StaticFiles {
+    root: PathBuf,
+    listing: bool,
+    // see spa_fallback
+    spa_index: Option<PathBuf>,
+    spa_excluded: Vec<String>,
+}
+
+impl StaticFiles {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into(), listing: false, spa_index: None, spa_excluded: Vec::new() }
+    }
+
+    // Lists the contents of directories without an index.html.
+    pub fn directory_listing(mut self, enabled: bool) -> Self {
+        self.listing = enabled;
+        self
+    }
+
+    // Serves |index|, relative to the root, with a 200 for paths with no file
+    // or directory, so a single-page app can route them in the browser. Only
+    // requests whose Accept names text/html get it: a bare */* is what
+    // scripts, images and fetch() send, whose misses should stay 404s.
+    pub fn spa_fallback(mut self, index: impl Into<PathBuf>) -> Self {
+        self.spa_index = Some(index.into());
+        self
+    }
+
+    // Keeps the paths under |prefix|, e.g. /api, out of the spa_fallback.
+    pub fn spa_exclude(mut self, prefix: &str) -> Self {
+        self.spa_excluded.push(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    // The spa_fallback's index, if |req| should get it.
+    fn spa_index(&self, req: &Request) -> Option<&Path> {
+        let index = self.spa_index.as_deref()?;
+        let path = unmounted_path(req);
+        let excluded = self.spa_excluded.iter().any(|prefix| {
+            path.strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        });
+        let accept = parse_quality_list(req.get_header("accept").unwrap_or_default());
+        let html = match accept.iter().any(|item| item.value == "text/html") {
+            true => quality(&accept, "text/html"),
+            false => quality(&accept, "text/*"),
+        };
+        (!excluded && html > 0.0).then_some(index)
+    }
+
+    // The directory |path| names, if it's one within |root|.
+    fn dir(&self, path: &str) -> Option<PathBuf> {
+        if !Path::new(path).components().all(|c| matches!(c, Component::Normal(_))) {
+            return None;
+        }
+        let root = self.root.canonicalize().ok()?;
+        let dir = root.join(path).canonicalize().ok()?;
+        (dir.starts_with(&root) && dir.is_dir()).then_some(dir)
+    }
+
+    fn serve_dir(&self, ctx: &Context, req: &Request, dir: &Path) -> Result<Response, HttpError> {
+        let index = dir.join("index.html");
+        if !index.is_file() && !self.listing {
+            return Err(HttpError(HttpStatus::Forbidden));
+        }
+        // with empty leading segments dropped: a Location of //host/ would
+        // leave this server
+        let path = format!("/{}", unmounted_path(req).trim_start_matches('/'));
+        // so relative links resolve inside the directory
+        if !path.ends_with('/') {
+            // the only escapes left in a normalized path are %2F and %25
+            let sent = encode(&path, true).replace("%25", "%");
+            let location = match &req.query {
+                Some(query) => format!("{}/?{}", sent, query),
+                None => format!("{}/", sent),
+            };
+            return Ok(Response::redirect_permanent(&location));
+        }
+        if index.is_file() {
+            return Response::file(index, &ctx.mime_types);
+        }
+        listing(dir, &path).map_err(|_| HttpError(HttpStatus::Forbidden))
+    }
+}
+
+// The whole of |req|'s path, since a mount shortens req.path.
+fn unmounted_path<'r>(req: &'r Request) -> &'r str {
+    match req.extensions.get::<UnmountedPath>() {
+        Some(UnmountedPath(path)) => path,
+        None => &req.path,
+    }
+}
+
+// An HTML page listing the entries of |dir|, served at |path|. Entries that
+// can't be read, e.g. removed while listing, are left out.
+fn listing(dir: &Path, path: &str) -> io::Result<Response> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)?.flatten() {
+        let mut name = entry.file_name().to_string_lossy().into_owned();
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let size = match metadata.is_dir() {
+            true => {
+                name.push('/');
+                "-".to_string()
+            }
+            false => metadata.len().to_string(),
+        };
+        let modified = metadata.modified().map(http_date).unwrap_or_default();
+        entries.push((name, size, modified));
+    }
+    entries.sort();
+
+    let title = escape_html(&format!("Index of {}", path));
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html><head><title>{title}</title></head><body>\n<h1>{title}</h1>\n\
+         <table>\n<tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n"
+    );
+    if path != "/" {
+        page.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for (name, size, modified) in entries {
+        let href = encode(&name, true);
+        let name = escape_html(&name);
+        writeln!(
+            page,
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>",
+            href, name, size, modified
+        )
+        .unwrap();
+    }
+    page.push_str("</table>\n</body></html>\n");
+    Ok(Response::builder().header("content-type", "text/html; charset=utf-8").body(page))
+}
+
+impl Handler for StaticFiles {
     fn handle(&self, ctx: &Context, req: Request) -> Result<Response, HttpError> {
-        let (matches, h) = self
-            .routes
-            .iter()
-            .filter(|(method, ..)| *method == req.method)
-            .filter_map(|(_, pat, h)| match_pat(pat, &req.path).map(|caps| (caps, h)))
-            .next()
-            .ok_or(HttpError(HttpStatus::NotFound))?;
-        h.handle(ctx, req.with_matches(matches))
+        if !matches!(req.method, Method::Get | Method::Head) {
+            let mut resp = Response::empty();
+            resp.status = HttpStatus::MethodNotAllowed;
+            resp.set_header("allow".to_string(), "GET, HEAD".to_string());
+            return Ok(resp);
+        }
+        let path = match req.params().last() {
+            Some((_, path)) => path.as_str(),
+            None => req.path.trim_start_matches('/'),
+        };
+        match Response::send_file(&self.root, path, &ctx.mime_types) {
+            Err(HttpError(HttpStatus::NotFound)) => match self.dir(path) {
+                Some(dir) => self.serve_dir(ctx, &req, &dir),
+                None if self.spa_index.is_some() => {
+                    let mut resp = match self.spa_index(&req) {
+                        Some(index) => Response::send_file(&self.root, index, &ctx.mime_types)?,
+                        None => Response::builder().status(HttpStatus::NotFound).build(),
+                    };
+                    // either way, as the index and the 404 depend on what the
+                    // client accepts, and caches mustn't give one for the other
+                    resp.set_header("vary".to_string(), "Accept".to_string());
+                    Ok(resp)
+                }
+                None => Err(HttpError(HttpStatus::NotFound)),
+            },
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        thread,
+    };
+
+    use super::*;
+    use crate::{Config, Server, TempDir};
+
+    #[test]
+    fn test_compile() {
+        let re = compile("/echo/:message").unwrap();
+        assert!(re.is_match("/echo/foo"));
+        assert!(!re.is_match("/echo/foo/bar"));
+        assert!(!re.is_match("/echo/"));
+
+        let re = compile("/static/*path").unwrap();
+        let (_, params) = match_pat(&re, "/static/css/site.css").unwrap();
+        assert_eq!(params, vec![("path".to_string(), "css/site.css".to_string())]);
+        assert!(re.is_match("/static/"));
+        assert!(!re.is_match("/other/x"));
+
+        let re = compile("/a.b").unwrap();
+        assert!(!re.is_match("/axb"));
+
+        for pat in ["/users/:foo-bar", "/users/:", "/users/:1st", "/files/*rest/x", "/:a/:a", "^("]
+        {
+            assert!(compile(pat).is_err(), "{}", pat);
+        }
+        let err = compile("/users/:foo-bar").unwrap_err();
+        assert_eq!(err, r#"invalid parameter name "foo-bar""#);
+    }
+
+    #[test]
+    fn test_scope() {
+        let router =
+            Router::default().scope("/admin/", |s| {
+                s.route(Method::Get, "/users/:id", |_ctx: &Context, _req: Request| {
+                    Ok(Response::empty())
+                })
+                .route(Method::Get, "^/raw$", |_ctx: &Context, _req: Request| Ok(Response::empty()))
+            });
+        assert!(router.find(&Method::Get, "/admin/users/1").is_some());
+        assert!(router.find(&Method::Get, "/users/1").is_none());
+        assert!(router.find(&Method::Get, "/admin/raw").is_some());
+        assert_eq!(router.routes[0].pat, "/admin/users/:id");
+    }
+
+    #[test]
+    fn test_methods() {
+        let router = Router::default()
+            .methods(&[Method::Put, Method::Patch], "/item", |_ctx: &Context, _req: Request| {
+                Ok(Response::empty())
+            })
+            .get("/other", |_ctx: &Context, _req: Request| Ok(Response::empty()));
+        assert!(router.find(&Method::Put, "/item").is_some());
+        assert!(router.find(&Method::Patch, "/item").is_some());
+        assert!(router.find(&Method::Get, "/item").is_none());
+        assert_eq!(router.allowed("/item"), vec![Method::Put, Method::Patch]);
+    }
+
+    #[test]
+    fn test_find_mount() {
+        let api =
+            Router::default().route(Method::Get, "/users/:id", |_ctx: &Context, _req: Request| {
+                Ok(Response::empty())
+            });
+        let router = Router::default().mount("/api/", api);
+        let (api, path) = router.find_mount("/api/users/7").unwrap();
+        assert_eq!(path, "/users/7");
+        assert_eq!(api.routes[0].pat, "/api/users/:id");
+        assert_eq!(router.find_mount("/api").unwrap().1, "/");
+        assert!(router.find_mount("/apix").is_none());
+    }
+
+    #[test]
+    fn test_url_for() {
+        let ok = |_ctx: &Context, _req: Request| Ok(Response::empty());
+        let api = Router::default().get("/users/:id", ok).name("user");
+        let router = Router::default()
+            .get("/files/*path", ok)
+            .name("file")
+            .get("^/raw$", ok)
+            .name("raw")
+            .scope("/admin/", |s| s.route(Method::Get, "/", ok).name("admin"))
+            .mount("/api/", api);
+        assert_eq!(router.url_for("user", &[("id", "7")]).unwrap(), "/api/users/7");
+        assert_eq!(router.url_for("user", &[("id", "a b/c")]).unwrap(), "/api/users/a%20b%2Fc");
+        assert_eq!(router.url_for("file", &[("path", "css/x.css")]).unwrap(), "/files/css/x.css");
+        assert_eq!(router.url_for("admin", &[]).unwrap(), "/admin/");
+        assert!(router.url_for("user", &[]).is_none());
+        assert!(router.url_for("raw", &[]).is_none());
+        assert!(router.url_for("missing", &[]).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "duplicate route name: index (mounted at /b)")]
+    fn test_duplicate_mounted_names() {
+        let ok = |_ctx: &Context, _req: Request| Ok(Response::empty());
+        let a = Router::default().get("/", ok).name("index");
+        let b = Router::default().get("/", ok).name("index");
+        let _ = Router::default().mount("/a/", a).mount("/b/", b);
+    }
+
+    #[test]
+    #[should_panic(expected = "duplicate route name: index")]
+    fn test_name_clashing_with_mount() {
+        let ok = |_ctx: &Context, _req: Request| Ok(Response::empty());
+        let a = Router::default().get("/", ok).name("index");
+        let _ = Router::default().mount("/a/", a).get("/", ok).name("index");
+    }
+
+    #[test]
+    fn test_specificity() {
+        let ok = |_ctx: &Context, _req: Request| Ok(Response::empty());
+        let router = Router::default()
+            .get("/files/*path", ok)
+            .get("/files/:name", ok)
+            .get("/files/readme", ok)
+            .get("^/files/.*$", ok);
+        assert_eq!(router.lookup(Method::Get, "/files/readme").unwrap().0, "/files/readme");
+        assert_eq!(router.lookup(Method::Get, "/files/other").unwrap().0, "/files/:name");
+        assert_eq!(router.lookup(Method::Get, "/files/a/b").unwrap().0, "/files/*path");
+    }
+
+    // The response to a GET for |target|, sent as is: clients like reqwest
+    // resolve .. segments before they're sent.
+    fn raw_response(addr: &str, target: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", target).unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        resp
+    }
+
+    fn raw_get(addr: &str, target: &str) -> String {
+        raw_response(addr, target).split(' ').nth(1).unwrap_or_default().to_string()
+    }
+
+    #[test]
+    fn test_static_files() {
+        let base = TempDir::new("static-files").unwrap();
+        let dir = base.join("public");
+        fs::create_dir_all(dir.join("css")).unwrap();
+        fs::write(dir.join("css/site.css"), "body{}").unwrap();
+        fs::write(dir.join("index.html"), "<p>hi</p>").unwrap();
+        fs::write(base.join("secret.txt"), "secret").unwrap();
+        let router = Router::default()
+            .get("/static/*path", StaticFiles::new(&dir))
+            .post("/static/*path", StaticFiles::new(&dir))
+            .get("/listed/*path", StaticFiles::new(&dir).directory_listing(true));
+        let config = Config { port: 0, ..Config::default() };
+        let server = Arc::new(Server::builder(config).router(router).start());
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let get = |path: &str| {
+            reqwest::blocking::get(format!("http://{}/static/{}", server.addr(), path)).unwrap()
+        };
+        let resp = get("css/site.css");
+        assert_eq!(resp.headers()["content-type"], "text/css");
+        assert_eq!(resp.text().unwrap(), "body{}");
+        assert_eq!(get("index.html").headers()["content-type"], "text/html");
+        assert_eq!(get("missing.txt").status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(get("css").status(), reqwest::StatusCode::FORBIDDEN);
+        let url = format!("http://{}/static/index.html", server.addr());
+        let resp = reqwest::blocking::Client::new().post(url).send().unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::METHOD_NOT_ALLOWED);
+
+        // the file outside the root is reachable by neither .. nor a symlink
+        // the server resolves .. segments, so they can't climb past the route
+        assert_eq!(raw_get(server.addr(), "/static/css/../index.html"), "200");
+        assert_eq!(raw_get(server.addr(), "/static/../secret.txt"), "404");
+        assert_eq!(raw_get(server.addr(), "/static/css/../../secret.txt"), "404");
+        assert_eq!(raw_get(server.addr(), "/static/..%2fsecret.txt"), "404");
+        assert_eq!(raw_get(server.addr(), "/listed/.."), "404");
+        // nor past the root when they reach the handler unresolved
+        let types = MimeTypes::default();
+        for path in ["../secret.txt", "css/../../secret.txt", "/etc/passwd"] {
+            let resp = Response::send_file(&dir, path, &types);
+            assert_eq!(resp.err().map(|err| err.0), Some(HttpStatus::NotFound), "{}", path);
+            assert_eq!(StaticFiles::new(&dir).dir(path), None, "{}", path);
+        }
+        assert_eq!(StaticFiles::new(&dir).dir(".."), None);
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(base.join("secret.txt"), dir.join("secret.txt")).unwrap();
+            std::os::unix::fs::symlink(base.path(), dir.join("up")).unwrap();
+            assert_eq!(raw_get(server.addr(), "/static/secret.txt"), "404");
+            assert_eq!(raw_get(server.addr(), "/static/up/secret.txt"), "404");
+            assert_eq!(raw_get(server.addr(), "/listed/up"), "404");
+            // links that stay inside the root are followed
+            std::os::unix::fs::symlink(dir.join("css"), dir.join("styles")).unwrap();
+            assert_eq!(raw_get(server.addr(), "/static/styles/site.css"), "200");
+        }
+    }
+
+    #[test]
+    fn test_static_files_redirect() {
+        let dir = TempDir::new("static-redirect").unwrap();
+        fs::create_dir_all(dir.join("d")).unwrap();
+        fs::create_dir_all(dir.join("my docs")).unwrap();
+        fs::write(dir.join("index.html"), "<p>hi</p>").unwrap();
+        let router =
+            Router::default().fallback(StaticFiles::new(dir.path()).directory_listing(true));
+        let config = Config { port: 0, ..Config::default() };
+        let server = Arc::new(Server::builder(config).router(router).start());
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let location = |target: &str| {
+            let resp = raw_response(server.addr(), target);
+            let location = resp.lines().find_map(|line| line.strip_prefix("location: "));
+            (resp.split(' ').nth(1).unwrap().to_string(), location.map(str::to_string))
+        };
+        // redirects stay on this server, rather than going to //evil.com/
+        assert_eq!(location("//evil.com/.."), ("200".to_string(), None));
+        assert_eq!(location("//d"), ("301".to_string(), Some("/d/".to_string())));
+        assert_eq!(location("/d?x=1"), ("301".to_string(), Some("/d/?x=1".to_string())));
+        assert_eq!(location("/my%20docs"), ("301".to_string(), Some("/my%20docs/".to_string())));
+    }
+
+    #[test]
+    fn test_spa_fallback() {
+        let dir = TempDir::new("spa").unwrap();
+        fs::write(dir.join("index.html"), "<div id=app></div>").unwrap();
+        fs::write(dir.join("app.js"), "render()").unwrap();
+        let files = StaticFiles::new(dir.path()).spa_fallback("index.html").spa_exclude("/api/");
+        let router = Router::default()
+            .get("/api/users", |_ctx: &Context, _req: Request| Ok(Response::plain_text("[]")))
+            .fallback(files);
+        let config = Config { port: 0, ..Config::default() };
+        let server = Arc::new(Server::builder(config).router(router).start());
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let client = reqwest::blocking::Client::new();
+        let send = |method: reqwest::Method, path: &str, accept: &str| {
+            let url = format!("http://{}{}", server.addr(), path);
+            client.request(method, url).header("accept", accept).send().unwrap()
+        };
+        let get = |path: &str, accept: &str| send(reqwest::Method::GET, path, accept);
+        let browser = "text/html,application/xhtml+xml,*/*;q=0.8";
+        let varies_on_accept = |resp: &reqwest::blocking::Response| {
+            let vary = resp.headers()["vary"].to_str().unwrap();
+            vary.split(',').any(|v| v.trim() == "Accept")
+        };
+
+        // routes the app handles in the browser
+        let resp = get("/settings/profile", browser);
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], "text/html");
+        assert!(varies_on_accept(&resp));
+        assert_eq!(resp.text().unwrap(), "<div id=app></div>");
+        assert_eq!(get("/settings", "text/*").status(), reqwest::StatusCode::OK);
+        let resp = send(reqwest::Method::HEAD, "/settings", browser);
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        assert_eq!(resp.text().unwrap(), "");
+
+        // files that exist are served as they are
+        assert_eq!(get("/app.js", "*/*").text().unwrap(), "render()");
+        assert_eq!(get("/app.js", browser).text().unwrap(), "render()");
+
+        // and everything else is still a 404: assets that are missing...
+        let resp = get("/missing.js", "*/*");
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+        // so a cache can't answer a navigation to the same path with it
+        assert!(varies_on_accept(&resp));
+        assert_eq!(get("/settings", "application/json").status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(get("/settings", "text/html;q=0, */*").status(), reqwest::StatusCode::NOT_FOUND);
+        // ...paths under the excluded prefixes...
+        assert_eq!(get("/api/users", browser).text().unwrap(), "[]");
+        assert_eq!(get("/api/missing", browser).status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(get("/api", browser).status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(get("/apiary", browser).status(), reqwest::StatusCode::OK);
+        // ...and other methods
+        let resp = send(reqwest::Method::POST, "/settings", browser);
+        assert_eq!(resp.status(), reqwest::StatusCode::METHOD_NOT_ALLOWED);
     }
 }
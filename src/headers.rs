@@ -0,0 +1,321 @@
+use std::fmt::Display;
+
+use crate::{base64, ByteRange, Request};
+
+// Splits a header value on |sep|, except inside quoted strings, leaving the
+// fields as they were sent, quotes and all.
+pub fn split_quoted(value: &str, sep: char) -> impl Iterator<Item = &str> {
+    let (mut quoted, mut escaped) = (false, false);
+    let mut start = 0;
+    let mut ends = Vec::new();
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            _ if c == sep && !quoted => ends.push(i),
+            _ => {}
+        }
+    }
+    ends.push(value.len());
+    ends.into_iter().map(move |end| {
+        let field = &value[start..end];
+        start = end + sep.len_utf8();
+        field
+    })
+}
+
+// Removes the quotes and backslash escapes of the quoted strings in |field|.
+fn unquote(field: &str) -> String {
+    let mut out = String::new();
+    let (mut quoted, mut escaped) = (false, false);
+    for c in field.chars() {
+        match c {
+            _ if escaped => {
+                out.push(c);
+                escaped = false;
+            }
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+// Splits a header value like `form-data; name="a"; filename="b.txt"` into
+// its leading value and parameters. Parameter names are lowercased and quoted
+// values unquoted.
+pub fn parse_params(value: &str) -> (String, Vec<(String, String)>) {
+    let mut fields = split_quoted(value, ';').map(unquote);
+    let first = fields.next().unwrap_or_default().trim().to_owned();
+    let params = fields
+        .filter_map(|f| {
+            let (k, v) = f.split_once('=')?;
+            Some((k.trim().to_ascii_lowercase(), v.trim().to_owned()))
+        })
+        .collect();
+    (first, params)
+}
+
+// One element of a list like Accept or Accept-Encoding and its q-value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityItem {
+    pub value: String,
+    pub q: f32,
+}
+
+// Parses a comma-separated list with optional q-values, most preferred first.
+// Elements with a malformed q-value are dropped.
+pub fn parse_quality_list(value: &str) -> Vec<QualityItem> {
+    let mut items: Vec<QualityItem> = split_quoted(value, ',')
+        .filter(|item| !item.trim().is_empty())
+        .filter_map(|item| {
+            let (value, params) = parse_params(item);
+            let q = match params.iter().find(|(k, _)| k == "q") {
+                Some((_, q)) => q.parse().ok().filter(|q| (0.0..=1.0).contains(q))?,
+                None => 1.0,
+            };
+            Some(QualityItem { value: value.to_ascii_lowercase(), q })
+        })
+        .collect();
+    // stable, so equally preferred items keep the client's order
+    items.sort_by(|a, b| b.q.total_cmp(&a.q));
+    items
+}
+
+// The q-value |items| give |value|, falling back to a * wildcard; 0 means
+// refused. |items| must come from parse_quality_list.
+pub fn quality(items: &[QualityItem], value: &str) -> f32 {
+    let find = |v: &str| items.iter().find(|i| i.value == v).map(|i| i.q);
+    find(&value.to_ascii_lowercase()).or_else(|| find("*")).unwrap_or(0.0)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentType {
+    // lowercased, without parameters, e.g. "text/html"
+    pub mime: String,
+    pub charset: Option<String>,
+    pub params: Vec<(String, String)>,
+}
+
+impl ContentType {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (mime, params) = parse_params(value);
+        if !mime.contains('/') {
+            return None;
+        }
+        let charset = params.iter().find(|(k, _)| k == "charset").map(|(_, v)| v.clone());
+        Some(Self { mime: mime.to_ascii_lowercase(), charset, params })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Authorization {
+    Basic { user: String, password: String },
+    Bearer(String),
+    // any other scheme, with its credentials as sent
+    Other { scheme: String, credentials: String },
+}
+
+impl Authorization {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (scheme, credentials) = value.trim().split_once(' ')?;
+        let credentials = credentials.trim();
+        if scheme.eq_ignore_ascii_case("basic") {
+            let decoded = String::from_utf8(base64::decode(credentials)?).ok()?;
+            let (user, password) = decoded.split_once(':')?;
+            Some(Self::Basic { user: user.to_owned(), password: password.to_owned() })
+        } else if scheme.eq_ignore_ascii_case("bearer") {
+            Some(Self::Bearer(credentials.to_owned()))
+        } else {
+            Some(Self::Other { scheme: scheme.to_owned(), credentials: credentials.to_owned() })
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntityTag {
+    pub weak: bool,
+    // without the quotes
+    pub tag: String,
+}
+
+impl EntityTag {
+    // e.g. "abc" or W/"abc", as sent in an ETag header
+    pub fn parse(value: &str) -> Option<Self> {
+        match parse_entity_tag(value.trim())? {
+            (tag, "") => Some(tag),
+            _ => None,
+        }
+    }
+
+    // weak comparison, as used by If-None-Match
+    pub fn weak_eq(&self, other: &EntityTag) -> bool {
+        self.tag == other.tag
+    }
+}
+
+impl Display for EntityTag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let weak = if self.weak { "W/" } else { "" };
+        write!(f, "{}\"{}\"", weak, self.tag)
+    }
+}
+
+// An entity tag at the start of |s|, and what follows it. Tags may contain
+// commas, so lists can't simply be split on them.
+fn parse_entity_tag(s: &str) -> Option<(EntityTag, &str)> {
+    let (weak, s) = match s.strip_prefix("W/") {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let s = s.strip_prefix('"')?;
+    let end = s.find('"')?;
+    let tag = &s[..end];
+    // etagc excludes controls, spaces and DEL
+    if tag.bytes().any(|b| b <= b' ' || b == 0x7f) {
+        return None;
+    }
+    Some((EntityTag { weak, tag: tag.to_owned() }, &s[end + 1..]))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IfNoneMatch {
+    Any,
+    Tags(Vec<EntityTag>),
+}
+
+impl IfNoneMatch {
+    // A comma-separated list of entity tags, or *. A * among tags, which
+    // some clients send, matches anything as well.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut tags = Vec::new();
+        let mut any = false;
+        let mut rest = value.trim_start();
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('*') {
+                any = true;
+                rest = after;
+            } else {
+                let (tag, after) = parse_entity_tag(rest)?;
+                tags.push(tag);
+                rest = after;
+            }
+            rest = rest.trim_start();
+            match rest.strip_prefix(',') {
+                Some(after) => rest = after.trim_start(),
+                None if rest.is_empty() => {}
+                None => return None,
+            }
+        }
+        match (any, tags.is_empty()) {
+            (true, _) => Some(Self::Any),
+            (false, true) => None,
+            (false, false) => Some(Self::Tags(tags)),
+        }
+    }
+
+    // whether a representation tagged |etag| matches, i.e. is unchanged
+    pub fn matches(&self, etag: &EntityTag) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Tags(tags) => tags.iter().any(|t| t.weak_eq(etag)),
+        }
+    }
+}
+
+impl Request<'_> {
+    pub fn accept(&self) -> Vec<QualityItem> {
+        self.get_header("accept").map(parse_quality_list).unwrap_or_default()
+    }
+
+    pub fn accept_encoding(&self) -> Vec<QualityItem> {
+        self.get_header("accept-encoding").map(parse_quality_list).unwrap_or_default()
+    }
+
+    pub fn content_type(&self) -> Option<ContentType> {
+        ContentType::parse(self.get_header("content-type")?)
+    }
+
+    pub fn authorization(&self) -> Option<Authorization> {
+        Authorization::parse(self.get_header("authorization")?)
+    }
+
+    pub fn range(&self) -> Option<ByteRange> {
+        ByteRange::parse(self.get_header("range")?)
+    }
+
+    pub fn if_none_match(&self) -> Option<IfNoneMatch> {
+        IfNoneMatch::parse(self.get_header("if-none-match")?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_quality_list() {
+        let items = parse_quality_list("gzip;q=0.5, br, identity;q=0, deflate;q=bad, *;q=0.1");
+        let values: Vec<&str> = items.iter().map(|i| i.value.as_str()).collect();
+        assert_eq!(values, ["br", "gzip", "*", "identity"]);
+        assert_eq!(quality(&items, "GZIP"), 0.5);
+        assert_eq!(quality(&items, "identity"), 0.0);
+        assert_eq!(quality(&items, "zstd"), 0.1);
+        assert_eq!(quality(&parse_quality_list("br"), "gzip"), 0.0);
+
+        // a quoted parameter may contain the list's separator
+        let items = parse_quality_list(r#"text/html;x="a,b\";q=0";q=0.5, text/plain"#);
+        let values: Vec<(&str, f32)> = items.iter().map(|i| (i.value.as_str(), i.q)).collect();
+        assert_eq!(values, [("text/plain", 1.0), ("text/html", 0.5)]);
+    }
+
+    #[test]
+    fn test_content_type() {
+        let ct = ContentType::parse("Text/HTML; Charset=\"utf-8\"; x=1").unwrap();
+        assert_eq!(ct.mime, "text/html");
+        assert_eq!(ct.charset.as_deref(), Some("utf-8"));
+        assert!(ContentType::parse("garbage").is_none());
+    }
+
+    #[test]
+    fn test_authorization() {
+        let basic = Authorization::parse("Basic dXNlcjpwYTpzcw==").unwrap();
+        let expected = Authorization::Basic { user: "user".into(), password: "pa:ss".into() };
+        assert_eq!(basic, expected);
+        assert_eq!(
+            Authorization::parse("bearer t0k").unwrap(),
+            Authorization::Bearer("t0k".into())
+        );
+        assert!(Authorization::parse("Basic !!!").is_none());
+        assert!(Authorization::parse("token").is_none());
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let inm = IfNoneMatch::parse("\"a\", W/\"b\"").unwrap();
+        assert!(inm.matches(&EntityTag { weak: false, tag: "b".into() }));
+        assert!(!inm.matches(&EntityTag { weak: false, tag: "c".into() }));
+        assert_eq!(IfNoneMatch::parse("*"), Some(IfNoneMatch::Any));
+        assert!(IfNoneMatch::parse("unquoted").is_none());
+
+        // weak comparison ignores W/ on either side
+        let inm = IfNoneMatch::parse(r#"W/"x,y" ,"z""#).unwrap();
+        assert_eq!(
+            inm,
+            IfNoneMatch::Tags(vec![
+                EntityTag { weak: true, tag: "x,y".into() },
+                EntityTag { weak: false, tag: "z".into() },
+            ])
+        );
+        assert!(inm.matches(&EntityTag::parse(r#""x,y""#).unwrap()));
+        assert!(inm.matches(&EntityTag::parse(r#"W/"z""#).unwrap()));
+        assert_eq!(IfNoneMatch::parse(r#""a", W/"b", *"#), Some(IfNoneMatch::Any));
+        for bad in ["", r#""a" "b""#, r#""a b""#, r#"W/ "a""#] {
+            assert!(IfNoneMatch::parse(bad).is_none(), "{}", bad);
+        }
+        assert_eq!(EntityTag::parse(r#"W/"v1""#).unwrap().to_string(), r#"W/"v1""#);
+        assert!(EntityTag::parse(r#""v1"x"#).is_none());
+    }
+}
@@ -0,0 +1,85 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{Context, FromRequest, HttpError, HttpStatus, Request, Response};
+
+impl Request<'_> {
+    // Reads and deserializes a JSON body, answering 415 for other content
+    // types and 400 if the body doesn't parse as a T.
+    pub fn json<T: DeserializeOwned>(&mut self) -> Result<T, HttpError> {
+        if !self.content_type_is("application/json") {
+            return Err(HttpError(HttpStatus::UnsupportedMediaType));
+        }
+        serde_json::from_slice(&self.bytes()?).map_err(|_| HttpError(HttpStatus::BadRequest))
+    }
+}
+
+impl Response {
+    // |value| serialized as a JSON body, or 500 if it can't be.
+    pub fn json<T: Serialize + ?Sized>(value: &T) -> Result<Response, HttpError> {
+        let data = serde_json::to_vec(value).map_err(|err| {
+            eprintln!("error: failed to serialize response: {}", err);
+            HttpError(HttpStatus::ServerError)
+        })?;
+        Ok(Response::builder().header("content-type", "application/json").body(data))
+    }
+}
+
+// A JSON body deserialized as a T, see Request::json.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Json<T> {
+    fn from_request(_ctx: &Context, req: &mut Request) -> Result<Self, HttpError> {
+        req.json().map(Json)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, io::Cursor};
+
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::parse_request;
+
+    #[derive(Debug, Deserialize, Serialize, PartialEq)]
+    struct User {
+        name: String,
+        age: u32,
+    }
+
+    fn parse(content_type: &str, body: &str) -> Result<User, HttpStatus> {
+        let raw = format!(
+            "POST / HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}trailing",
+            content_type,
+            body.len(),
+            body
+        );
+        let mut reader = Cursor::new(raw.into_bytes());
+        let mut req = parse_request(&mut reader).unwrap();
+        req.json().map_err(|HttpError(status)| status)
+    }
+
+    #[test]
+    fn test_json() {
+        let body = r#"{"name": "ada", "age": 36}"#;
+        let user = parse("application/json; charset=utf-8", body).unwrap();
+        assert_eq!(user, User { name: "ada".to_string(), age: 36 });
+        assert_eq!(parse("text/plain", body), Err(HttpStatus::UnsupportedMediaType));
+        assert_eq!(parse("application/json", r#"{"name": "ada"}"#), Err(HttpStatus::BadRequest));
+        assert_eq!(parse("application/json", "{"), Err(HttpStatus::BadRequest));
+    }
+
+    #[test]
+    fn test_json_response() {
+        let mut resp = Response::json(&User { name: "ada".to_string(), age: 36 }).unwrap();
+        assert_eq!(resp.get_header("content-type"), Some("application/json"));
+        assert_eq!(resp.get_header("content-length"), Some("23"));
+        let body = resp.body.take().unwrap().into_bytes().unwrap();
+        assert_eq!(body, br#"{"name":"ada","age":36}"#);
+        // JSON object keys must be strings
+        let bad: HashMap<(u8, u8), u8> = [((1, 2), 3)].into();
+        assert!(matches!(Response::json(&bad), Err(HttpError(HttpStatus::ServerError))));
+    }
+}
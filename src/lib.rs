@@ -1,10 +1,74 @@
+mod accept;
+mod audit;
+mod base64;
+mod chaos;
+mod client;
 mod compression;
+mod conditional;
+mod config_file;
+mod cookie;
+mod debug;
+mod diagnostics;
+mod extensions;
+mod extract;
+mod form;
+mod forward;
 mod handlers;
+mod headers;
+mod html;
+#[cfg(feature = "json")]
+mod json;
+mod memory;
+mod metrics;
+mod mime;
+#[cfg(any(test, feature = "test-util"))]
+mod mock_upstream;
+mod multipart;
+mod plugin;
+mod range;
+mod redact;
+mod reuseport;
 mod server;
+mod shard;
+mod slow_log;
+#[cfg(any(test, feature = "test-util"))]
+mod temp_dir;
 mod thread_pool;
+mod tls;
+mod trace;
 mod types;
+mod webhook;
 
+pub use crate::audit::*;
+pub use crate::chaos::*;
+pub use crate::client::*;
 pub use crate::compression::*;
+pub use crate::conditional::*;
+pub use crate::config_file::*;
+pub use crate::cookie::*;
+pub use crate::extensions::*;
+pub use crate::extract::*;
+pub use crate::form::*;
+pub use crate::forward::*;
 pub use crate::handlers::*;
+pub use crate::headers::*;
+#[cfg(feature = "json")]
+pub use crate::json::*;
+pub use crate::memory::*;
+pub use crate::metrics::*;
+pub use crate::mime::*;
+#[cfg(any(test, feature = "test-util"))]
+pub use crate::mock_upstream::*;
+pub use crate::multipart::*;
+pub use crate::plugin::*;
+pub use crate::range::*;
+pub use crate::redact::*;
 pub use crate::server::*;
+pub use crate::shard::*;
+pub use crate::slow_log::*;
+#[cfg(any(test, feature = "test-util"))]
+pub use crate::temp_dir::*;
+pub use crate::tls::*;
+pub use crate::trace::*;
 pub use crate::types::*;
+pub use crate::webhook::*;
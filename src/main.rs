@@ -2,82 +2,110 @@ use clap::Parser;
 use codecrafters_http_server::*;
 use signal_hook::{consts::TERM_SIGNALS, flag, iterator::Signals};
 use std::{
-    fs::File,
-    io::{self, BufRead},
-    os::unix::fs::MetadataExt,
+    fs::{self, File},
+    io,
+    path::{Component, Path, PathBuf},
     sync::{atomic::AtomicBool, Arc},
     thread,
 };
 
-// TODO: use Read and move to a different module
-struct SizedReader<'t> {
-    r: &'t mut dyn BufRead,
-    lim: usize,
-    n: usize,
-}
-
-impl<'t> SizedReader<'t> {
-    fn new(r: &'t mut dyn BufRead, lim: usize) -> Self {
-        Self { r, lim, n: 0 }
-    }
-}
-
-impl io::Read for SizedReader<'_> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let n = if self.n < self.lim {
-            let k = self.r.read(buf)?;
-            self.n += k;
-            k
-        } else {
-            0
-        };
-        Ok(n)
-    }
-}
-
 fn codecrafters_handler() -> Box<dyn Handler> {
     Router::default()
-        .route(Method::Get, "^/$", |_ctx: &Context, _req: Request| Ok(Response::empty()))
-        .route(Method::Get, "^/echo/([^/]+)$", |_ctx: &Context, req: Request| {
-            let message = req.matches.unwrap().swap_remove(1).unwrap();
-            Ok(Response::plain_text(message))
+        .get("/", |_ctx: &Context, _req: Request| Ok(Response::empty()))
+        .get("/echo/:message", |_ctx: &Context, req: Request| {
+            let message = req.param("message").unwrap();
+            Ok(Response::plain_text(message.to_owned()))
         })
-        .route(Method::Get, "^/user-agent$", |_ctx: &Context, req: Request| {
+        .get("/user-agent", |_ctx: &Context, req: Request| {
             let user_agent = req.get_header("User-Agent").ok_or(HttpStatus::BadRequest)?;
             Ok(Response::plain_text(user_agent.to_owned()))
         })
-        .route(Method::Get, "^/files/([^/]+)$", |ctx: &Context, req: Request| {
-            let filename = req.matches.unwrap().swap_remove(1).unwrap();
-            let path = ctx.working_dir.join(filename);
-            let file = File::open(path).map_err(|_| HttpStatus::NotFound)?;
-            let size = file.metadata().map_err(|_| HttpStatus::NotFound)?.size();
-            Ok(Response::binary(Box::new(file), size))
-        })
-        .route(Method::Get, "^/test-post", |_ctx: &Context, _req: Request| {
-            Ok(Err(HttpStatus::BadRequest)?)
-        })
-        .route(Method::Post, "^/test-post", |_ctx: &Context, _req: Request| Ok(Response::empty()))
-        .route(Method::Post, "^/files/([^/]+)$", |ctx: &Context, req: Request| {
-            let size: usize = req
-                .get_header("content-length")
-                .ok_or(HttpStatus::BadRequest)?
-                .parse()
-                .map_err(|_| HttpStatus::BadRequest)?;
-            // TODO: if route matches we can always propagate non-none |matches|
-            // TODO: if route matches then we should statically know the len and avoid the get() option
-            let filename = req.matches.as_ref().unwrap().get(1).unwrap().as_ref().unwrap();
-            let path = ctx.working_dir.join(filename);
-            let mut file = File::create_new(path).map_err(|_| HttpStatus::BadRequest)?;
-            let mut from = SizedReader::new(req.body, size);
-            io::copy(&mut from, &mut file).map_err(|err| {
-                eprintln!("error: {}", err);
-                HttpStatus::ServerError
-            })?;
-            Ok(Response::created())
+        .get("/files/:filename", |ctx: &Context, req: Request| {
+            let filename = req.param("filename").unwrap();
+            ctx.send_file(filename)
         })
+        .get("^/test-post", |_ctx: &Context, _req: Request| Ok(Err(HttpStatus::BadRequest)?))
+        .post("^/test-post", |_ctx: &Context, _req: Request| Ok(Response::empty()))
+        .post(
+            "/files/:filename",
+            audited(|ctx: &Context, mut req: Request| {
+                let path = file_path(ctx, &req)?;
+                let mut file = File::create_new(path).map_err(|_| HttpStatus::BadRequest)?;
+                let size = io::copy(&mut req.body, &mut file).map_err(|err| {
+                    eprintln!("error: {}", err);
+                    HttpStatus::ServerError
+                })?;
+                ctx.metrics.increment("files_uploaded_total", &[]);
+                ctx.file_changed(FileAction::Uploaded, &req.path, Some(size));
+                Ok(Response::created())
+            }),
+        )
+        .put(
+            "/files/:filename",
+            audited(|ctx: &Context, mut req: Request| {
+                let path = file_path(ctx, &req)?;
+                let existed = path.is_file();
+                let mut file = File::create(path).map_err(|_| HttpStatus::BadRequest)?;
+                let size = io::copy(&mut req.body, &mut file).map_err(|err| {
+                    eprintln!("error: {}", err);
+                    HttpStatus::ServerError
+                })?;
+                ctx.metrics.increment("files_uploaded_total", &[]);
+                if existed {
+                    ctx.file_changed(FileAction::Overwritten, &req.path, Some(size));
+                    return Ok(Response::builder().status(HttpStatus::NoContent).build());
+                }
+                ctx.file_changed(FileAction::Uploaded, &req.path, Some(size));
+                Ok(Response::created())
+            }),
+        )
+        .delete(
+            "/files/:filename",
+            audited(|ctx: &Context, req: Request| {
+                let path = file_path(ctx, &req)?;
+                fs::remove_file(path).map_err(|err| match err.kind() {
+                    io::ErrorKind::NotFound => HttpStatus::NotFound,
+                    _ => HttpStatus::Forbidden,
+                })?;
+                ctx.file_changed(FileAction::Deleted, &req.path, None);
+                Ok(Response::builder().status(HttpStatus::NoContent).build())
+            }),
+        )
+        .post(
+            "/files",
+            audited(|ctx: &Context, mut req: Request| {
+                let dir = ctx.files_dir()?.to_owned();
+                let mut multipart = req.multipart()?;
+                let mut saved = 0;
+                while let Some(mut part) = multipart.next_part()? {
+                    // keep only the last path component of what the browser sent
+                    let Some(filename) = part.filename.as_deref().map(Path::new) else {
+                        continue;
+                    };
+                    let filename = filename.file_name().ok_or(HttpStatus::BadRequest)?.to_owned();
+                    let size =
+                        part.save(&dir.join(&filename)).map_err(|_| HttpStatus::BadRequest)?;
+                    let path = format!("/files/{}", filename.to_string_lossy());
+                    ctx.file_changed(FileAction::Uploaded, &path, Some(size));
+                    saved += 1;
+                }
+                ctx.metrics.add("files_uploaded_total", &[], saved);
+                Ok(Response::created())
+            }),
+        )
         .into()
 }
 
+// The file the request's :filename names in the working directory, refusing
+// names such as .. that would leave it.
+fn file_path(ctx: &Context, req: &Request) -> Result<PathBuf, HttpError> {
+    let filename = Path::new(req.param("filename").unwrap());
+    if !filename.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(HttpError(HttpStatus::BadRequest));
+    }
+    Ok(ctx.files_dir()?.join(filename))
+}
+
 fn make_server(config: Config) -> Arc<Server> {
     Arc::new(Server::start(config, codecrafters_handler()))
 }
@@ -167,6 +195,49 @@ mod test {
         assert!(resp.status().is_success());
     }
 
+    #[test]
+    fn test_missing_directory() {
+        let dir = TempDir::new("files").unwrap();
+        std::fs::write(dir.join("a.txt"), "a").unwrap();
+        let server = make_server(Config { directory: dir.to_path_buf(), ..Config::default() });
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever().unwrap());
+
+        let url = format!("http://{}/files/a.txt", server.addr());
+        let get = || reqwest::blocking::get(&url).unwrap().status();
+        assert_eq!(get(), reqwest::StatusCode::OK);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(get(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(get(), reqwest::StatusCode::NOT_FOUND);
+        std::fs::write(dir.join("a.txt"), "a").unwrap();
+        assert_eq!(get(), reqwest::StatusCode::OK);
+    }
+
+    #[test]
+    fn test_multipart_upload() {
+        let dir = TempDir::new("uploads").unwrap();
+        let server = make_server(Config { directory: dir.to_path_buf(), ..Config::default() });
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever().unwrap());
+
+        let body = "--b\r\n\
+                    Content-Disposition: form-data; name=\"note\"\r\n\r\nignored\r\n\
+                    --b\r\n\
+                    Content-Disposition: form-data; name=\"f\"; filename=\"../up.txt\"\r\n\
+                    Content-Type: text/plain\r\n\r\nuploaded\r\n\
+                    --b--\r\n";
+        let client = reqwest::blocking::Client::new();
+        let resp = client
+            .post(format!("http://{}/files", server.addr()))
+            .header("content-type", "multipart/form-data; boundary=b")
+            .body(body)
+            .send()
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::CREATED);
+        assert_eq!(std::fs::read_to_string(dir.join("up.txt")).unwrap(), "uploaded");
+    }
+
     #[test]
     fn test_compression() {
         let server = make_server(Config::default());
@@ -178,4 +249,232 @@ mod test {
         assert!(resp.status().is_success());
         assert_eq!(resp.text().unwrap(), "foo");
     }
+
+    #[test]
+    fn test_range() {
+        let server = make_server(Config::default());
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever().unwrap());
+
+        let url = format!("http://{}/echo/abcdef", server.addr());
+        let get = |range: &str| {
+            // ranges are of the content, even when the client would take it compressed
+            let client = reqwest::blocking::Client::builder().no_gzip().build().unwrap();
+            let req = client.get(&url).header("range", range).header("accept-encoding", "gzip");
+            req.send().unwrap()
+        };
+
+        let resp = get("bytes=1-3");
+        assert_eq!(resp.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.headers()["content-range"], "bytes 1-3/6");
+        assert!(resp.headers().get("content-encoding").is_none());
+        assert_eq!(resp.text().unwrap(), "bcd");
+
+        let resp = get("bytes=-2");
+        assert_eq!(resp.text().unwrap(), "ef");
+
+        let resp = get("bytes=10-");
+        assert_eq!(resp.status(), reqwest::StatusCode::RANGE_NOT_SATISFIABLE);
+    }
+
+    #[test]
+    fn test_metrics() {
+        // off unless asked for, as it exposes traffic details to any client
+        let server = make_server(Config::default());
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever().unwrap());
+        let resp = reqwest::blocking::get(format!("http://{}/metrics", server.addr())).unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+
+        let server = make_server(Config { metrics: true, ..Config::default() });
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever().unwrap());
+
+        reqwest::blocking::get(format!("http://{}/echo/foo", server.addr())).unwrap();
+        reqwest::blocking::get(format!("http://{}/echo/bar", server.addr())).unwrap();
+        let resp = reqwest::blocking::get(format!("http://{}/metrics", server.addr())).unwrap();
+        let text = resp.text().unwrap();
+        assert!(text.contains(
+            r#"http_requests_total{method="GET",route="/echo/:message",status="200"} 2"#
+        ));
+        assert!(!text.contains("/echo/foo"));
+        assert!(text.contains(r#"http_response_size_bytes_bucket{le="64"} 2"#));
+        assert!(text.contains("http_request_headers_count{} 3"));
+    }
+
+    #[test]
+    fn test_keep_alive() {
+        let config = Config { max_requests_per_connection: 2, ..Config::default() };
+        let server = make_server(config);
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever().unwrap());
+
+        let client = reqwest::blocking::Client::new();
+        for i in 0..5 {
+            let resp = client.get(format!("http://{}/echo/{}", server.addr(), i)).send().unwrap();
+            let expected = if i % 2 == 1 { Some("close") } else { None };
+            assert_eq!(resp.headers().get("connection").map(|v| v.to_str().unwrap()), expected);
+            assert_eq!(resp.text().unwrap(), i.to_string());
+        }
+
+        // unread request bodies must not bleed into the next request
+        for _ in 0..5 {
+            let resp = client.post(format!("http://{}/test-post", server.addr())).body("x").send();
+            assert!(resp.unwrap().status().is_success());
+        }
+    }
+
+    #[test]
+    fn test_head() {
+        let server = make_server(Config::default());
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever().unwrap());
+
+        let client = reqwest::blocking::Client::builder().no_gzip().build().unwrap();
+        for _ in 0..2 {
+            let resp = client.head(format!("http://{}/echo/foo", server.addr())).send().unwrap();
+            assert!(resp.status().is_success());
+            assert_eq!(resp.headers()["content-length"], "3");
+            assert_eq!(resp.text().unwrap(), "");
+        }
+    }
+
+    #[test]
+    fn test_method_not_allowed() {
+        let server = make_server(Config::default());
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever().unwrap());
+
+        let client = reqwest::blocking::Client::new();
+        let resp = client.patch(format!("http://{}/files/foo", server.addr())).send().unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers()["allow"], "GET, POST, PUT, DELETE, HEAD");
+
+        let resp = client.delete(format!("http://{}/nothing", server.addr())).send().unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let dir = TempDir::new("audited").unwrap();
+        let log = dir.join("audit.log");
+        let config = Config {
+            directory: dir.to_path_buf(),
+            audit_log: Some(log.clone()),
+            ..Config::default()
+        };
+        let server = make_server(config);
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever().unwrap());
+
+        let client = reqwest::blocking::Client::new();
+        let url = format!("http://{}/files/a.txt", server.addr());
+        let upload = || client.post(&url).basic_auth("ada", Some("pw")).body("hello").send();
+        assert_eq!(upload().unwrap().status(), reqwest::StatusCode::CREATED);
+        // the file exists now, so this one fails and is audited as such
+        assert_eq!(upload().unwrap().status(), reqwest::StatusCode::BAD_REQUEST);
+        reqwest::blocking::get(&url).unwrap();
+
+        let lines: Vec<String> =
+            std::fs::read_to_string(&log).unwrap().lines().map(String::from).collect();
+        assert_eq!(lines.len(), 2);
+        for (line, status) in lines.iter().zip(["201", "400"]) {
+            assert!(line.contains(r#"method="POST" path="/files/a.txt" client="127.0.0.1""#));
+            assert!(line.contains(r#"user="ada" size=5"#));
+            assert!(line.ends_with(&format!("status={}", status)));
+        }
+
+        // overwrites and deletions are audited too
+        let resp = client.put(&url).body("hello again").send().unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+        assert_eq!(client.delete(&url).send().unwrap().status(), reqwest::StatusCode::NO_CONTENT);
+        assert_eq!(client.delete(&url).send().unwrap().status(), reqwest::StatusCode::NOT_FOUND);
+        // and each file a multipart upload writes gets its own record
+        let body = "--b\r\n\
+                    Content-Disposition: form-data; name=\"f\"; filename=\"b.txt\"\r\n\r\nbb\r\n\
+                    --b\r\n\
+                    Content-Disposition: form-data; name=\"g\"; filename=\"c.txt\"\r\n\r\nccc\r\n\
+                    --b--\r\n";
+        let resp = client
+            .post(format!("http://{}/files", server.addr()))
+            .header("content-type", "multipart/form-data; boundary=b")
+            .body(body)
+            .send()
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::CREATED);
+
+        let lines: Vec<String> =
+            std::fs::read_to_string(&log).unwrap().lines().skip(2).map(String::from).collect();
+        let expected = [
+            r#"method="PUT" path="/files/a.txt" client="127.0.0.1" size=11 status=204"#,
+            r#"method="DELETE" path="/files/a.txt" client="127.0.0.1" status=204"#,
+            r#"method="DELETE" path="/files/a.txt" client="127.0.0.1" status=404"#,
+            r#"method="POST" path="/files/b.txt" client="127.0.0.1" size=2 status=201"#,
+            r#"method="POST" path="/files/c.txt" client="127.0.0.1" size=3 status=201"#,
+        ];
+        assert_eq!(lines.len(), expected.len(), "{:?}", lines);
+        for (line, expected) in lines.iter().zip(expected) {
+            assert!(line.ends_with(expected), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_file_content_types() {
+        let dir = TempDir::new("types").unwrap();
+        for name in ["a.txt", "b.mjs", "c.png"] {
+            std::fs::write(dir.join(name), "x").unwrap();
+        }
+        // the config file adds to the flags, which win where both give a type
+        let file = dir.join("server.toml");
+        let types = "[mime_types]\ntxt = \"text/plain; charset=utf-8\"\nmjs = \"text/plain\"\n";
+        std::fs::write(&file, types).unwrap();
+        let args =
+            ["server", "--mime-type", "mjs=text/javascript", "--config", file.to_str().unwrap()];
+        let config = Config { directory: dir.to_path_buf(), port: 0, ..Config::parse_from(args) };
+        let server = make_server(config);
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever().unwrap());
+
+        let content_type = |name: &str| {
+            let resp = reqwest::blocking::get(format!("http://{}/files/{}", server.addr(), name));
+            resp.unwrap().headers()["content-type"].to_str().unwrap().to_owned()
+        };
+        assert_eq!(content_type("a.txt"), "text/plain; charset=utf-8");
+        assert_eq!(content_type("b.mjs"), "text/javascript");
+        assert_eq!(content_type("c.png"), "image/png");
+    }
+
+    #[test]
+    fn test_file_if_none_match() {
+        let dir = TempDir::new("etags").unwrap();
+        std::fs::write(dir.join("a.txt"), "abcdef").unwrap();
+        let server = make_server(Config { directory: dir.to_path_buf(), ..Config::default() });
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever().unwrap());
+
+        let client = reqwest::blocking::Client::new();
+        let url = format!("http://{}/files/a.txt", server.addr());
+        let etag = client.get(&url).send().unwrap().headers()["etag"].to_str().unwrap().to_owned();
+        assert!(etag.starts_with("W/\""));
+        let strong = etag.trim_start_matches("W/");
+        let send = |req: reqwest::blocking::RequestBuilder| req.send().unwrap().status().as_u16();
+
+        assert_eq!(send(client.get(&url).header("if-none-match", &etag)), 304);
+        // weak comparison: the W/ prefix on either side doesn't matter
+        assert_eq!(send(client.get(&url).header("if-none-match", strong)), 304);
+        let list = format!(r#""other", {}"#, etag);
+        assert_eq!(send(client.get(&url).header("if-none-match", list)), 304);
+        assert_eq!(send(client.get(&url).header("if-none-match", "*")), 304);
+        assert_eq!(send(client.get(&url).header("if-none-match", r#""other""#)), 200);
+        assert_eq!(send(client.head(&url).header("if-none-match", &etag)), 304);
+        assert_eq!(send(client.head(&url).header("if-none-match", r#"W/"other""#)), 200);
+
+        // a match answers 304 instead of the range
+        let range =
+            |inm: &str| client.get(&url).header("range", "bytes=1-2").header("if-none-match", inm);
+        assert_eq!(send(range(&etag)), 304);
+        let resp = range(r#""other""#).send().unwrap();
+        assert_eq!(resp.status().as_u16(), 206);
+        assert_eq!(resp.text().unwrap(), "bc");
+    }
 }
@@ -0,0 +1,81 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Approximate memory held on behalf of connections: their read and write
+// buffers and the request bodies that may be read into memory. With a limit,
+// reservations beyond it fail, so the server can shed load with 503s before
+// the allocator runs out.
+#[derive(Debug, Default)]
+pub struct MemoryBudget {
+    used: AtomicUsize,
+    limit: Option<usize>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self { used: AtomicUsize::new(0), limit }
+    }
+
+    // Accounts for |bytes| until the reservation is dropped, or None if that
+    // would exceed the limit.
+    pub fn reserve(&self, bytes: usize) -> Option<Reservation<'_>> {
+        let limit = self.limit.unwrap_or(usize::MAX);
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|total| *total <= limit)
+            })
+            .ok()?;
+        Some(Reservation { budget: self, bytes })
+    }
+
+    // Accounts for |bytes| already allocated until the reservation is dropped,
+    // even beyond the limit, so that new requests are shed meanwhile.
+    pub fn charge(&self, bytes: usize) -> Reservation<'_> {
+        self.used.fetch_add(bytes, Ordering::Relaxed);
+        Reservation { budget: self, bytes }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+}
+
+pub struct Reservation<'b> {
+    budget: &'b MemoryBudget,
+    bytes: usize,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_budget() {
+        let budget = MemoryBudget::new(Some(100));
+        let a = budget.reserve(60).unwrap();
+        assert!(budget.reserve(50).is_none());
+        let b = budget.reserve(40).unwrap();
+        assert_eq!(budget.used(), 100);
+        drop(a);
+        assert_eq!(budget.used(), 40);
+        assert!(budget.reserve(60).is_some());
+        drop(b);
+        assert_eq!(budget.used(), 0);
+        assert!(MemoryBudget::default().reserve(usize::MAX).is_some());
+
+        let charged = budget.charge(150);
+        assert_eq!(budget.used(), 150);
+        assert!(budget.reserve(1).is_none());
+        drop(charged);
+        assert!(budget.reserve(1).is_some());
+    }
+}
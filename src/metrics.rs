@@ -0,0 +1,152 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::{Mutex, PoisonError},
+};
+
+use crate::{Context, Handler, HttpError, Method, Request, Response, Shard};
+
+const DEFAULT_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+// metric name and rendered label set, e.g. ("requests_total", "method=\"GET\"")
+type Key = (String, String);
+
+struct Histogram {
+    buckets: Vec<f64>,
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &[f64]) -> Self {
+        Self { buckets: buckets.to_vec(), counts: vec![0; buckets.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = self.buckets.iter().position(|le| value <= *le) {
+            self.counts[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+// Counters, gauges and histograms in the Prometheus text exposition format. Handlers
+// can record their own metrics through Context; label values should come from
+// a small fixed set (route patterns, methods), never raw paths.
+#[derive(Default)]
+pub struct Metrics {
+    // sharded by key so concurrent requests rarely contend
+    counters: Shard<BTreeMap<Key, u64>>,
+    histograms: Shard<BTreeMap<Key, Histogram>>,
+    gauges: Mutex<BTreeMap<Key, f64>>,
+    buckets: Mutex<HashMap<String, Vec<f64>>>,
+}
+
+fn labels(labels: &[(&str, &str)]) -> String {
+    let mut s = String::new();
+    for (i, (k, v)) in labels.iter().enumerate() {
+        if i > 0 {
+            s.push(',');
+        }
+        let v = v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+        write!(s, "{}=\"{}\"", k, v).unwrap();
+    }
+    s
+}
+
+fn with_le(labels: &str, le: &str) -> String {
+    if labels.is_empty() {
+        format!("le=\"{}\"", le)
+    } else {
+        format!("{},le=\"{}\"", labels, le)
+    }
+}
+
+impl Metrics {
+    pub fn increment(&self, name: &str, l: &[(&str, &str)]) {
+        self.add(name, l, 1);
+    }
+
+    pub fn add(&self, name: &str, l: &[(&str, &str)], n: u64) {
+        let key = (name.to_owned(), labels(l));
+        *self.counters.get(&key).entry(key).or_default() += n;
+    }
+
+    pub fn counter(&self, name: &str, l: &[(&str, &str)]) -> u64 {
+        let key = (name.to_owned(), labels(l));
+        self.counters.get(&key).get(&key).copied().unwrap_or(0)
+    }
+
+    pub fn set_gauge(&self, name: &str, l: &[(&str, &str)], value: f64) {
+        self.gauges
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert((name.to_owned(), labels(l)), value);
+    }
+
+    // Sets the bucket upper bounds for histogram |name|; only affects label
+    // sets observed for the first time afterwards.
+    pub fn set_buckets(&self, name: &str, mut buckets: Vec<f64>) {
+        buckets.sort_by(f64::total_cmp);
+        self.buckets
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(name.to_owned(), buckets);
+    }
+
+    pub fn observe(&self, name: &str, l: &[(&str, &str)], value: f64) {
+        let key = (name.to_owned(), labels(l));
+        self.histograms
+            .get(&key)
+            .entry(key)
+            .or_insert_with(|| {
+                match self.buckets.lock().unwrap_or_else(PoisonError::into_inner).get(name) {
+                    Some(buckets) => Histogram::new(buckets),
+                    None => Histogram::new(DEFAULT_BUCKETS),
+                }
+            })
+            .observe(value);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        // merge the shards so the output stays sorted
+        let counters: BTreeMap<Key, u64> = self.counters.shards().flat_map(|s| s.clone()).collect();
+        for ((name, l), n) in &counters {
+            writeln!(out, "{}{{{}}} {}", name, l, n).unwrap();
+        }
+        for ((name, l), value) in self.gauges.lock().unwrap_or_else(PoisonError::into_inner).iter()
+        {
+            writeln!(out, "{}{{{}}} {}", name, l, value).unwrap();
+        }
+        let shards: Vec<_> = self.histograms.shards().collect();
+        let histograms: BTreeMap<&Key, &Histogram> = shards.iter().flat_map(|s| s.iter()).collect();
+        for ((name, l), h) in histograms {
+            let mut cumulative = 0;
+            for (le, n) in h.buckets.iter().zip(&h.counts) {
+                cumulative += n;
+                writeln!(out, "{}_bucket{{{}}} {}", name, with_le(l, &le.to_string()), cumulative)
+                    .unwrap();
+            }
+            writeln!(out, "{}_bucket{{{}}} {}", name, with_le(l, "+Inf"), h.count).unwrap();
+            writeln!(out, "{}_sum{{{}}} {}", name, l, h.sum).unwrap();
+            writeln!(out, "{}_count{{{}}} {}", name, l, h.count).unwrap();
+        }
+        out
+    }
+}
+
+// Serves the context's metrics at /metrics, enabled with --metrics. Other
+// requests go to the server's handler.
+pub struct MetricsRoute(pub Box<dyn Handler>);
+
+impl Handler for MetricsRoute {
+    fn handle(&self, ctx: &Context, req: Request) -> Result<Response, HttpError> {
+        if req.path == "/metrics" && matches!(req.method, Method::Get | Method::Head) {
+            return Ok(Response::plain_text(ctx.render_metrics()));
+        }
+        self.0.handle(ctx, req)
+    }
+}
@@ -0,0 +1,110 @@
+use std::path::Path;
+
+// Content types for common file extensions, which are compared lowercased.
+const TYPES: &[(&str, &str)] = &[
+    ("css", "text/css"),
+    ("csv", "text/csv"),
+    ("gif", "image/gif"),
+    ("gz", "application/gzip"),
+    ("htm", "text/html"),
+    ("html", "text/html"),
+    ("ico", "image/x-icon"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("js", "text/javascript"),
+    ("json", "application/json"),
+    ("md", "text/markdown"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("svg", "image/svg+xml"),
+    ("tar", "application/x-tar"),
+    ("txt", "text/plain"),
+    ("wasm", "application/wasm"),
+    ("webp", "image/webp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("xml", "application/xml"),
+    ("zip", "application/zip"),
+];
+
+fn extension(path: &Path) -> &str {
+    path.extension().and_then(|ext| ext.to_str()).unwrap_or_default()
+}
+
+// The Content-Type to serve |path| with, application/octet-stream for
+// unknown extensions.
+pub fn mime_type(path: &Path) -> &'static str {
+    let ext = extension(path);
+    TYPES
+        .iter()
+        .find(|(e, _)| e.eq_ignore_ascii_case(ext))
+        .map_or("application/octet-stream", |(_, mime)| mime)
+}
+
+// The built-in table with the --mime-type and config file overrides on top,
+// e.g. to serve .mjs as text/javascript or .txt as text/plain; charset=utf-8.
+// Earlier overrides win.
+#[derive(Debug, Clone, Default)]
+pub struct MimeTypes {
+    // extensions without the dot
+    overrides: Vec<(String, String)>,
+}
+
+impl MimeTypes {
+    pub fn new(overrides: &[(String, String)]) -> Self {
+        Self { overrides: overrides.to_vec() }
+    }
+
+    pub fn get(&self, path: &Path) -> &str {
+        let ext = extension(path);
+        match self.overrides.iter().find(|(e, _)| e.eq_ignore_ascii_case(ext)) {
+            Some((_, mime)) => mime,
+            None => mime_type(path),
+        }
+    }
+}
+
+// Parses a --mime-type EXT=TYPE argument; the extension may start with a dot.
+pub fn parse_mime_override(arg: &str) -> Result<(String, String), String> {
+    let (ext, mime) = arg.split_once('=').ok_or("expected EXT=TYPE")?;
+    mime_override(ext, mime)
+}
+
+// An override of |ext|'s type, as given on the command line or in the config
+// file's [mime_types] section.
+pub(crate) fn mime_override(ext: &str, mime: &str) -> Result<(String, String), String> {
+    let ext = ext.trim().trim_start_matches('.');
+    if ext.is_empty() || !mime.contains('/') {
+        return Err(format!("invalid mime type override: {}={}", ext, mime));
+    }
+    Ok((ext.to_string(), mime.trim().to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mime_type() {
+        assert_eq!(mime_type(Path::new("a/index.HTML")), "text/html");
+        assert_eq!(mime_type(Path::new("photo.jpg")), "image/jpeg");
+        assert_eq!(mime_type(Path::new("archive.tar.gz")), "application/gzip");
+        assert_eq!(mime_type(Path::new("README")), "application/octet-stream");
+        assert_eq!(mime_type(Path::new(".bashrc")), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_overrides() {
+        let overrides = ["mjs=text/javascript", ".TXT=text/plain; charset=utf-8"];
+        let overrides: Vec<_> = overrides.iter().map(|o| parse_mime_override(o).unwrap()).collect();
+        let types = MimeTypes::new(&overrides);
+        assert_eq!(types.get(Path::new("app.mjs")), "text/javascript");
+        assert_eq!(types.get(Path::new("notes.txt")), "text/plain; charset=utf-8");
+        assert_eq!(types.get(Path::new("page.html")), "text/html");
+        assert!(parse_mime_override("mjs").is_err());
+        assert!(parse_mime_override("=text/plain").is_err());
+        assert!(parse_mime_override("txt=plain").is_err());
+    }
+}
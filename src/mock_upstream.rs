@@ -0,0 +1,212 @@
+use std::{
+    collections::VecDeque,
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{parse_request, HttpStatus, Method};
+
+// What the mock does with the next request it receives.
+pub enum Reply {
+    // answers with a content-length framed response
+    Respond { status: HttpStatus, headers: Vec<(String, String)>, body: Vec<u8> },
+    // waits before carrying out the inner reply
+    Delay(Duration, Box<Reply>),
+    // closes the connection without answering
+    Abort,
+}
+
+impl Reply {
+    pub fn ok(body: &str) -> Self {
+        Self::status(HttpStatus::OK).body(body)
+    }
+
+    pub fn status(status: HttpStatus) -> Self {
+        Self::Respond { status, headers: Vec::new(), body: Vec::new() }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        if let Self::Respond { headers, .. } = &mut self {
+            headers.push((name.to_owned(), value.to_owned()));
+        }
+        self
+    }
+
+    pub fn body(mut self, data: &str) -> Self {
+        if let Self::Respond { body, .. } = &mut self {
+            *body = data.as_bytes().to_vec();
+        }
+        self
+    }
+
+    pub fn delayed(self, delay: Duration) -> Self {
+        Self::Delay(delay, Box::new(self))
+    }
+}
+
+// A request as the mock received it.
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: Method,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl RecordedRequest {
+    pub fn get_header(&self, key: &str) -> Option<&str> {
+        self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Default)]
+struct State {
+    script: Mutex<VecDeque<Reply>>,
+    requests: Mutex<Vec<RecordedRequest>>,
+    connections: AtomicUsize,
+    stopped: AtomicBool,
+}
+
+// A scripted upstream server for testing code that talks to other servers.
+// Each request consumes the next reply in the script; once it runs out,
+// requests are answered with 503. Stops listening when dropped.
+pub struct MockUpstream {
+    addr: String,
+    state: Arc<State>,
+}
+
+impl MockUpstream {
+    pub fn start(script: Vec<Reply>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let state = Arc::new(State { script: Mutex::new(script.into()), ..State::default() });
+        let state2 = Arc::clone(&state);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if state2.stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+                let Ok(stream) = stream else { continue };
+                state2.connections.fetch_add(1, Ordering::SeqCst);
+                let state = Arc::clone(&state2);
+                thread::spawn(move || {
+                    let _ = serve(&state, stream);
+                });
+            }
+        });
+        Self { addr, state }
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    // requests received so far, in arrival order
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    // connections accepted so far, to tell whether clients reuse them
+    pub fn connections(&self) -> usize {
+        self.state.connections.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for MockUpstream {
+    fn drop(&mut self) {
+        self.state.stopped.store(true, Ordering::SeqCst);
+        // wake up the accept loop so it sees the flag
+        let _ = TcpStream::connect(&self.addr);
+    }
+}
+
+fn serve(state: &State, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(&stream);
+    let mut writer = BufWriter::new(&stream);
+    loop {
+        let Ok(mut req) = parse_request(&mut reader) else {
+            return Ok(());
+        };
+        let mut body = Vec::new();
+        req.body.read_to_end(&mut body)?;
+        let keep_alive = req.keep_alive();
+        let headers = req.headers().cloned().collect();
+        let (method, path) = (req.method.clone(), req.path.clone());
+        drop(req);
+        state.requests.lock().unwrap().push(RecordedRequest { method, path, headers, body });
+
+        let mut reply = state
+            .script
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or(Reply::status(HttpStatus::ServiceUnavailable));
+        while let Reply::Delay(delay, inner) = reply {
+            thread::sleep(delay);
+            reply = *inner;
+        }
+        let Reply::Respond { status, headers, body } = reply else {
+            return stream.shutdown(Shutdown::Both);
+        };
+        write!(writer, "HTTP/1.1 {}\r\n", status)?;
+        for (k, v) in &headers {
+            write!(writer, "{}: {}\r\n", k, v)?;
+        }
+        write!(writer, "content-length: {}\r\n", body.len())?;
+        if !keep_alive {
+            write!(writer, "connection: close\r\n")?;
+        }
+        write!(writer, "\r\n")?;
+        writer.write_all(&body)?;
+        writer.flush()?;
+        if !keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_script() {
+        let upstream = MockUpstream::start(vec![
+            Reply::ok("hello").header("x-upstream", "1"),
+            Reply::status(HttpStatus::BadGateway).delayed(Duration::from_millis(50)),
+            Reply::Abort,
+        ]);
+        let client = reqwest::blocking::Client::new();
+
+        let resp = client.post(upstream.url("/a")).body("ping").send().unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        assert_eq!(resp.headers()["x-upstream"], "1");
+        assert_eq!(resp.text().unwrap(), "hello");
+
+        let resp = client.get(upstream.url("/b")).send().unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_GATEWAY);
+
+        assert!(client.get(upstream.url("/c")).send().is_err());
+
+        let resp = client.get(upstream.url("/d")).send().unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+
+        let requests = upstream.requests();
+        let paths: Vec<&str> = requests.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, ["/a", "/b", "/c", "/d"]);
+        assert_eq!(requests[0].method, Method::Post);
+        assert_eq!(requests[0].body, b"ping");
+        // the aborted connection couldn't be reused
+        assert_eq!(upstream.connections(), 2);
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read},
+    path::Path,
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{parse_params, HttpError, HttpStatus, Request};
+
+// largest header block accepted for a single part
+const MAX_PART_HEADERS: usize = 8192;
+
+fn malformed(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("multipart: {}", msg))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // before the first delimiter
+    Preamble,
+    // reading a part's body
+    Body,
+    // just past a delimiter, before the next part's headers
+    Delimiter,
+    Done,
+}
+
+// A streaming multipart/form-data reader, see Request::multipart. Parts are
+// read one at a time without buffering whole bodies, so large uploads can go
+// straight to disk.
+pub struct Multipart<'r> {
+    reader: &'r mut dyn Read,
+    // CRLF followed by "--" and the boundary
+    delimiter: Vec<u8>,
+    // read from |reader| but not yet consumed
+    buf: Vec<u8>,
+    eof: bool,
+    state: State,
+}
+
+impl<'r> Multipart<'r> {
+    pub fn new(reader: &'r mut dyn Read, boundary: &str) -> Self {
+        let delimiter = format!("\r\n--{}", boundary).into_bytes();
+        // the first delimiter isn't preceded by a line break, pretend it is
+        Self { reader, delimiter, buf: b"\r\n".to_vec(), eof: false, state: State::Preamble }
+    }
+
+    // reads until at least |want| bytes are buffered or the input ends
+    fn fill(&mut self, want: usize) -> io::Result<()> {
+        let mut chunk = [0; 4096];
+        while self.buf.len() < want && !self.eof {
+            let n = self.reader.read(&mut chunk)?;
+            self.eof = n == 0;
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+        Ok(())
+    }
+
+    // Reads part body bytes into |out|, returning 0 at the end of the part.
+    fn read_body(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.state != State::Body && self.state != State::Preamble {
+            return Ok(0);
+        }
+        loop {
+            if let Some(i) = find(&self.buf, &self.delimiter) {
+                if i == 0 {
+                    self.buf.drain(..self.delimiter.len());
+                    self.state = State::Delimiter;
+                    return Ok(0);
+                }
+                let n = i.min(out.len());
+                out[..n].copy_from_slice(&self.buf[..n]);
+                self.buf.drain(..n);
+                return Ok(n);
+            }
+            // everything but a possible partial delimiter at the end is body
+            let safe = self.buf.len().saturating_sub(self.delimiter.len() - 1);
+            if safe > 0 {
+                let n = safe.min(out.len());
+                out[..n].copy_from_slice(&self.buf[..n]);
+                self.buf.drain(..n);
+                return Ok(n);
+            }
+            if self.eof {
+                return Err(malformed("missing closing boundary"));
+            }
+            self.fill(self.buf.len() + 4096)?;
+        }
+    }
+
+    // reads the header block after a delimiter, or None after the last part
+    fn read_headers(&mut self) -> io::Result<Option<Vec<(String, String)>>> {
+        self.fill(2)?;
+        if self.buf.starts_with(b"--") {
+            self.state = State::Done;
+            return Ok(None);
+        }
+        let end = loop {
+            // a part without headers starts right after the delimiter's CRLF
+            if let Some(end) = find(&self.buf, b"\r\n\r\n") {
+                break end;
+            }
+            if self.buf.len() > MAX_PART_HEADERS {
+                return Err(malformed("part headers too large"));
+            }
+            if self.eof {
+                return Err(malformed("unterminated part headers"));
+            }
+            self.fill(self.buf.len() + 1)?;
+        };
+        let block: Vec<u8> = self.buf.drain(..end + 4).collect();
+        let block = String::from_utf8(block).map_err(|_| malformed("invalid part headers"))?;
+        // whitespace may pad the delimiter line
+        let mut lines = block.split("\r\n");
+        if !lines.next().unwrap_or_default().trim().is_empty() {
+            return Err(malformed("garbage after boundary"));
+        }
+        let headers = lines
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let (k, v) = line.split_once(':').ok_or_else(|| malformed("invalid header"))?;
+                Ok((k.trim().to_ascii_lowercase(), v.trim().to_owned()))
+            })
+            .collect::<io::Result<_>>()?;
+        self.state = State::Body;
+        Ok(Some(headers))
+    }
+
+    fn advance(&mut self) -> io::Result<Option<Vec<(String, String)>>> {
+        // skip the preamble, or whatever the caller left of the previous part
+        while matches!(self.state, State::Preamble | State::Body) {
+            io::copy(&mut PartBody(self), &mut io::sink())?;
+        }
+        match self.state {
+            State::Delimiter => self.read_headers(),
+            _ => Ok(None),
+        }
+    }
+
+    // The next part, or None once all parts have been read. Unread bytes of
+    // the previous part are skipped.
+    pub fn next_part(&mut self) -> Result<Option<Part<'_, 'r>>, HttpError> {
+        let Some(headers) = self.advance().map_err(|err| {
+            eprintln!("error: {}", err);
+            HttpError(HttpStatus::BadRequest)
+        })?
+        else {
+            return Ok(None);
+        };
+        let get = |name: &str| headers.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str());
+        let (_, params) = parse_params(get("content-disposition").unwrap_or_default());
+        let param = |name: &str| params.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone());
+        Ok(Some(Part {
+            name: param("name").unwrap_or_default(),
+            filename: param("filename"),
+            content_type: get("content-type").map(str::to_owned),
+            multipart: self,
+        }))
+    }
+}
+
+struct PartBody<'a, 'r>(&'a mut Multipart<'r>);
+
+impl Read for PartBody<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read_body(buf)
+    }
+}
+
+// A single part; its body is read from the part itself.
+pub struct Part<'a, 'r> {
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    multipart: &'a mut Multipart<'r>,
+}
+
+impl Part<'_, '_> {
+    // Streams the body into a new file at |path|, returning its size. The
+    // body goes to a temporary file next to it first, which only takes the
+    // name once complete, so a failed upload never leaves a truncated file.
+    pub fn save(&mut self, path: &Path) -> io::Result<u64> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        if path.exists() {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        let name = path.file_name().ok_or(io::ErrorKind::InvalidInput)?.to_string_lossy();
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let temp = path.with_file_name(format!(".{}.{}-{}.part", name, process::id(), n));
+        let result = File::create_new(&temp).and_then(|mut file| io::copy(self, &mut file));
+        // unlike a rename, linking fails if another upload took the name meanwhile
+        let result = result.and_then(|size| fs::hard_link(&temp, path).map(|_| size));
+        let _ = fs::remove_file(&temp);
+        result
+    }
+}
+
+impl Read for Part<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.multipart.read_body(buf)
+    }
+}
+
+impl Request<'_> {
+    // A reader for a multipart/form-data body, answering 415 for other
+    // content types and 400 if there's no boundary.
+    pub fn multipart(&mut self) -> Result<Multipart<'_>, HttpError> {
+        if !self.content_type_is("multipart/form-data") {
+            return Err(HttpError(HttpStatus::UnsupportedMediaType));
+        }
+        let (_, params) = parse_params(self.get_header("content-type").unwrap_or_default());
+        let boundary = params
+            .into_iter()
+            .find(|(k, v)| k == "boundary" && !v.is_empty())
+            .ok_or(HttpError(HttpStatus::BadRequest))?
+            .1;
+        Ok(Multipart::new(&mut self.body, &boundary))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::TempDir;
+
+    // name, filename and body of a part
+    type Parsed = (String, Option<String>, Vec<u8>);
+
+    const BODY: &str = "preamble\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\
+        \r\n\
+        hello\r\n\
+        --XyZ  \r\n\
+        Content-Disposition: form-data; name=\"upload\"; filename=\"a \\\"b\\\";.txt\"\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        line one\r\n--Xy\r\n\
+        --XyZ--\r\n\
+        epilogue";
+
+    fn parts(body: &[u8], chunk: usize) -> Result<Vec<Parsed>, HttpError> {
+        // serve the input in small reads to exercise delimiters split across them
+        struct Chunked<'a>(&'a [u8], usize);
+        impl Read for Chunked<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let n = self.0.len().min(self.1).min(buf.len());
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+        let mut reader = Chunked(body, chunk);
+        let mut multipart = Multipart::new(&mut reader, "XyZ");
+        let mut parts = Vec::new();
+        while let Some(mut part) = multipart.next_part()? {
+            let mut data = Vec::new();
+            part.read_to_end(&mut data).map_err(|_| HttpError(HttpStatus::BadRequest))?;
+            parts.push((part.name.clone(), part.filename.clone(), data));
+        }
+        Ok(parts)
+    }
+
+    #[test]
+    fn test_parts() {
+        for chunk in [1, 3, 7, 4096] {
+            let parts = parts(BODY.as_bytes(), chunk).unwrap();
+            assert_eq!(parts.len(), 2);
+            assert_eq!(parts[0], ("title".to_string(), None, b"hello".to_vec()));
+            assert_eq!(parts[1].0, "upload");
+            assert_eq!(parts[1].1.as_deref(), Some("a \"b\";.txt"));
+            assert_eq!(parts[1].2, b"line one\r\n--Xy");
+        }
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert!(parts(b"--XyZ\r\nContent-Disposition: form-data; name=a\r\n\r\nunterminated", 5)
+            .is_err());
+        assert!(parts(b"no delimiter at all", 5).is_err());
+        assert!(parts(b"--XyZjunk\r\n\r\n--XyZ--", 5).is_err());
+        assert!(parts(b"--XyZ--", 5).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_save() {
+        let dir = TempDir::new("multipart").unwrap();
+        let save = |body: &[u8], name: &str| {
+            let mut reader = body;
+            let mut multipart = Multipart::new(&mut reader, "XyZ");
+            multipart.next_part().unwrap().unwrap().save(&dir.join(name))
+        };
+        assert_eq!(save(BODY.as_bytes(), "a.txt").unwrap(), 5);
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "hello");
+        let err = save(BODY.as_bytes(), "a.txt").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        // a cut-off upload leaves nothing behind, not even its temporary file
+        let truncated = b"--XyZ\r\nContent-Disposition: form-data; name=a\r\n\r\nunterminated";
+        assert!(save(truncated, "b.txt").is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,91 @@
+#[cfg(feature = "dylib")]
+use std::path::Path;
+
+use crate::{MiddlewareFactory, Router};
+
+// A bundle of routes and middleware that can be shipped as a separate crate
+// and installed with ServerBuilder::plugin, or built as a cdylib and loaded
+// with load_plugin.
+pub trait Plugin {
+    fn routes(&self, router: Router) -> Router {
+        router
+    }
+
+    fn middleware(&self) -> Vec<Box<dyn MiddlewareFactory>> {
+        Vec::new()
+    }
+}
+
+// so what load_plugin returns can be installed like any other plugin
+impl<P: Plugin + ?Sized> Plugin for Box<P> {
+    fn routes(&self, router: Router) -> Router {
+        (**self).routes(router)
+    }
+
+    fn middleware(&self) -> Vec<Box<dyn MiddlewareFactory>> {
+        (**self).middleware()
+    }
+}
+
+// The name a plugin library exports its PluginEntry under.
+#[cfg(feature = "dylib")]
+pub const PLUGIN_ENTRY: &str = "http_server_plugin";
+
+// The entry point of a plugin library, which hands over its plugin boxed
+// twice, as a Box<dyn Plugin> is too wide for a C return value. Define it
+// with export_plugin! rather than by hand.
+#[cfg(feature = "dylib")]
+pub type PluginEntry = unsafe extern "C" fn() -> *mut Box<dyn Plugin>;
+
+// Defines the PluginEntry of a cdylib crate, returning |plugin|:
+//
+//     codecrafters_http_server::export_plugin!(Greeter);
+#[cfg(feature = "dylib")]
+#[macro_export]
+macro_rules! export_plugin {
+    ($plugin:expr) => {
+        #[no_mangle]
+        pub extern "C" fn http_server_plugin() -> *mut Box<dyn $crate::Plugin> {
+            let plugin: Box<dyn $crate::Plugin> = Box::new($plugin);
+            Box::into_raw(Box::new(plugin))
+        }
+    };
+}
+
+// Loads the plugin a library built with export_plugin! exports.
+//
+// Rust has no stable ABI, and the plugin crosses over as a trait object: the
+// library must be built by the same rustc, against the same version of this
+// crate, as the server loading it. A mismatch is undefined behavior, which
+// nothing here can detect. The library stays loaded until the process exits,
+// since the routes and middleware it registers run its code.
+#[cfg(feature = "dylib")]
+pub fn load_plugin(path: impl AsRef<Path>) -> Result<Box<dyn Plugin>, libloading::Error> {
+    // SAFETY: only as safe as the library, see above
+    unsafe {
+        let library = libloading::Library::new(path.as_ref())?;
+        let plugin = {
+            let entry = library.get::<PluginEntry>(PLUGIN_ENTRY.as_bytes())?;
+            Box::from_raw(entry())
+        };
+        std::mem::forget(library);
+        Ok(*plugin)
+    }
+}
+
+#[cfg(all(test, feature = "dylib"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_plugin() {
+        let err = load_plugin("/nonexistent/libplugin.so").err().unwrap();
+        assert!(err.to_string().contains("libplugin.so"), "{}", err);
+        // a library that loads, but isn't a plugin
+        #[cfg(target_os = "linux")]
+        {
+            let err = load_plugin("libc.so.6").err().unwrap();
+            assert!(err.to_string().contains(PLUGIN_ENTRY), "{}", err);
+        }
+    }
+}
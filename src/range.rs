@@ -0,0 +1,185 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::{
+    HttpStatus, Method, Middleware, MiddlewareError, MiddlewareFactory, Request, Response,
+    ResponseBody,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    // bytes=start- or bytes=start-end
+    From(u64, Option<u64>),
+    // bytes=-len
+    Suffix(u64),
+}
+
+impl ByteRange {
+    // only single ranges are supported, anything else is served in full
+    pub fn parse(value: &str) -> Option<Self> {
+        let spec = value.trim().strip_prefix("bytes=")?;
+        if spec.contains(',') {
+            return None;
+        }
+        let (start, end) = spec.split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
+        if start.is_empty() {
+            return Some(Self::Suffix(end.parse().ok()?));
+        }
+        let start = start.parse().ok()?;
+        let end = if end.is_empty() { None } else { Some(end.parse().ok()?) };
+        match end {
+            Some(end) if end < start => None,
+            _ => Some(Self::From(start, end)),
+        }
+    }
+
+    // returns the inclusive (start, end) offsets, or None if unsatisfiable
+    pub fn resolve(&self, len: u64) -> Option<(u64, u64)> {
+        if len == 0 {
+            return None;
+        }
+        match *self {
+            Self::From(start, _) if start >= len => None,
+            Self::From(start, end) => Some((start, end.map_or(len - 1, |end| end.min(len - 1)))),
+            Self::Suffix(0) => None,
+            Self::Suffix(n) => Some((len.saturating_sub(n), len - 1)),
+        }
+    }
+}
+
+pub struct RangeFactory;
+
+impl MiddlewareFactory for RangeFactory {
+    fn new(&self, req: &Request) -> Option<Box<dyn Middleware>> {
+        // ranges are only defined for GET, so other methods get the full response
+        if req.method != Method::Get {
+            return None;
+        }
+        let range = req.range()?;
+        Some(Box::new(Range(range)))
+    }
+}
+
+// Serves a slice of any successful response with a known length. Files are
+// seeked into; other bodies still produce the skipped prefix, which is
+// discarded.
+pub struct Range(ByteRange);
+
+impl Middleware for Range {
+    fn apply_before(&self, _req: &mut Request) -> Result<(), MiddlewareError> {
+        // do nothing
+        Ok(())
+    }
+
+    fn apply_after(&self, resp: &mut Response) -> Result<(), MiddlewareError> {
+        if resp.status != HttpStatus::OK {
+            return Ok(());
+        }
+        let Some(len) = resp.get_header("content-length").and_then(|v| v.parse::<u64>().ok())
+        else {
+            return Ok(());
+        };
+        let Some((start, end)) = self.0.resolve(len) else {
+            resp.status = HttpStatus::RangeNotSatisfiable;
+            resp.body = None;
+            resp.set_header("content-range".to_string(), format!("bytes */{}", len));
+            resp.set_header("content-length".to_string(), "0".to_string());
+            return Ok(());
+        };
+        let size = end - start + 1;
+        resp.body = match resp.body.take() {
+            Some(ResponseBody::Bytes(data)) => {
+                let start = (start as usize).min(data.len());
+                let end = (start + size as usize).min(data.len());
+                Some(ResponseBody::Bytes(data.slice(start..end)))
+            }
+            Some(ResponseBody::File(mut file)) => {
+                file.seek(SeekFrom::Start(start))?;
+                Some(ResponseBody::Reader(Box::new(file.take(size))))
+            }
+            Some(ResponseBody::Reader(mut data)) => {
+                io::copy(&mut (&mut data).take(start), &mut io::sink())?;
+                Some(ResponseBody::Reader(Box::new(data.take(size))))
+            }
+            Some(ResponseBody::Writer(write)) => Some(ResponseBody::Writer(Box::new(move |w| {
+                write(&mut Window { inner: w, skip: start, left: size })
+            }))),
+            None => None,
+        };
+        resp.status = HttpStatus::PartialContent;
+        resp.set_header("content-range".to_string(), format!("bytes {}-{}/{}", start, end, len));
+        resp.set_header("content-length".to_string(), size.to_string());
+        Ok(())
+    }
+}
+
+// Passes on the part of a written body that falls within a range.
+struct Window<'t> {
+    inner: &'t mut dyn Write,
+    // bytes still to drop before the range, then to pass on
+    skip: u64,
+    left: u64,
+}
+
+impl Write for Window<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let skipped = self.skip.min(buf.len() as u64) as usize;
+        let rest = &buf[skipped..];
+        let kept = self.left.min(rest.len() as u64) as usize;
+        self.inner.write_all(&rest[..kept])?;
+        self.skip -= skipped as u64;
+        self.left -= kept as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{parse_request, MimeTypes, TempDir};
+
+    #[test]
+    fn test_writer_range() {
+        let mut resp = Response::from_writer(|w| {
+            for piece in [&b"abc"[..], b"defg", b"hij"] {
+                w.write_all(piece)?;
+            }
+            Ok(())
+        });
+        resp.set_header("content-length".to_string(), "10".to_string());
+        Range(ByteRange::From(2, Some(7))).apply_after(&mut resp).unwrap();
+        assert_eq!(resp.status, HttpStatus::PartialContent);
+        assert_eq!(resp.get_header("content-range"), Some("bytes 2-7/10"));
+        assert_eq!(resp.body.unwrap().into_bytes().unwrap(), b"cdefgh");
+    }
+
+    #[test]
+    fn test_file_range() {
+        let dir = TempDir::new("file-range").unwrap();
+        std::fs::write(dir.join("a.txt"), "0123456789").unwrap();
+        let range = |method: &str| {
+            let raw = format!("{} / HTTP/1.1\r\nRange: bytes=6-\r\n\r\n", method);
+            let mut reader = Cursor::new(raw.into_bytes());
+            let req = parse_request(&mut reader).unwrap();
+            let mut resp = Response::file(dir.join("a.txt"), &MimeTypes::default()).unwrap();
+            if let Some(middleware) = RangeFactory.new(&req) {
+                middleware.apply_after(&mut resp).unwrap();
+            }
+            resp
+        };
+
+        let resp = range("GET");
+        assert_eq!(resp.status, HttpStatus::PartialContent);
+        assert_eq!(resp.body.unwrap().into_bytes().unwrap(), b"6789");
+        // only GET has ranges
+        let resp = range("POST");
+        assert_eq!(resp.status, HttpStatus::OK);
+        assert_eq!(resp.body.unwrap().into_bytes().unwrap(), b"0123456789");
+    }
+}
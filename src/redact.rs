@@ -0,0 +1,98 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{form::decode, Request};
+
+const REDACTED: &str = "[redacted]";
+
+// Which header and query parameter values to keep out of the logs, see the
+// corresponding Config fields.
+#[derive(Debug, Clone, Default)]
+pub struct Redaction {
+    // lowercase names
+    headers: Vec<String>,
+    query_params: Vec<String>,
+    // with a key, values are replaced with their HMAC under it instead of a
+    // fixed marker, so requests sharing a credential can still be correlated;
+    // without the key, guessing short secrets from the logs isn't possible
+    hash_key: Option<Vec<u8>>,
+}
+
+impl Redaction {
+    pub fn new(headers: &[String], query_params: &[String], hash_key: Option<&str>) -> Self {
+        let lower = |names: &[String]| names.iter().map(|n| n.to_ascii_lowercase()).collect();
+        let hash_key = hash_key.map(|key| key.as_bytes().to_vec());
+        Self { headers: lower(headers), query_params: lower(query_params), hash_key }
+    }
+
+    fn replacement(&self, value: &str) -> String {
+        let Some(key) = &self.hash_key else {
+            return REDACTED.to_string();
+        };
+        // HMAC takes keys of any length
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+        mac.update(value.as_bytes());
+        // 64 bits are plenty to tell values apart in logs
+        let digest = mac.finalize().into_bytes();
+        format!("hmac:{:016x}", u64::from_be_bytes(digest[..8].try_into().unwrap()))
+    }
+
+    pub fn header(&self, name: &str, value: &str) -> String {
+        if self.headers.iter().any(|h| h.eq_ignore_ascii_case(name)) {
+            self.replacement(value)
+        } else {
+            value.to_string()
+        }
+    }
+
+    // |query| with the values of redacted parameters replaced. Names are
+    // compared decoded, so encoding them doesn't slip past.
+    pub fn query(&self, query: &str) -> String {
+        let pairs = query.split('&').map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let decoded = decode(name.as_bytes()).unwrap_or_else(|_| name.to_string());
+            if self.query_params.iter().any(|p| p.eq_ignore_ascii_case(&decoded)) {
+                format!("{}={}", name, self.replacement(value))
+            } else {
+                pair.to_string()
+            }
+        });
+        pairs.collect::<Vec<_>>().join("&")
+    }
+
+    // The request's path and redacted query, as it should appear in logs.
+    pub fn target(&self, req: &Request) -> String {
+        match &req.query {
+            Some(query) => format!("{}?{}", req.path, self.query(query)),
+            None => req.path.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn test_redaction() {
+        let strip = Redaction::new(&names(&["Authorization"]), &names(&["token", "key"]), None);
+        assert_eq!(strip.header("authorization", "Bearer abc"), "[redacted]");
+        assert_eq!(strip.header("accept", "*/*"), "*/*");
+        assert_eq!(
+            strip.query("a=1&token=s3cret&Key=k&%74oken=x&flag"),
+            "a=1&token=[redacted]&Key=[redacted]&%74oken=[redacted]&flag"
+        );
+
+        let hash = Redaction::new(&names(&["cookie"]), &names(&["token"]), Some("k1"));
+        let (a, b) = (hash.query("token=one"), hash.query("token=two"));
+        assert!(a.starts_with("token=hmac:") && !a.contains("one"));
+        assert_ne!(a, b);
+        assert_eq!(a, hash.query("token=one"));
+        let other_key = Redaction::new(&[], &names(&["token"]), Some("k2"));
+        assert_ne!(a, other_key.query("token=one"));
+    }
+}
@@ -0,0 +1,72 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpListener},
+};
+
+// Binds a listener with SO_REUSEPORT set, so several can share |addr| and the
+// kernel spreads incoming connections across their separate accept queues.
+#[cfg(target_os = "linux")]
+pub fn bind_reuseport(addr: SocketAddr) -> io::Result<TcpListener> {
+    use std::{mem, os::fd::FromRawFd};
+
+    let check = |ret: libc::c_int| if ret < 0 { Err(io::Error::last_os_error()) } else { Ok(ret) };
+    let domain = if addr.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+    // SAFETY: the fd is fresh and owned by |listener| from here on, and the
+    // sockaddr_storage is large enough for either address family
+    unsafe {
+        let fd = check(libc::socket(domain, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0))?;
+        let listener = TcpListener::from_raw_fd(fd);
+        let one: libc::c_int = 1;
+        for opt in [libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+            check(libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                opt,
+                &one as *const _ as *const libc::c_void,
+                mem::size_of_val(&one) as libc::socklen_t,
+            ))?;
+        }
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                let sin = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in);
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr = libc::in_addr { s_addr: u32::from_ne_bytes(addr.ip().octets()) };
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6);
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_addr = libc::in6_addr { s6_addr: addr.ip().octets() };
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_scope_id = addr.scope_id();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        let sockaddr = &storage as *const _ as *const libc::sockaddr;
+        check(libc::bind(fd, sockaddr, len as libc::socklen_t))?;
+        check(libc::listen(fd, 128))?;
+        Ok(listener)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_reuseport(_addr: SocketAddr) -> io::Result<TcpListener> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "SO_REUSEPORT listeners need Linux"))
+}
+
+// Wakes a thread blocked accepting on |listener| and takes the listener out
+// of its reuseport group, so new connections go to the remaining ones.
+#[cfg(target_os = "linux")]
+pub fn shutdown(listener: &TcpListener) {
+    use std::os::fd::AsRawFd;
+    // SAFETY: the fd is valid for as long as |listener| is
+    unsafe {
+        libc::shutdown(listener.as_raw_fd(), libc::SHUT_RDWR);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn shutdown(_listener: &TcpListener) {}
@@ -1,21 +1,45 @@
 use crate::{
-    parse_request, thread_pool::ThreadPool, CompressionFactory, Context, Handler, HttpError,
-    Request, RequestParsingError, Response,
+    accept::AcceptBackoff,
+    debug::DebugRoutes,
+    diagnostics,
+    handlers::RouteSlot,
+    http_date, is_valid_header, parse_mime_override, parse_request_with, reuseport,
+    thread_pool::{ThreadPool, WorkerOptions},
+    AuditLog, ChaosFactory, Client, ClientMiddleware, ClientTls, CompressionFactory,
+    ConditionalFactory, ConfigError, ConfigFile, Context, DecompressionFactory, Encoding, Fault,
+    ForwardPolicy, Handler, HttpError, HttpStatus, IntoHandler, MemoryBudget, Method, Metrics,
+    MetricsRoute, MimeTypes, ParseOptions, Plugin, RangeFactory, Redaction, Request, Response,
+    ResponseBody, Router, Settings, SlowLog, SlowRequest, Timings, TraceFactory, TypePolicy, Urls,
+    Version, Webhook, INCOMPRESSIBLE_TYPES,
 };
 use clap::Parser;
+use regex::Regex;
 use std::{
     env,
     error::Error,
     fmt::Display,
-    io::{self, BufReader, BufWriter, Write},
-    net::{TcpListener, TcpStream},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
     path::PathBuf,
-    sync::{Arc, Mutex},
-    time::Duration,
+    sync::{atomic::Ordering, Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
 };
 
+// Either a failure, which aborts the connection, or a rejection, which
+// answers the request with a status instead of invoking the handler.
 #[derive(Debug)]
-pub struct MiddlewareError(String);
+pub struct MiddlewareError(String, Option<HttpStatus>);
+
+impl MiddlewareError {
+    pub fn reject(status: HttpStatus) -> Self {
+        Self(status.to_string(), Some(status))
+    }
+
+    pub fn status(&self) -> Option<HttpStatus> {
+        self.1
+    }
+}
 
 impl Display for MiddlewareError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -25,11 +49,24 @@ impl Display for MiddlewareError {
 
 impl<E: Error> From<E> for MiddlewareError {
     fn from(value: E) -> Self {
-        Self(value.to_string())
+        Self(value.to_string(), None)
+    }
+}
+
+impl From<MiddlewareError> for HttpError {
+    fn from(err: MiddlewareError) -> Self {
+        match err.status() {
+            Some(status) => HttpError(status),
+            None => {
+                eprintln!("{}", err);
+                HttpError(HttpStatus::ServerError)
+            }
+        }
     }
 }
 
 pub trait MiddlewareFactory: Send + Sync {
+    #[allow(clippy::new_ret_no_self, clippy::wrong_self_convention)]
     fn new(&self, req: &Request) -> Option<Box<dyn Middleware>>;
 }
 
@@ -55,18 +92,24 @@ impl From<io::Error> for ConnectionError {
     }
 }
 
-impl From<RequestParsingError> for ConnectionError {
-    fn from(err: RequestParsingError) -> Self {
-        Self(err.to_string())
-    }
-}
-
 impl From<MiddlewareError> for ConnectionError {
     fn from(err: MiddlewareError) -> Self {
         Self(err.to_string())
     }
 }
 
+// How accepted connections reach the workers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum AcceptMode {
+    // one thread accepts and hands each connection to the worker pool
+    #[default]
+    Shared,
+    // every worker has its own SO_REUSEPORT listener and serves the
+    // connections it accepts itself; Linux only, elsewhere the server warns
+    // and accepts in shared mode instead
+    PerWorker,
+}
+
 #[derive(PartialEq, Eq, Clone, Copy)]
 enum ServerState {
     Stopped,
@@ -87,8 +130,202 @@ pub struct Config {
     pub read_timeout_ms: u64,
     #[arg(long, default_value = "4")]
     pub workers: usize,
+    /// Stack size of worker threads in bytes, defaults to the platform's
+    #[arg(long)]
+    pub worker_stack_size: Option<usize>,
+    /// CPU cores to pin worker threads to, assigned round-robin (Linux only)
+    #[arg(long, value_delimiter = ',')]
+    pub worker_cpus: Vec<usize>,
+    /// Whether one thread accepts for all workers, or each worker accepts on its own listener
+    #[arg(long, value_enum, default_value = "shared")]
+    pub accept_mode: AcceptMode,
+    /// Acceptors allowed to retry at once while backing off after accept failures
+    #[arg(long, default_value = "1")]
+    pub accept_probes: usize,
     #[arg(long, default_value = ".")]
     pub directory: PathBuf,
+    #[arg(long, default_value = "100")]
+    pub max_requests_per_connection: usize,
+    /// Value of the Server response header, or empty to omit it
+    #[arg(long, default_value = "codecrafters-http-server")]
+    pub server_name: String,
+    /// Report per-phase durations in a Server-Timing response header
+    #[arg(long)]
+    pub server_timing: bool,
+    /// Answer 500s with a page describing the failure, including caught panics; for local
+    /// development only, as it reveals the server's internals
+    #[arg(long)]
+    pub dev_errors: bool,
+    /// Log requests taking at least this long to the slow log
+    #[arg(long)]
+    pub slow_request_ms: Option<u64>,
+    /// File to append slow requests to, defaults to stderr
+    #[arg(long)]
+    pub slow_log: Option<PathBuf>,
+    /// Lowercase incoming header names and merge repeated header fields
+    #[arg(long)]
+    pub normalize_headers: bool,
+    /// Largest request body handlers read into memory
+    #[arg(long, default_value = "1048576")]
+    pub max_body_bytes: u64,
+    /// Approximate memory connections may hold before new requests are shed with 503s
+    #[arg(long)]
+    pub memory_limit_bytes: Option<usize>,
+    /// Largest request header block, and request line, in bytes
+    #[arg(long, default_value = "65536")]
+    pub max_header_bytes: usize,
+    /// Accept requests from legacy clients with bare LF line endings or raw spaces in the path,
+    /// logging a warning
+    #[arg(long)]
+    pub lenient_parsing: bool,
+    /// Largest single request header value in bytes
+    #[arg(long, default_value = "16384")]
+    pub max_header_value_bytes: usize,
+    /// File to append an audit record of every file upload, overwrite or deletion to
+    #[arg(long)]
+    pub audit_log: Option<PathBuf>,
+    /// Sync the audit log to disk after every record
+    #[arg(long)]
+    pub audit_fsync: bool,
+    /// URL to POST a JSON event to whenever a file is uploaded, overwritten or deleted
+    #[arg(long)]
+    pub webhook_url: Option<String>,
+    /// Secret for the HMAC-SHA256 signature sent with webhook events
+    #[arg(long)]
+    pub webhook_secret: Option<String>,
+    /// Times to retry a failed webhook delivery
+    #[arg(long, default_value = "3")]
+    pub webhook_retries: u32,
+    /// Delay before the first webhook retry, doubling after each
+    #[arg(long, default_value = "500")]
+    pub webhook_backoff_ms: u64,
+    /// Webhook events that may wait for delivery before new ones are dropped
+    #[arg(long, default_value = "1024")]
+    pub webhook_queue: usize,
+    /// Content type to serve files with an extension as, overriding the built-in table
+    #[arg(long = "mime-type", value_name = "EXT=TYPE", value_parser = parse_mime_override)]
+    pub mime_types: Vec<(String, String)>,
+    /// PEM bundle of extra CA certificates to trust for outbound HTTPS, e.g. webhooks
+    #[arg(long)]
+    pub tls_ca_bundle: Option<PathBuf>,
+    /// DANGEROUS: don't verify certificates of outbound HTTPS servers, for test environments only
+    #[arg(long)]
+    pub tls_insecure_skip_verify: bool,
+    /// PEM certificate chain to present to outbound HTTPS servers requiring mutual TLS
+    #[arg(long, requires = "tls_client_key")]
+    pub tls_client_cert: Option<PathBuf>,
+    /// PKCS#8 PEM private key for --tls-client-cert
+    #[arg(long, requires = "tls_client_cert")]
+    pub tls_client_key: Option<PathBuf>,
+    /// Inbound request headers that outbound requests made on a request's behalf pass on
+    #[arg(long, value_delimiter = ',')]
+    pub client_forward_headers: Vec<String>,
+    /// Serve the built-in diagnostic routes /__echo, /__status, /__delay/{ms} (up to a
+    /// minute) and /__bytes/{n} (up to 16 MiB)
+    #[arg(long)]
+    pub debug_routes: bool,
+    /// Serve the request metrics at /metrics, in the Prometheus text format; they're public to
+    /// any client that can reach the server
+    #[arg(long)]
+    pub metrics: bool,
+    /// Headers whose values are redacted in logs: the slow log's records, and those of handlers
+    /// and plugins logging through the context's redaction
+    #[arg(long, value_delimiter = ',', default_value = "authorization,proxy-authorization,cookie")]
+    pub redact_headers: Vec<String>,
+    /// Query parameters whose values are redacted in logs
+    #[arg(long, value_delimiter = ',', default_value = "token,key,password")]
+    pub redact_query_params: Vec<String>,
+    /// Log an HMAC-SHA256 of redacted values under this secret key instead of removing them
+    #[arg(long, value_name = "KEY")]
+    pub redact_hash_key: Option<String>,
+    /// Bucket bounds for the request and response body size histograms
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "64,256,1024,4096,16384,65536,262144,1048576,4194304,16777216"
+    )]
+    pub size_buckets: Vec<f64>,
+    #[arg(long, value_delimiter = ',', default_value = "4,8,16,32,64,128")]
+    pub header_count_buckets: Vec<f64>,
+    /// TOML file declaring the middleware chain and each one's settings, e.g. a
+    /// [middleware.compression] section with level = 6, and content types by extension in a
+    /// [mime_types] section
+    #[arg(long = "config", value_name = "FILE")]
+    pub config_file: Option<PathBuf>,
+    /// Middleware applied to every request and response, in order; conditional requests are
+    /// answered before ranges, and range should precede compression, so ranges count bytes of
+    /// the content rather than of its encoding
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "trace,decompression,conditional,range,compression,chaos",
+        value_parser = ["trace", "decompression", "conditional", "compression", "range", "chaos"]
+    )]
+    pub middleware: Vec<String>,
+    /// Largest request body the decompression middleware inflates a compressed upload to
+    #[arg(long, default_value = "16777216")]
+    pub max_decompressed_body_bytes: u64,
+    /// Content codings the compression middleware may use, most preferred first
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "zstd,gzip,deflate")]
+    pub compression_algos: Vec<Encoding>,
+    /// Gzip and deflate level from 0 (none) to 9 (smallest) for the compression middleware
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(0..=9))]
+    pub compression_level: u32,
+    /// Zstd level from 1 (fastest) to 22 (smallest) for the compression middleware
+    #[arg(long, default_value = "3", value_parser = clap::value_parser!(i32).range(1..=22))]
+    pub zstd_level: i32,
+    /// Smallest response in bytes the compression middleware encodes, 0 compresses any size
+    #[arg(long, default_value = "0")]
+    pub compression_min_bytes: u64,
+    /// Content types the compression middleware encodes, as type/subtype or type/*; any when
+    /// unset
+    #[arg(long, value_delimiter = ',')]
+    pub compress_types: Vec<String>,
+    /// Content types the compression middleware leaves alone, as type/subtype or type/*
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_values = INCOMPRESSIBLE_TYPES.iter().copied()
+    )]
+    pub no_compress_types: Vec<String>,
+    /// Fraction of matching requests to inject faults into, 0 disables chaos testing
+    #[arg(long, default_value = "0")]
+    pub chaos_rate: f64,
+    #[arg(long, default_value = ".*")]
+    pub chaos_routes: String,
+    #[arg(long, default_value = "0")]
+    pub chaos_delay_ms: u64,
+    #[arg(long, value_enum, default_value = "none")]
+    pub chaos_fault: Fault,
+}
+
+impl Config {
+    pub fn client_tls(&self) -> ClientTls {
+        ClientTls {
+            ca_bundle: self.tls_ca_bundle.clone(),
+            insecure_skip_verify: self.tls_insecure_skip_verify,
+            client_cert: self.tls_client_cert.clone(),
+            client_key: self.tls_client_key.clone(),
+        }
+    }
+
+    // A client for outbound requests, e.g. webhook deliveries, with the
+    // configured TLS settings and forwarded headers. Connections are kept for
+    // reuse by reqwest's own pool, and a host with both IPv6 and IPv4
+    // addresses is reached as RFC 8305 describes: its connector tries the
+    // family of the first address and falls back to the other after 300ms.
+    // Webhook deliveries are retried by the Webhook itself.
+    pub fn client(&self) -> io::Result<Client> {
+        let builder = self.client_tls().builder()?.timeout(Duration::from_secs(10));
+        if self.tls_insecure_skip_verify {
+            eprintln!("warning: not verifying TLS certificates of outbound requests");
+        }
+        let mut client = Client::new(builder.build().map_err(io::Error::other)?);
+        let forward =
+            self.client_forward_headers.iter().fold(ForwardPolicy::default(), |p, h| p.allow(h));
+        client = client.forward(forward);
+        Ok(client)
+    }
 }
 
 impl Default for Config {
@@ -99,73 +336,476 @@ impl Default for Config {
             write_timeout_ms: 1000,
             read_timeout_ms: 1000,
             workers: 4,
+            worker_stack_size: None,
+            worker_cpus: Vec::new(),
+            accept_mode: AcceptMode::Shared,
+            accept_probes: 1,
             directory: env::current_dir().unwrap(),
+            max_requests_per_connection: 100,
+            server_name: String::from("codecrafters-http-server"),
+            server_timing: false,
+            dev_errors: false,
+            slow_request_ms: None,
+            slow_log: None,
+            normalize_headers: false,
+            max_body_bytes: 1 << 20,
+            memory_limit_bytes: None,
+            max_header_bytes: 64 << 10,
+            lenient_parsing: false,
+            max_header_value_bytes: 16 << 10,
+            audit_log: None,
+            audit_fsync: false,
+            webhook_url: None,
+            webhook_secret: None,
+            webhook_retries: 3,
+            webhook_backoff_ms: 500,
+            webhook_queue: 1024,
+            mime_types: Vec::new(),
+            tls_ca_bundle: None,
+            tls_insecure_skip_verify: false,
+            tls_client_cert: None,
+            tls_client_key: None,
+            client_forward_headers: Vec::new(),
+            debug_routes: false,
+            metrics: false,
+            redact_headers: ["authorization", "proxy-authorization", "cookie"]
+                .map(String::from)
+                .into(),
+            redact_query_params: ["token", "key", "password"].map(String::from).into(),
+            redact_hash_key: None,
+            size_buckets: vec![
+                64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0, 4194304.0,
+                16777216.0,
+            ],
+            header_count_buckets: vec![4.0, 8.0, 16.0, 32.0, 64.0, 128.0],
+            config_file: None,
+            middleware: ["trace", "decompression", "conditional", "range", "compression", "chaos"]
+                .map(String::from)
+                .to_vec(),
+            max_decompressed_body_bytes: 16 << 20,
+            compression_algos: vec![Encoding::Zstd, Encoding::Gzip, Encoding::Deflate],
+            compression_level: 1,
+            zstd_level: 3,
+            compression_min_bytes: 0,
+            compress_types: Vec::new(),
+            no_compress_types: INCOMPRESSIBLE_TYPES.iter().map(|t| t.to_string()).collect(),
+            chaos_rate: 0.0,
+            chaos_routes: String::from(".*"),
+            chaos_delay_ms: 0,
+            chaos_fault: Fault::None,
         }
     }
 }
 
+// Writes each buffer it receives as a single chunk, counting the body bytes.
+struct ChunkedWriter<'t> {
+    inner: &'t mut dyn Write,
+    written: u64,
+    // whether each chunk is flushed as soon as it's written
+    streaming: bool,
+}
+
+impl<'t> ChunkedWriter<'t> {
+    fn new(inner: &'t mut dyn Write) -> Self {
+        Self { inner, written: 0, streaming: false }
+    }
+
+    // for bodies produced while they're sent, which should reach the client
+    // as they're produced rather than when the connection's buffer fills
+    fn streaming(inner: &'t mut dyn Write) -> Self {
+        Self { inner, written: 0, streaming: true }
+    }
+
+    // ends the body, returning its length without the chunk framing
+    fn finish(self) -> io::Result<u64> {
+        write!(self.inner, "0\r\n\r\n")?;
+        Ok(self.written)
+    }
+}
+
+impl Write for ChunkedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !buf.is_empty() {
+            write!(self.inner, "{:x}\r\n", buf.len())?;
+            self.inner.write_all(buf)?;
+            write!(self.inner, "\r\n")?;
+            self.written += buf.len() as u64;
+            if self.streaming {
+                self.inner.flush()?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// the default capacity of a connection's BufReader and BufWriter
+const CONNECTION_BUFFER_BYTES: usize = 2 * 8 * 1024;
+
 struct ConnectionHandler {
     context: Context,
     request_handler: Box<dyn Handler>,
     middleware: Vec<Box<dyn MiddlewareFactory>>,
+    max_requests: usize,
+    server_name: String,
+    server_timing: bool,
+    dev_errors: bool,
+    slow_log: Option<SlowLog>,
+    parse_options: ParseOptions,
 }
 
 impl ConnectionHandler {
     fn new(
+        config: &Config,
         context: Context,
         request_handler: Box<dyn Handler>,
         middleware: Vec<Box<dyn MiddlewareFactory>>,
     ) -> Self {
-        Self { context, request_handler, middleware }
+        let slow_log = config.slow_request_ms.map(|ms| {
+            let threshold = Duration::from_millis(ms);
+            SlowLog::open(threshold, config.slow_log.as_deref()).expect("can't open slow log")
+        });
+        Self {
+            context,
+            request_handler,
+            middleware,
+            max_requests: config.max_requests_per_connection,
+            server_name: config.server_name.clone(),
+            server_timing: config.server_timing,
+            dev_errors: config.dev_errors,
+            slow_log,
+            parse_options: ParseOptions {
+                normalize_headers: config.normalize_headers,
+                max_body_bytes: config.max_body_bytes,
+                max_header_bytes: config.max_header_bytes,
+                max_header_value_bytes: config.max_header_value_bytes,
+                lenient: config.lenient_parsing,
+            },
+        }
+    }
+
+    fn record_headers(&self, req: &Request) {
+        let count = req.headers().count() as f64;
+        self.context.metrics.observe("http_request_headers", &[], count);
+    }
+
+    // headers stamped on every response after the handler and middleware ran
+    fn finalize(&self, resp: &mut Response) {
+        resp.status = resp.status.normalized();
+        resp.set_header("date".to_string(), http_date(SystemTime::now()));
+        if !self.server_name.is_empty() && resp.get_header("server").is_none() {
+            resp.set_header("server".to_string(), self.server_name.clone());
+        }
+    }
+
+    // Answers with |status| and no body, then closes the connection.
+    fn close_with(
+        &self,
+        writer: &mut dyn Write,
+        status: HttpStatus,
+    ) -> Result<bool, ConnectionError> {
+        let mut resp = Response::empty();
+        resp.status = status;
+        self.finalize(&mut resp);
+        resp.set_header("content-length".to_string(), "0".to_string());
+        resp.set_header("connection".to_string(), "close".to_string());
+        write_head(writer, &resp)?;
+        writer.flush()?;
+        Ok(false)
+    }
+
+    fn shed(&self, addr: &str, writer: &mut dyn Write) -> Result<bool, ConnectionError> {
+        let memory = &self.context.memory;
+        println!("{}: shedding load, {} bytes of memory in use", addr, memory.used());
+        self.context.metrics.increment("requests_shed_total", &[]);
+        self.close_with(writer, HttpStatus::ServiceUnavailable)
     }
 
     fn handle(&self, stream: TcpStream) -> Result<(), ConnectionError> {
-        let addr = stream.peer_addr().unwrap().to_string();
+        let peer = stream.peer_addr()?;
+        let Some(_buffers) = self.context.memory.reserve(CONNECTION_BUFFER_BYTES) else {
+            self.shed(&peer.to_string(), &mut &stream)?;
+            return Ok(());
+        };
         let mut reader = BufReader::new(&stream);
         let mut writer = BufWriter::new(&stream);
 
-        let mut request = parse_request(&mut reader)?;
+        for served in 1..=self.max_requests {
+            // wait for the next request, quietly closing idle connections
+            match reader.fill_buf() {
+                Ok([]) => break,
+                Ok(_) => {}
+                Err(_) if served > 1 => break,
+                Err(err) => return Err(err.into()),
+            }
+            let last = served == self.max_requests;
+            if !self.handle_request(peer, &mut reader, &mut writer, last)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Serves a single request, returning whether the connection can be reused.
+    fn handle_request(
+        &self,
+        peer: SocketAddr,
+        reader: &mut dyn BufRead,
+        writer: &mut dyn Write,
+        last: bool,
+    ) -> Result<bool, ConnectionError> {
+        let addr = &peer.to_string();
+        let mut timings = Timings::start();
+        let mut request = match parse_request_with(reader, self.parse_options) {
+            Ok(request) => request,
+            Err(err) => {
+                // the rest of the stream can't be framed reliably, so answer
+                // and close rather than guess where the next request starts
+                println!("{}: {}", addr, err);
+                return self.close_with(writer, err.status());
+            }
+        };
+        request.peer_addr = Some(peer);
+        if !request.quirks().is_empty() {
+            let (target, quirks) = (self.context.redaction.target(&request), request.quirks());
+            eprintln!("warning: accepted {} {} with {}", request.method, target, quirks.join(", "));
+        }
+        // handlers may read the body into memory, up to the configured
+        // maximum, which a chunked body of unknown length could reach
+        let max_body = self.parse_options.max_body_bytes;
+        let declared = request.get_header("content-length").and_then(|v| v.parse::<u64>().ok());
+        let body_bytes = match declared {
+            Some(declared) => declared.min(max_body),
+            None if request.get_header("transfer-encoding").is_some() => max_body,
+            None => 0,
+        };
+        let Some(_body) = self.context.memory.reserve(body_bytes as usize) else {
+            // answer before the body arrives rather than reading it just to
+            // throw it away
+            request.body.discard();
+            return self.shed(addr, writer);
+        };
+        self.record_headers(&request);
+        let received = request.body.received();
+        let mut keep_alive = request.keep_alive() && !last;
+        request.strip_hop_by_hop();
+        timings.lap("parse");
         let middleware: Vec<Box<dyn Middleware>> =
             self.middleware.iter().flat_map(|m| m.new(&request)).collect();
+        let mut rejected = None;
         for m in &middleware {
-            m.apply_before(&mut request)?;
+            if let Err(err) = m.apply_before(&mut request) {
+                rejected = Some(err.status().ok_or(err)?);
+                break;
+            }
         }
+        timings.lap("middleware");
 
-        let (method, path) = (request.method, request.path.clone());
-        let result = self.request_handler.handle(&self.context, request);
-        let status = match result {
+        let method = request.method.clone();
+        let target = self.context.redaction.target(&request);
+        let head = method == Method::Head;
+        let version = request.version;
+        let wants_json = diagnostics::prefers_json(request.get_header("accept"));
+        let abandoned = request.body.abandoned();
+        let route = RouteSlot::default();
+        request.extensions.insert(route.clone());
+        // kept for the slow log, as the handler takes the request; they're
+        // only redacted if the request turns out to be slow
+        let headers: Vec<(String, String)> = match &self.slow_log {
+            Some(_) => request.headers().cloned().collect(),
+            None => Vec::new(),
+        };
+        let mut panicked = None;
+        let result = match rejected {
+            Some(status) => {
+                drop(request);
+                Err(HttpError(status))
+            }
+            None => diagnostics::catch(|| self.request_handler.handle(&self.context, request))
+                .unwrap_or_else(|panic| {
+                    eprintln!("error: handler panicked: {}", panic);
+                    panicked = Some(panic);
+                    Err(HttpError(HttpStatus::ServerError))
+                }),
+        };
+        timings.lap("handler");
+        // the request is gone, and with it what the handler left of its body
+        if abandoned.load(Ordering::SeqCst) {
+            keep_alive = false;
+        }
+        // only now is a chunked body's length known, as it's been read
+        let request_bytes = declared.unwrap_or_else(|| received.load(Ordering::Relaxed));
+        self.context.metrics.observe("http_request_size_bytes", &[], request_bytes as f64);
+        let mut resp = match result {
+            Err(HttpError(HttpStatus::ServerError)) if self.dev_errors => {
+                let status = HttpStatus::ServerError;
+                diagnostics::error_page(status, &method, &target, panicked.as_ref(), wants_json)
+            }
             Err(HttpError(status)) => {
-                write!(writer, "HTTP/1.1 {}\r\n", status)?;
-                write!(writer, "\r\n")?;
-                status
+                let mut resp = Response::empty();
+                resp.status = status;
+                resp
             }
             Ok(mut resp) => {
                 for m in &middleware {
                     m.apply_after(&mut resp)?;
                 }
-                write!(writer, "HTTP/1.1 {}\r\n", resp.status)?;
-                for (k, v) in resp.headers() {
-                    write!(writer, "{}: {}\r\n", k, v)?;
-                }
-                write!(writer, "\r\n")?;
-                if let Some(data) = &mut resp.body {
-                    io::copy(data, &mut writer)?;
-                }
-                resp.status
+                resp
             }
         };
 
-        println!("{}: {} {}: {}", addr, method, path, status);
-        Ok(())
+        timings.lap("middleware");
+
+        // the server frames the response itself, but still honors a handler
+        // asking to close
+        if resp.closes_connection() {
+            keep_alive = false;
+        }
+        resp.strip_hop_by_hop();
+        if let Some(name) = invalid_header(&resp) {
+            // e.g. a handler copying a client's input into a header unchecked
+            eprintln!("error: response header {:?} can't be sent, answering 500", name);
+            resp = Response::empty();
+            resp.status = HttpStatus::ServerError;
+        }
+        self.finalize(&mut resp);
+        if self.server_timing {
+            resp.set_header("server-timing".to_string(), timings.server_timing());
+        }
+        let content_length = resp.get_header("content-length").and_then(|v| v.parse().ok());
+        // a body in memory is held until it's written; streamed ones only
+        // need small buffers
+        let held = match &resp.body {
+            Some(ResponseBody::Bytes(data)) => data.len(),
+            _ => 0,
+        };
+        let _response = self.context.memory.charge(held);
+
+        // bodies without a known length are framed with chunked encoding, or
+        // by closing the connection for HTTP/1.0 clients that don't support it
+        let unsized_body = resp.body.is_some() && resp.get_header("content-length").is_none();
+        let chunked = unsized_body && version == Version::Http11;
+        let bodiless = resp.status.code() < 200
+            || resp.status == HttpStatus::NoContent
+            || resp.status == HttpStatus::NotModified;
+        if resp.body.is_none() && !bodiless {
+            resp.set_header("content-length".to_string(), "0".to_string());
+        } else if chunked {
+            resp.set_header("transfer-encoding".to_string(), "chunked".to_string());
+        } else if unsized_body {
+            keep_alive = false;
+        }
+        if !keep_alive {
+            resp.set_header("connection".to_string(), "close".to_string());
+        } else if version == Version::Http10 {
+            resp.set_header("connection".to_string(), "keep-alive".to_string());
+        }
+
+        write_head(writer, &resp)?;
+        // the length of a chunked body, known once it's been sent
+        let chunked_bytes = match resp.body.take() {
+            // HEAD responses keep the GET headers but never carry a body
+            Some(_) if head => None,
+            Some(ResponseBody::Bytes(data)) if chunked => {
+                let mut chunks = ChunkedWriter::new(writer);
+                chunks.write_all(&data)?;
+                Some(chunks.finish()?)
+            }
+            Some(ResponseBody::File(mut data)) if chunked => {
+                let mut chunks = ChunkedWriter::new(writer);
+                io::copy(&mut data, &mut chunks)?;
+                Some(chunks.finish()?)
+            }
+            Some(ResponseBody::Reader(mut data)) if chunked => {
+                // e.g. a channel's pieces, each sent as soon as it's ready
+                let mut chunks = ChunkedWriter::streaming(writer);
+                io::copy(&mut data, &mut chunks)?;
+                Some(chunks.finish()?)
+            }
+            Some(ResponseBody::Writer(write)) if chunked => {
+                // gather the callback's small writes into chunks of a
+                // sensible size, rather than framing each one
+                let mut chunks = BufWriter::new(ChunkedWriter::streaming(writer));
+                write(&mut chunks)?;
+                Some(chunks.into_inner().map_err(|err| err.into_error())?.finish()?)
+            }
+            Some(body) => {
+                body.write_to(writer)?;
+                None
+            }
+            None => None,
+        };
+        // recorded before the end of the response goes out, so a client's
+        // next request sees it
+        let response_bytes = content_length.or(chunked_bytes);
+        if let Some(size) = response_bytes {
+            self.context.metrics.observe("http_response_size_bytes", &[], size as f64);
+        }
+        writer.flush()?;
+        timings.lap("write");
+
+        println!("{}: {} {}: {} {}", addr, method, target, resp.status, timings);
+        if let Some(slow_log) = &self.slow_log {
+            slow_log.record(&SlowRequest {
+                addr,
+                method,
+                path: &target,
+                route: route.0.get().map(String::as_str),
+                headers: &headers,
+                redaction: &self.context.redaction,
+                status: resp.status,
+                request_bytes,
+                response_bytes,
+                timings: &timings,
+            });
+        }
+        Ok(keep_alive)
     }
 }
 
+// Writes the status line and headers, refusing any header that would break
+// out of its line; see invalid_header.
+fn write_head(writer: &mut dyn Write, resp: &Response) -> io::Result<()> {
+    if let Some(name) = invalid_header(resp) {
+        let msg = format!("invalid response header {:?}", name);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+    write!(writer, "HTTP/1.1 {}\r\n", resp.status)?;
+    for (k, v) in resp.headers() {
+        write!(writer, "{}: {}\r\n", k, v)?;
+    }
+    write!(writer, "\r\n")
+}
+
+// Accepts a connection only if one is already queued, failing with
+// WouldBlock otherwise.
+fn accept_queued(listener: &TcpListener) -> io::Result<(TcpStream, SocketAddr)> {
+    listener.set_nonblocking(true)?;
+    let accepted = listener.accept();
+    listener.set_nonblocking(false)?;
+    let (stream, addr) = accepted?;
+    // some platforms hand the listener's mode down to accepted sockets
+    stream.set_nonblocking(false)?;
+    Ok((stream, addr))
+}
+
+// the name of the first header that can't be sent as is, see is_valid_header
+fn invalid_header(resp: &Response) -> Option<&str> {
+    resp.headers().find(|(k, v)| !is_valid_header(k, v)).map(|(k, _)| k.as_str())
+}
+
 pub struct Server {
     config: Config,
     addr: String,
     listener: TcpListener,
     state: Mutex<ServerState>,
     handler: Arc<ConnectionHandler>,
+    // the workers' own listeners besides |listener| in per-worker mode
+    worker_listeners: Mutex<Vec<TcpListener>>,
+    backoff: AcceptBackoff,
 }
 
 impl Drop for Server {
@@ -174,21 +814,222 @@ impl Drop for Server {
     }
 }
 
-fn default_middleware() -> Vec<Box<dyn MiddlewareFactory>> {
-    vec![Box::new(CompressionFactory)]
+// Builds a middleware from its settings, which fall back to the flags', or
+// None if they turn it off.
+type MiddlewareConstructor =
+    fn(&Config, &Settings) -> Result<Option<Box<dyn MiddlewareFactory>>, ConfigError>;
+
+// The middleware --middleware and the config file can name, and the settings
+// each takes in the config file.
+const MIDDLEWARE: &[(&str, &[&str], MiddlewareConstructor)] = &[
+    ("trace", &[], |_, _| Ok(Some(Box::new(TraceFactory)))),
+    ("decompression", &["max_bytes"], |config, settings| {
+        let max_bytes = settings.integer("max_bytes", 0..=i64::MAX)?;
+        let max_bytes = max_bytes.map_or(config.max_decompressed_body_bytes, |n| n as u64);
+        Ok(Some(Box::new(DecompressionFactory { max_bytes })))
+    }),
+    ("conditional", &[], |_, _| Ok(Some(Box::new(ConditionalFactory)))),
+    (
+        "compression",
+        &["algos", "level", "zstd_level", "min_bytes", "types", "skip_types"],
+        |config, settings| {
+            let level = settings.integer("level", 0..=9)?;
+            let zstd_level = settings.integer("zstd_level", 1..=22)?;
+            let min_bytes = settings.integer("min_bytes", 0..=i64::MAX)?;
+            Ok(Some(Box::new(CompressionFactory {
+                encodings: settings.values("algos")?.unwrap_or(config.compression_algos.clone()),
+                level: level.map_or(config.compression_level, |n| n as u32),
+                zstd_level: zstd_level.map_or(config.zstd_level, |n| n as i32),
+                min_bytes: min_bytes.map_or(config.compression_min_bytes, |n| n as u64),
+                types: Arc::new(TypePolicy {
+                    allow: settings.strings("types")?.unwrap_or(config.compress_types.clone()),
+                    deny: settings
+                        .strings("skip_types")?
+                        .unwrap_or(config.no_compress_types.clone()),
+                }),
+            })))
+        },
+    ),
+    ("range", &[], |_, _| Ok(Some(Box::new(RangeFactory)))),
+    ("chaos", &["rate", "routes", "delay_ms", "fault"], |config, settings| {
+        let rate = settings.float("rate")?.unwrap_or(config.chaos_rate);
+        if rate <= 0.0 {
+            return Ok(None);
+        }
+        let routes = settings.string("routes")?.unwrap_or(config.chaos_routes.clone());
+        let routes = Regex::new(&routes)
+            .map_err(|err| ConfigError(format!("invalid chaos routes pattern: {}", err)))?;
+        let delay = settings.integer("delay_ms", 0..=i64::MAX)?;
+        Ok(Some(Box::new(ChaosFactory::new(
+            rate,
+            routes,
+            Duration::from_millis(delay.map_or(config.chaos_delay_ms, |n| n as u64)),
+            settings.value("fault")?.unwrap_or(config.chaos_fault),
+        ))))
+    }),
+];
+
+// Installed after any application middleware so encoding and slicing see the
+// final response. The config file's chain, if it declares one, replaces
+// --middleware.
+fn default_middleware(
+    config: &Config,
+    file: &ConfigFile,
+) -> Result<Vec<Box<dyn MiddlewareFactory>>, ConfigError> {
+    let chain = match file.middleware.is_empty() {
+        true => config.middleware.iter().map(|name| Settings::new(name)).collect(),
+        false => file.middleware.clone(),
+    };
+    let mut middleware = Vec::new();
+    for settings in &chain {
+        let Some((_, known, construct)) = MIDDLEWARE.iter().find(|(n, ..)| *n == settings.name)
+        else {
+            return Err(ConfigError(format!("unknown middleware: {}", settings.name)));
+        };
+        settings.only(known)?;
+        middleware.extend(construct(config, settings)?);
+    }
+    Ok(middleware)
+}
+
+pub struct ServerBuilder {
+    config: Config,
+    router: Router,
+    middleware: Vec<Box<dyn MiddlewareFactory>>,
+    client_middleware: Vec<Box<dyn ClientMiddleware>>,
+}
+
+impl ServerBuilder {
+    pub fn router(mut self, router: Router) -> Self {
+        self.router = router;
+        self
+    }
+
+    pub fn middleware<M: MiddlewareFactory + 'static>(mut self, middleware: M) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    // Middleware for the server's outbound requests, e.g. webhook deliveries.
+    pub fn client_middleware<M: ClientMiddleware + 'static>(mut self, middleware: M) -> Self {
+        self.client_middleware.push(Box::new(middleware));
+        self
+    }
+
+    pub fn plugin<P: Plugin>(mut self, plugin: P) -> Self {
+        self.router = plugin.routes(self.router);
+        self.middleware.extend(plugin.middleware());
+        self
+    }
+
+    pub fn start(self) -> Server {
+        let urls = self.router.urls();
+        Server::start_with_middleware(
+            self.config,
+            self.router,
+            self.middleware,
+            self.client_middleware,
+            urls,
+        )
+    }
 }
 
 impl Server {
-    pub fn start<H: Into<Box<dyn Handler>>>(config: Config, handler: H) -> Self {
+    pub fn builder(config: Config) -> ServerBuilder {
+        ServerBuilder {
+            config,
+            router: Router::default(),
+            middleware: Vec::new(),
+            client_middleware: Vec::new(),
+        }
+    }
+
+    pub fn start<H: IntoHandler<M>, M>(config: Config, handler: H) -> Self {
+        Self::start_with_middleware(config, handler, Vec::new(), Vec::new(), Urls::default())
+    }
+
+    fn start_with_middleware<H: IntoHandler<M>, M>(
+        mut config: Config,
+        handler: H,
+        mut middleware: Vec<Box<dyn MiddlewareFactory>>,
+        client_middleware: Vec<Box<dyn ClientMiddleware>>,
+        urls: Urls,
+    ) -> Self {
+        let file = match &config.config_file {
+            Some(path) => ConfigFile::load(path).expect("can't load config file"),
+            None => ConfigFile::default(),
+        };
         let addr = format!("{}:{}", config.host, config.port);
-        let listener = TcpListener::bind(&addr).unwrap();
+        let listener = match config.accept_mode {
+            AcceptMode::Shared => TcpListener::bind(&addr),
+            AcceptMode::PerWorker => addr
+                .to_socket_addrs()
+                .and_then(|mut addrs| addrs.next().ok_or(io::ErrorKind::AddrNotAvailable.into()))
+                .and_then(reuseport::bind_reuseport)
+                .or_else(|err| {
+                    if err.kind() != io::ErrorKind::Unsupported {
+                        return Err(err);
+                    }
+                    eprintln!("warning: {}, accepting connections in shared mode", err);
+                    config.accept_mode = AcceptMode::Shared;
+                    TcpListener::bind(&addr)
+                }),
+        }
+        .unwrap();
         let addr = listener.local_addr().unwrap().to_string();
         let state = Mutex::new(ServerState::Stopped);
         let working_dir = config.directory.clone();
-        let context = Context { working_dir };
+        let metrics = Metrics::default();
+        metrics.set_buckets("http_request_size_bytes", config.size_buckets.clone());
+        metrics.set_buckets("http_response_size_bytes", config.size_buckets.clone());
+        metrics.set_buckets("http_request_headers", config.header_count_buckets.clone());
+        let audit = config
+            .audit_log
+            .as_deref()
+            .map(|path| AuditLog::open(path, config.audit_fsync).expect("can't open audit log"));
+        let webhook = config.webhook_url.clone().map(|url| {
+            let backoff = Duration::from_millis(config.webhook_backoff_ms);
+            let secret = config.webhook_secret.clone();
+            let (retries, queue) = (config.webhook_retries, config.webhook_queue);
+            let client = config.client().expect("can't start webhook client");
+            Webhook::start(url, secret, retries, backoff, queue, client.extend(client_middleware))
+        });
+        // the flags are given for this run, so they win over the file
+        let mime_types: Vec<_> =
+            config.mime_types.iter().chain(&file.mime_types).cloned().collect();
+        let mime_types = MimeTypes::new(&mime_types);
+        let context = Context {
+            working_dir,
+            metrics,
+            urls,
+            audit,
+            webhook,
+            mime_types,
+            memory: MemoryBudget::new(config.memory_limit_bytes),
+            redaction: Redaction::new(
+                &config.redact_headers,
+                &config.redact_query_params,
+                config.redact_hash_key.as_deref(),
+            ),
+            dir_missing: Default::default(),
+        };
+        middleware.extend(default_middleware(&config, &file).expect("can't build middleware"));
+        let mut request_handler = handler.into_handler();
+        if config.debug_routes {
+            request_handler = Box::new(DebugRoutes(request_handler));
+        }
+        if config.metrics {
+            request_handler = Box::new(MetricsRoute(request_handler));
+        }
+        if config.dev_errors {
+            eprintln!("warning: --dev-errors shows failure details to clients");
+            diagnostics::install_panic_hook();
+        }
         let handler =
-            Arc::new(ConnectionHandler::new(context, handler.into(), default_middleware()));
-        Self { config, listener, addr, state, handler }
+            Arc::new(ConnectionHandler::new(&config, context, request_handler, middleware));
+        let worker_listeners = Mutex::new(Vec::new());
+        let backoff = AcceptBackoff::new(config.accept_probes);
+        Self { config, listener, addr, state, handler, worker_listeners, backoff }
     }
 
     pub fn stop(&self) {
@@ -197,6 +1038,10 @@ impl Server {
             return;
         }
         *guard = ServerState::Stopping;
+        for listener in self.worker_listeners.lock().unwrap().drain(..) {
+            reuseport::shutdown(&listener);
+        }
+        // with the others shut down, this reaches |listener|
         let _ = TcpStream::connect(&self.addr);
     }
 
@@ -211,16 +1056,49 @@ impl Server {
             if *guard != ServerState::Stopped {
                 return Ok(());
             }
+            // bound while holding the lock so that stop() sees them
+            if self.config.accept_mode == AcceptMode::PerWorker {
+                *self.worker_listeners.lock().unwrap() = self.bind_worker_listeners()?;
+            }
             *guard = ServerState::Running;
         }
+        // a restart owes nothing to failures before the last stop
+        self.backoff.reset();
 
         // run until stopped
-        let mut pool = ThreadPool::new(self.config.workers);
-        for stream in self.listener.incoming() {
+        let options = WorkerOptions {
+            stack_size: self.config.worker_stack_size,
+            cpus: self.config.worker_cpus.clone(),
+        };
+        let result = match self.config.accept_mode {
+            AcceptMode::Shared => self.accept_shared(&options),
+            AcceptMode::PerWorker => self.accept_per_worker(&options),
+        };
+
+        // mark as stopped
+        {
+            let mut guard = self.state.lock().unwrap();
+            *guard = ServerState::Stopped;
+        }
+
+        result
+    }
+
+    fn bind_worker_listeners(&self) -> io::Result<Vec<TcpListener>> {
+        let addr = self.listener.local_addr()?;
+        (1..self.config.workers).map(|_| reuseport::bind_reuseport(addr)).collect()
+    }
+
+    fn accept_shared(&self, options: &WorkerOptions) -> io::Result<()> {
+        let mut pool = ThreadPool::new(self.config.workers, options)?;
+        loop {
+            let accepted = self.accept(&self.listener);
             if *self.state.lock().unwrap() == ServerState::Stopping {
                 break;
             }
-            let stream = stream?;
+            let Some(stream) = accepted else {
+                continue;
+            };
             stream.set_write_timeout(Some(Duration::from_millis(self.config.write_timeout_ms)))?;
             stream.set_read_timeout(Some(Duration::from_millis(self.config.read_timeout_ms)))?;
             let handler = Arc::clone(&self.handler);
@@ -230,22 +1108,81 @@ impl Server {
                 }
             }));
         }
+        Ok(())
+    }
 
-        // mark as stopped
-        {
-            let mut guard = self.state.lock().unwrap();
-            *guard = ServerState::Stopped;
+    // Runs a worker per listener, each parsing, handling and writing the
+    // connections it accepts without handing them to another thread.
+    fn accept_per_worker(&self, options: &WorkerOptions) -> io::Result<()> {
+        let mut listeners = vec![self.listener.try_clone()?];
+        for listener in self.worker_listeners.lock().unwrap().iter() {
+            listeners.push(listener.try_clone()?);
         }
+        thread::scope(|scope| {
+            let mut workers = Vec::new();
+            for (id, listener) in listeners.into_iter().enumerate() {
+                workers.push(options.spawn_scoped(scope, id, move || self.accept_loop(&listener))?);
+            }
+            workers.into_iter().try_for_each(|worker| worker.join().unwrap())
+        })
+    }
 
-        Ok(())
+    // The next connection, or None after a failure, in which case the caller
+    // should keep serving the listener's queue; see AcceptBackoff.
+    fn accept(&self, listener: &TcpListener) -> Option<TcpStream> {
+        let probe = self.backoff.wait();
+        let accepted = match probe {
+            Some(_) => accept_queued(listener),
+            None => listener.accept(),
+        };
+        drop(probe);
+        match accepted {
+            Ok((stream, _)) => {
+                self.backoff.succeeded();
+                Some(stream)
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                self.backoff.idle();
+                None
+            }
+            // stop() shutting the listeners down isn't a failure to back off from
+            Err(_) if *self.state.lock().unwrap() == ServerState::Stopping => None,
+            Err(err) => {
+                eprintln!("failed to accept connection: {}", err);
+                self.backoff.failed();
+                None
+            }
+        }
+    }
+
+    fn accept_loop(&self, listener: &TcpListener) -> io::Result<()> {
+        loop {
+            let accepted = self.accept(listener);
+            if *self.state.lock().unwrap() == ServerState::Stopping {
+                return Ok(());
+            }
+            let Some(stream) = accepted else {
+                continue;
+            };
+            stream.set_write_timeout(Some(Duration::from_millis(self.config.write_timeout_ms)))?;
+            stream.set_read_timeout(Some(Duration::from_millis(self.config.read_timeout_ms)))?;
+            if let Err(err) = self.handler.handle(stream) {
+                eprintln!("failed to handle connection: {}", err);
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::Request;
-    use std::{sync::Arc, thread};
+    use crate::{BodyPolicy, Request, StaticFiles, TempDir};
+    use std::{
+        io::{Cursor, Read},
+        sync::{mpsc, Arc},
+        thread,
+        time::Instant,
+    };
 
     // TODO: test out of order lifecycle calls
 
@@ -273,4 +1210,877 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_chunked() {
+        let body: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+        let expected = body.clone();
+        let server =
+            Arc::new(Server::start(Config::default(), move |_ctx: &Context, _req: Request<'_>| {
+                Ok(Response::chunked(Box::new(Cursor::new(body.clone()))))
+            }));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let client = reqwest::blocking::Client::builder().no_gzip().build().unwrap();
+        for _ in 0..3 {
+            let resp = client.get(format!("http://{}", server.addr())).send().unwrap();
+            assert_eq!(resp.headers()["transfer-encoding"], "chunked");
+            assert_eq!(resp.bytes().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_methods() {
+        let server =
+            Arc::new(Server::start(Config::default(), |_ctx: &Context, req: Request<'_>| {
+                Ok(Response::plain_text(req.method.to_string()))
+            }));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let client = reqwest::blocking::Client::new();
+        for method in ["GET", "POST", "PUT", "DELETE", "PATCH", "OPTIONS"] {
+            let method = reqwest::Method::from_bytes(method.as_bytes()).unwrap();
+            let url = format!("http://{}/", server.addr());
+            let resp = client.request(method.clone(), url).send().unwrap();
+            assert_eq!(resp.text().unwrap(), method.as_str());
+        }
+    }
+
+    #[test]
+    fn test_http10() {
+        let server =
+            Arc::new(Server::start(Config::default(), |_ctx: &Context, _req: Request<'_>| {
+                Ok(Response::chunked(Box::new(Cursor::new(b"hello".to_vec()))))
+            }));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream.write_all(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        assert!(resp.contains("connection: close\r\n"));
+        assert!(!resp.contains("transfer-encoding"));
+        assert!(resp.ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn test_http_date() {
+        let t = SystemTime::UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(http_date(t), "Sun, 06 Nov 1994 08:49:37 GMT");
+        let t = SystemTime::UNIX_EPOCH + Duration::from_secs(951782400);
+        assert_eq!(http_date(t), "Tue, 29 Feb 2000 00:00:00 GMT");
+    }
+
+    #[test]
+    fn test_date_and_server() {
+        let config =
+            Config { server_name: "test".to_string(), server_timing: true, ..Config::default() };
+        let server = Arc::new(Server::start(config, |_ctx: &Context, _req: Request<'_>| {
+            Ok(Response::empty())
+        }));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let resp = reqwest::blocking::get(format!("http://{}", server.addr())).unwrap();
+        assert_eq!(resp.headers()["server"], "test");
+        assert!(resp.headers()["date"].to_str().unwrap().ends_with(" GMT"));
+        let timing = resp.headers()["server-timing"].to_str().unwrap();
+        assert!(timing.starts_with("parse;dur="));
+        assert!(timing.contains(", handler;dur="));
+    }
+
+    #[test]
+    fn test_chaos() {
+        let config = Config {
+            chaos_rate: 1.0,
+            chaos_routes: "^/flaky".to_string(),
+            chaos_fault: Fault::Error,
+            ..Config::default()
+        };
+        let server = Arc::new(Server::start(config, |_ctx: &Context, _req: Request<'_>| {
+            Ok(Response::empty())
+        }));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let resp = reqwest::blocking::get(format!("http://{}/flaky", server.addr())).unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::INTERNAL_SERVER_ERROR);
+        let resp = reqwest::blocking::get(format!("http://{}/stable", server.addr())).unwrap();
+        assert!(resp.status().is_success());
+    }
+
+    #[test]
+    fn test_status() {
+        assert_eq!(HttpStatus::from(404), HttpStatus::NotFound);
+        assert_eq!(HttpStatus::from(429).to_string(), "429 Too Many Requests");
+        assert_eq!(HttpStatus::from(299), HttpStatus::Custom(299, ""));
+        assert_eq!(HttpStatus::Custom(599, "Network Timeout").to_string(), "599 Network Timeout");
+        assert_eq!(HttpStatus::Custom(200, "OK"), HttpStatus::OK);
+        assert!(matches!(HttpStatus::Custom(404, "Missing").normalized(), HttpStatus::NotFound));
+        assert_eq!(HttpStatus::from(42), HttpStatus::ServerError);
+        assert_eq!(HttpStatus::from(1000).to_string(), "500 Internal Server Error");
+        let split = HttpStatus::Custom(299, "Fine\r\nx-injected: 1").normalized();
+        assert_eq!(split.to_string(), "299 ");
+
+        let server =
+            Arc::new(Server::start(Config::default(), |_ctx: &Context, _req: Request<'_>| {
+                let mut resp = Response::empty();
+                resp.status = HttpStatus::Custom(299, "Fine");
+                Ok(resp)
+            }));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let resp = reqwest::blocking::get(format!("http://{}", server.addr())).unwrap();
+        assert_eq!(resp.status().as_u16(), 299);
+    }
+
+    struct Greeter;
+
+    struct Stamp;
+
+    impl MiddlewareFactory for Stamp {
+        fn new(&self, _req: &Request) -> Option<Box<dyn Middleware>> {
+            Some(Box::new(Stamp))
+        }
+    }
+
+    impl Middleware for Stamp {
+        fn apply_before(&self, _req: &mut Request) -> Result<(), MiddlewareError> {
+            Ok(())
+        }
+
+        fn apply_after(&self, resp: &mut Response) -> Result<(), MiddlewareError> {
+            resp.set_header("x-greeter".to_string(), "1".to_string());
+            Ok(())
+        }
+    }
+
+    impl Plugin for Greeter {
+        fn routes(&self, router: Router) -> Router {
+            router.route(Method::Get, "/hello", |_ctx: &Context, _req: Request<'_>| {
+                Ok(Response::plain_text("hello".to_string()))
+            })
+        }
+
+        fn middleware(&self) -> Vec<Box<dyn MiddlewareFactory>> {
+            vec![Box::new(Stamp)]
+        }
+    }
+
+    #[test]
+    fn test_plugin() {
+        let server = Arc::new(Server::builder(Config::default()).plugin(Greeter).start());
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let resp = reqwest::blocking::get(format!("http://{}/hello", server.addr())).unwrap();
+        assert_eq!(resp.headers()["x-greeter"], "1");
+        assert_eq!(resp.text().unwrap(), "hello");
+    }
+
+    #[test]
+    fn test_slow_log() {
+        let dir = TempDir::new("slow-log").unwrap();
+        let path = dir.join("slow.log");
+        let config =
+            Config { slow_request_ms: Some(0), slow_log: Some(path.clone()), ..Config::default() };
+        let router = Router::default().get("/slow/:id", |_ctx: &Context, _req: Request<'_>| {
+            Ok(Response::plain_text("slow".to_string()))
+        });
+        let server = Arc::new(Server::builder(config).router(router).start());
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        // read to the end, so the server isn't cut off mid-body
+        let url = format!("http://{}/slow/7?token=abc&x=1", server.addr());
+        let req = reqwest::blocking::Client::new().get(url).header("authorization", "Bearer abc");
+        req.send().unwrap().text().unwrap();
+        // the entry is written after the response is flushed
+        let mut log = String::new();
+        for _ in 0..50 {
+            log = std::fs::read_to_string(&path).unwrap_or_default();
+            if !log.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(log.contains("GET /slow/7?token=[redacted]&x=1: 200 OK"), "{}", log);
+        assert!(log.contains(" route=/slow/:id "), "{}", log);
+        assert!(log.contains(r#""authorization": "[redacted]""#), "{}", log);
+        assert!(!log.contains("Bearer"), "{}", log);
+        assert!(log.contains(" parse=") && log.contains(" handler=") && log.contains(" write="));
+    }
+
+    struct RequireToken;
+
+    // who RequireToken let through, for the handler
+    struct Caller(String);
+
+    impl MiddlewareFactory for RequireToken {
+        fn new(&self, _req: &Request) -> Option<Box<dyn Middleware>> {
+            Some(Box::new(RequireToken))
+        }
+    }
+
+    impl Middleware for RequireToken {
+        fn apply_before(&self, req: &mut Request) -> Result<(), MiddlewareError> {
+            match req.get_header("x-token") {
+                Some("secret") => {
+                    req.extensions.insert(Caller("admin".to_string()));
+                    Ok(())
+                }
+                _ => Err(MiddlewareError::reject(HttpStatus::Unauthorized)),
+            }
+        }
+
+        fn apply_after(&self, _resp: &mut Response) -> Result<(), MiddlewareError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_route_middleware() {
+        let router = Router::default()
+            .route(Method::Get, "/private", |_ctx: &Context, req: Request<'_>| {
+                let caller = req.extensions.get::<Caller>().ok_or(HttpStatus::ServerError)?;
+                Ok(Response::plain_text(caller.0.clone()))
+            })
+            .with(RequireToken)
+            .route(Method::Get, "/public", |_ctx: &Context, _req: Request<'_>| {
+                Ok(Response::empty())
+            });
+        let server = Arc::new(Server::start(Config::default(), router));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let client = reqwest::blocking::Client::new();
+        let url = |path: &str| format!("http://{}{}", server.addr(), path);
+        let resp = client.get(url("/private")).send().unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+        let resp = client.get(url("/private")).header("x-token", "secret").send().unwrap();
+        assert_eq!(resp.text().unwrap(), "admin");
+        let resp = client.get(url("/public")).send().unwrap();
+        assert!(resp.status().is_success());
+    }
+
+    #[test]
+    fn test_body_policy() {
+        let router = Router::default()
+            .route(Method::Post, "/buffered", |_ctx: &Context, req: Request<'_>| {
+                let data = req.buffered().ok_or(HttpStatus::ServerError)?;
+                Ok(Response::bytes(data.clone()))
+            })
+            .body(BodyPolicy::Buffer)
+            .route(Method::Post, "/streamed", |_ctx: &Context, req: Request<'_>| {
+                assert!(req.buffered().is_none());
+                Ok(Response::empty())
+            });
+        let config = Config { max_body_bytes: 8, ..Config::default() };
+        let server = Arc::new(Server::start(config, router));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let client = reqwest::blocking::Client::new();
+        let url = |path: &str| format!("http://{}{}", server.addr(), path);
+        let resp = client.post(url("/buffered")).body("hello").send().unwrap();
+        assert_eq!(resp.text().unwrap(), "hello");
+        let resp = client.post(url("/buffered")).body("too long a body").send().unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+        let resp = client.post(url("/streamed")).body("too long a body").send().unwrap();
+        assert!(resp.status().is_success());
+    }
+
+    #[test]
+    fn test_fallback() {
+        let router = Router::default()
+            .route(Method::Post, "/only-post", |_ctx: &Context, _req: Request<'_>| {
+                Ok(Response::empty())
+            })
+            .fallback(|_ctx: &Context, _req: Request<'_>| {
+                let mut resp = Response::plain_text("custom not found".to_string());
+                resp.status = HttpStatus::NotFound;
+                Ok(resp)
+            });
+        let server = Arc::new(Server::start(Config::default(), router));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let resp = reqwest::blocking::get(format!("http://{}/missing", server.addr())).unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+        assert_eq!(resp.text().unwrap(), "custom not found");
+        let resp = reqwest::blocking::get(format!("http://{}/only-post", server.addr())).unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn test_http10_keep_alive() {
+        let server =
+            Arc::new(Server::start(Config::default(), |_ctx: &Context, _req: Request<'_>| {
+                Ok(Response::plain_text("hi".to_string()))
+            }));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        // without keep-alive the server closes after one response
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        assert!(resp.contains("connection: close\r\n"));
+
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream.write_all(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").unwrap();
+        let mut buf = [0; 1024];
+        let n = stream.read(&mut buf).unwrap();
+        let resp = String::from_utf8_lossy(&buf[..n]);
+        assert!(resp.contains("connection: keep-alive\r\n"));
+    }
+
+    #[test]
+    fn test_handler_closes_connection() {
+        let server =
+            Arc::new(Server::start(Config::default(), |_ctx: &Context, _req: Request<'_>| {
+                let mut resp = Response::plain_text("bye".to_string());
+                resp.set_header("Connection".to_string(), "close".to_string());
+                Ok(resp)
+            }));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        // the server closes after the response, rather than waiting for more
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        assert!(resp.ends_with("bye"), "{}", resp);
+        assert_eq!(resp.matches("connection: close\r\n").count(), 1, "{}", resp);
+    }
+
+    #[test]
+    fn test_unread_body() {
+        let server =
+            Arc::new(Server::start(Config::default(), |_ctx: &Context, _req: Request<'_>| {
+                Ok(Response::plain_text("ignored".to_string()))
+            }));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        // a small body is drained and the connection kept
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream.write_all(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello").unwrap();
+        let mut buf = [0; 1024];
+        let n = stream.read(&mut buf).unwrap();
+        assert!(!String::from_utf8_lossy(&buf[..n]).contains("connection: close"));
+
+        // a large one isn't read to its end, so the connection is closed
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        thread::spawn(move || {
+            let _ = writer.write_all(b"POST / HTTP/1.1\r\nContent-Length: 100000000\r\n\r\n");
+            let _ = writer.write_all(&vec![b'x'; 1 << 20]);
+        });
+        let mut resp = Vec::new();
+        let _ = stream.read_to_end(&mut resp);
+        assert!(String::from_utf8_lossy(&resp).contains("connection: close\r\n"));
+    }
+
+    #[test]
+    fn test_worker_options() {
+        let config = Config { worker_stack_size: Some(4 << 20), ..Config::default() };
+        let server = Arc::new(Server::start(config, |_ctx: &Context, _req: Request<'_>| {
+            let name = thread::current().name().unwrap_or_default().to_string();
+            Ok(Response::plain_text(name))
+        }));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let resp = reqwest::blocking::get(format!("http://{}/", server.addr())).unwrap();
+        assert!(resp.text().unwrap().starts_with("worker-"));
+    }
+
+    // the calling thread's stack size and the cores it may run on
+    #[cfg(target_os = "linux")]
+    fn thread_placement() -> (usize, Vec<usize>) {
+        // SAFETY: attr is initialized by pthread_getattr_np before use and
+        // destroyed after, and the cpu set is plain data
+        unsafe {
+            let mut attr: libc::pthread_attr_t = std::mem::zeroed();
+            assert_eq!(libc::pthread_getattr_np(libc::pthread_self(), &mut attr), 0);
+            let mut stack_size = 0;
+            libc::pthread_attr_getstacksize(&attr, &mut stack_size);
+            libc::pthread_attr_destroy(&mut attr);
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            assert_eq!(libc::sched_getaffinity(0, std::mem::size_of_val(&set), &mut set), 0);
+            let cpus = (0..libc::CPU_SETSIZE as usize).filter(|&i| libc::CPU_ISSET(i, &set));
+            (stack_size, cpus.collect())
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_worker_placement() {
+        // a core this process may use, which needn't be 0
+        let cpu = *thread_placement().1.last().unwrap();
+        for accept_mode in [AcceptMode::Shared, AcceptMode::PerWorker] {
+            let config = Config {
+                worker_stack_size: Some(4 << 20),
+                worker_cpus: vec![cpu],
+                accept_mode,
+                ..Config::default()
+            };
+            let server = Arc::new(Server::start(config, |_ctx: &Context, _req: Request<'_>| {
+                let (stack_size, cpus) = thread_placement();
+                Ok(Response::plain_text(format!("{} {:?}", stack_size, cpus)))
+            }));
+            let server2 = Arc::clone(&server);
+            thread::spawn(move || server2.listen_forever());
+
+            let resp = reqwest::blocking::get(format!("http://{}/", server.addr())).unwrap();
+            let text = resp.text().unwrap();
+            let (stack_size, cpus) = text.split_once(' ').unwrap();
+            // rounded up to whole pages
+            let stack_size: usize = stack_size.parse().unwrap();
+            assert!((4 << 20..5 << 20).contains(&stack_size), "{}", stack_size);
+            assert_eq!(cpus, format!("[{}]", cpu));
+            server.stop();
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_per_worker_accept() {
+        let config = Config { accept_mode: AcceptMode::PerWorker, ..Config::default() };
+        let server = Arc::new(Server::start(config, |_ctx: &Context, _req: Request<'_>| {
+            let name = thread::current().name().unwrap_or_default().to_string();
+            Ok(Response::plain_text(name))
+        }));
+        let addr = format!("http://{}", server.addr());
+        for _ in 0..3 {
+            let server2 = Arc::clone(&server);
+            let handle = thread::spawn(move || server2.listen_forever());
+            for _ in 0..20 {
+                // a new connection each time, so they spread across the listeners
+                let client = reqwest::blocking::Client::new();
+                let resp = client.get(&addr).send().unwrap();
+                assert!(resp.text().unwrap().starts_with("worker-"));
+            }
+            server.stop();
+            handle.join().unwrap().expect("server failed");
+        }
+    }
+
+    #[test]
+    fn test_streamed_response() {
+        let (go_on, wait) = mpsc::channel::<()>();
+        let wait = Mutex::new(Some(wait));
+        let server =
+            Arc::new(Server::start(Config::default(), move |_ctx: &Context, _req: Request<'_>| {
+                let wait = wait.lock().unwrap().take().ok_or(HttpStatus::ServerError)?;
+                let (sender, receiver) = mpsc::channel();
+                thread::spawn(move || {
+                    sender.send(b"first".to_vec()).unwrap();
+                    // the second piece waits until the client has seen the first
+                    wait.recv().unwrap();
+                    sender.send(b"second".to_vec()).unwrap();
+                });
+                Ok(Response::from_channel(receiver))
+            }));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut seen = Vec::new();
+        let mut buf = [0; 1024];
+        while !String::from_utf8_lossy(&seen).contains("5\r\nfirst\r\n") {
+            let n = stream.read(&mut buf).expect("first piece wasn't sent on its own");
+            assert!(n > 0);
+            seen.extend_from_slice(&buf[..n]);
+        }
+        go_on.send(()).unwrap();
+        stream.read_to_end(&mut seen).unwrap();
+        assert!(String::from_utf8_lossy(&seen).ends_with("6\r\nsecond\r\n0\r\n\r\n"));
+
+        let pieces = vec![Ok(b"a".to_vec()), Ok(Vec::new()), Ok(b"bc".to_vec())];
+        assert_eq!(Response::stream(pieces).body.unwrap().into_bytes().unwrap(), b"abc");
+    }
+
+    #[test]
+    fn test_all_headers_written() {
+        let server =
+            Arc::new(Server::start(Config::default(), |_ctx: &Context, _req: Request<'_>| {
+                let mut resp = Response::plain_text("hello".to_string());
+                resp.set_header("x-custom".to_string(), "1".to_string());
+                Ok(resp)
+            }));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        let req = "GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n";
+        stream.write_all(req.as_bytes()).unwrap();
+        let mut resp = Vec::new();
+        stream.read_to_end(&mut resp).unwrap();
+        let resp = String::from_utf8_lossy(&resp);
+        let (head, _) = resp.split_once("\r\n\r\n").unwrap();
+        for header in ["content-type: text/plain", "x-custom: 1", "content-encoding: gzip"] {
+            assert!(head.lines().any(|line| line == header), "{} missing from {}", header, head);
+        }
+    }
+
+    #[test]
+    fn test_accept_queued() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let err = accept_queued(&listener).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        // the connection may take a moment to reach the queue
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let (stream, _) = loop {
+            match accept_queued(&listener) {
+                Err(err)
+                    if err.kind() == io::ErrorKind::WouldBlock && Instant::now() < deadline =>
+                {
+                    thread::sleep(Duration::from_millis(1))
+                }
+                accepted => break accepted.unwrap(),
+            }
+        };
+        // the accepted socket blocks, waiting out its timeout for data
+        stream.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+        let start = Instant::now();
+        assert!((&stream).read(&mut [0]).is_err());
+        assert!(start.elapsed() >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_header_injection() {
+        let server =
+            Arc::new(Server::start(Config::default(), |_ctx: &Context, req: Request<'_>| {
+                let mut resp = Response::plain_text("hello".to_string());
+                let value = req.path.trim_start_matches('/').replace("%0D%0A", "\r\n");
+                resp.set_header("x-echo".to_string(), value);
+                Ok(resp)
+            }));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let addr = format!("http://{}", server.addr());
+        let resp = reqwest::blocking::get(format!("{}/ok", addr)).unwrap();
+        assert_eq!(resp.headers()["x-echo"], "ok");
+        let resp = reqwest::blocking::get(format!("{}/a%0D%0Aset-cookie:%20x=1", addr)).unwrap();
+        assert_eq!(resp.status(), 500);
+        assert!(resp.headers().get("set-cookie").is_none());
+        assert!(resp.headers().get("x-echo").is_none());
+
+        let mut resp = Response::plain_text("hello".to_string());
+        resp.set_header("bad name".to_string(), "1".to_string());
+        let err = write_head(&mut Vec::new(), &resp).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_writer_response() {
+        let server =
+            Arc::new(Server::start(Config::default(), |_ctx: &Context, _req: Request<'_>| {
+                Ok(Response::from_writer(|w| {
+                    for i in 0..1000 {
+                        write!(w, "{},", i)?;
+                    }
+                    Ok(())
+                }))
+            }));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap();
+        let mut resp = Vec::new();
+        stream.read_to_end(&mut resp).unwrap();
+        let resp = String::from_utf8(resp).unwrap();
+        assert!(resp.contains("transfer-encoding: chunked\r\n"));
+        // the thousand small writes go out in a few large chunks
+        let (_, body) = resp.split_once("\r\n\r\n").unwrap();
+        assert!(body.matches("\r\n").count() < 10, "{}", body);
+        let body = reqwest::blocking::get(format!("http://{}/", server.addr())).unwrap();
+        let expected: String = (0..1000).map(|i| format!("{},", i)).collect();
+        assert_eq!(body.text().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_directory_listing() {
+        let dir = TempDir::new("listing").unwrap();
+        std::fs::create_dir_all(dir.join("docs/site")).unwrap();
+        std::fs::write(dir.join("docs/<b> & c.txt"), "abc").unwrap();
+        std::fs::write(dir.join("docs/site/index.html"), "<p>home</p>").unwrap();
+        let router = Router::default()
+            .get("/plain/*path", StaticFiles::new(dir.path()))
+            .get("/listed/*path", StaticFiles::new(dir.path()).directory_listing(true))
+            .mount(
+                "/mounted",
+                Router::default()
+                    .get("/*path", StaticFiles::new(dir.path()).directory_listing(true)),
+            );
+        let config = Config { port: 0, ..Config::default() };
+        let server = Arc::new(Server::builder(config).router(router).start());
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let client = reqwest::blocking::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let get = |path: &str| client.get(format!("http://{}{}", server.addr(), path)).send();
+        assert_eq!(get("/plain/docs/").unwrap().status(), reqwest::StatusCode::FORBIDDEN);
+        assert_eq!(get("/plain/docs/site/").unwrap().text().unwrap(), "<p>home</p>");
+        let resp = get("/listed/docs").unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(resp.headers()["location"], "/listed/docs/");
+
+        let resp = get("/listed/docs/").unwrap();
+        assert_eq!(resp.headers()["content-type"], "text/html; charset=utf-8");
+        let page = resp.text().unwrap();
+        assert!(page.contains("<title>Index of /listed/docs/</title>"), "{}", page);
+        assert!(page.contains(r#"<a href="../">../</a>"#));
+        assert!(page.contains(
+            r#"<a href="%3Cb%3E%20%26%20c.txt">&lt;b&gt; &amp; c.txt</a></td><td>3</td>"#
+        ));
+        assert!(page.contains(r#"<a href="site/">site/</a></td><td>-</td>"#));
+
+        // links and redirects keep the mount's prefix, which req.path lacks
+        let resp = get("/mounted/docs?sort=name").unwrap();
+        assert_eq!(resp.headers()["location"], "/mounted/docs/?sort=name");
+        let page = get("/mounted/docs/").unwrap().text().unwrap();
+        assert!(page.contains("<title>Index of /mounted/docs/</title>"), "{}", page);
+    }
+
+    #[test]
+    fn test_handler_panics() {
+        for dev_errors in [false, true] {
+            let config = Config { dev_errors, ..Config::default() };
+            let server = Arc::new(Server::start(config, |_ctx: &Context, req: Request<'_>| {
+                if req.path == "/panic" {
+                    panic!("handler bug");
+                }
+                Err(HttpError(HttpStatus::ServerError))
+            }));
+            let server2 = Arc::clone(&server);
+            thread::spawn(move || server2.listen_forever());
+
+            // the connection survives, and so does its worker
+            let client = reqwest::blocking::Client::new();
+            let url = |path: &str| format!("http://{}{}", server.addr(), path);
+            for _ in 0..2 {
+                let resp = client.get(url("/panic")).send().unwrap();
+                assert_eq!(resp.status(), reqwest::StatusCode::INTERNAL_SERVER_ERROR);
+                let body = resp.text().unwrap();
+                assert_eq!(body.contains("handler panicked: handler bug"), dev_errors, "{}", body);
+            }
+            let resp =
+                client.get(url("/fail")).header("accept", "application/json").send().unwrap();
+            let body = resp.text().unwrap();
+            match dev_errors {
+                true => {
+                    assert!(body.starts_with(r#"{"status":500,"method":"GET","target":"/fail""#))
+                }
+                false => assert_eq!(body, ""),
+            }
+        }
+    }
+
+    #[test]
+    fn test_debug_routes() {
+        let config = Config { debug_routes: true, ..Config::default() };
+        let server = Arc::new(Server::start(config, |_ctx: &Context, _req: Request<'_>| {
+            Ok(Response::plain_text("app".to_string()))
+        }));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let client = reqwest::blocking::Client::builder().no_gzip().build().unwrap();
+        let url = |path: &str| format!("http://{}{}", server.addr(), path);
+        let resp = client.put(url("/__echo?x=1")).header("X-Thing", "a\"b").body("hi").send();
+        let json = resp.unwrap().text().unwrap();
+        assert!(json.starts_with(r#"{"method":"PUT","target":"/__echo?x=1","version":"HTTP/1.1""#));
+        assert!(json.contains(r#"["x-thing","a\"b"]"#));
+        assert!(json.ends_with(r#""body":"hi","truncated":false}"#));
+
+        let resp = client.post(url("/__echo")).header("accept", "text/plain").body(vec![0xff]);
+        let text = resp.send().unwrap().bytes().unwrap();
+        assert!(text.starts_with(b"POST /__echo HTTP/1.1\n") && text.ends_with(b"\n\n\xff"));
+        let resp = client.get(url("/__echo")).header("accept", "image/png").send().unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_ACCEPTABLE);
+        assert_eq!(client.get(url("/other")).send().unwrap().text().unwrap(), "app");
+
+        let start = Instant::now();
+        assert_eq!(
+            client.get(url("/__delay/50")).send().unwrap().text().unwrap(),
+            "delayed 50ms\n"
+        );
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        let body = client.get(url("/__bytes/20000?seed=7")).send().unwrap().bytes().unwrap();
+        assert_eq!(body.len(), 20000);
+        let resp = client.get(url("/__bytes/lots")).send().unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_middleware_config() {
+        let config = Config::try_parse_from(["server", "--middleware", "range,bogus"]);
+        assert!(config.is_err());
+        let config = Config::parse_from(["server", "--middleware", "range", "--port", "0"]);
+        let server = Arc::new(Server::start(config, |_ctx: &Context, _req: Request<'_>| {
+            Ok(Response::plain_text("abcdef".to_string()))
+        }));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let client = reqwest::blocking::Client::builder().no_gzip().build().unwrap();
+        let url = format!("http://{}/", server.addr());
+        let resp = client.get(&url).header("accept-encoding", "gzip").header("range", "bytes=1-2");
+        let resp = resp.send().unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::PARTIAL_CONTENT);
+        assert!(resp.headers().get("content-encoding").is_none());
+        assert_eq!(resp.text().unwrap(), "bc");
+
+        // the config file's chain replaces the flag's, with its settings
+        let dir = TempDir::new("middleware-config").unwrap();
+        let path = dir.join("server.toml");
+        std::fs::write(&path, "[middleware.compression]\nalgos = [\"deflate\"]\nlevel = 9\n")
+            .unwrap();
+        let args = ["server", "--config", path.to_str().unwrap(), "--port", "0"];
+        let server = Arc::new(Server::start(
+            Config::parse_from(args),
+            |_ctx: &Context, _req: Request<'_>| Ok(Response::plain_text("abcdef".to_string())),
+        ));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+        let url = format!("http://{}/", server.addr());
+        let req = client
+            .get(&url)
+            .header("accept-encoding", "gzip, deflate")
+            .header("range", "bytes=1-2");
+        let resp = req.send().unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::OK);
+        assert_eq!(resp.headers()["content-encoding"], "deflate");
+
+        let file = |text: &str| ConfigFile::parse(text).unwrap();
+        let chain =
+            |file: &ConfigFile| default_middleware(&Config::default(), file).map(|m| m.len());
+        assert_eq!(chain(&file("[middleware.trace]\n[middleware.chaos]\nrate = 0.5")), Ok(2));
+        // chaos is off at the default rate
+        assert_eq!(chain(&file("[middleware.chaos]")), Ok(0));
+        for bad in [
+            "[middleware.rate_limit]\nrps = 50",
+            "[middleware.compression]\nlevle = 6",
+            "[middleware.compression]\nalgos = [\"brotli\"]",
+            "[middleware.chaos]\nrate = 1\nroutes = \"(\"",
+        ] {
+            assert!(chain(&file(bad)).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_compression_flags() {
+        for bad in [["--compression-level", "10"], ["--compression-algos", "brotli"]] {
+            assert!(Config::try_parse_from(["server"].into_iter().chain(bad)).is_err());
+        }
+        assert_eq!(Config::parse_from(["server"]).no_compress_types, INCOMPRESSIBLE_TYPES);
+        assert_eq!(Config::default().no_compress_types, INCOMPRESSIBLE_TYPES);
+        let body = "abcdefgh".repeat(512);
+        let mut sizes = Vec::new();
+        for level in ["0", "9"] {
+            let args = ["server", "--compression-level", level, "--compression-algos", "gzip"];
+            let config = Config { port: 0, ..Config::parse_from(args) };
+            let body = body.clone();
+            let server =
+                Arc::new(Server::start(config, move |_ctx: &Context, _req: Request<'_>| {
+                    Ok(Response::plain_text(body.clone()))
+                }));
+            let server2 = Arc::clone(&server);
+            thread::spawn(move || server2.listen_forever());
+
+            let client = reqwest::blocking::Client::builder().no_gzip().build().unwrap();
+            let req = client.get(format!("http://{}/", server.addr()));
+            let resp = req.header("accept-encoding", "zstd, gzip").send().unwrap();
+            assert_eq!(resp.headers()["content-encoding"], "gzip");
+            sizes.push(resp.bytes().unwrap().len());
+        }
+        // level 0 only frames the body, 9 squeezes the repetition out
+        assert!(sizes[0] > body.len(), "{:?}", sizes);
+        assert!(sizes[1] < body.len() / 10, "{:?}", sizes);
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let config =
+            Config { memory_limit_bytes: Some(64 * 1024), debug_routes: true, ..Config::default() };
+        let server = Arc::new(Server::start(config, |_ctx: &Context, mut req: Request<'_>| {
+            Ok(Response::plain_text(req.bytes()?.len().to_string()))
+        }));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        let client = reqwest::blocking::Client::new();
+        let url = |path: &str| format!("http://{}{}", server.addr(), path);
+        let resp = client.post(url("/")).body(vec![0; 1000]).send().unwrap();
+        assert_eq!(resp.text().unwrap(), "1000");
+
+        // shed as soon as the headers arrive, without waiting for the body
+        let shed = |headers: &str| {
+            let mut stream = TcpStream::connect(server.addr()).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            write!(stream, "POST / HTTP/1.1\r\n{}\r\n\r\n", headers).unwrap();
+            let mut resp = String::new();
+            stream.read_to_string(&mut resp).unwrap();
+            resp
+        };
+        let resp = shed("Content-Length: 102400");
+        assert!(resp.starts_with("HTTP/1.1 503 "), "{}", resp);
+        // a chunked body could be as large as any allowed
+        let resp = shed("Transfer-Encoding: chunked");
+        assert!(resp.starts_with("HTTP/1.1 503 "), "{}", resp);
+
+        let status = client.get(url("/__status")).send().unwrap().text().unwrap();
+        // the shed connection may not have released its buffers yet
+        assert!(status.starts_with(r#"{"memory_used_bytes":"#), "{}", status);
+        assert!(status.ends_with(r#","memory_limit_bytes":65536}"#), "{}", status);
+        let context = &server.handler.context;
+        assert_eq!(context.metrics.counter("requests_shed_total", &[]), 2);
+        assert!(context.render_metrics().contains("memory_used_bytes{} "));
+    }
+
+    #[test]
+    fn test_request_size() {
+        let server =
+            Arc::new(Server::start(Config::default(), |_ctx: &Context, _req: Request<'_>| {
+                Ok(Response::empty())
+            }));
+        let server2 = Arc::clone(&server);
+        thread::spawn(move || server2.listen_forever());
+
+        // a chunked body's size is what was read of it, here by the drain
+        let mut stream = TcpStream::connect(server.addr()).unwrap();
+        let (a, b) = ("a".repeat(100), "b".repeat(200));
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+             64\r\n{}\r\nc8\r\n{}\r\n0\r\n\r\n",
+            a, b
+        )
+        .unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        assert!(resp.starts_with("HTTP/1.1 200 "), "{}", resp);
+        let metrics = server.handler.context.render_metrics();
+        assert!(metrics.contains("http_request_size_bytes_sum{} 300\n"), "{}", metrics);
+        assert!(metrics.contains(r#"http_request_size_bytes_bucket{le="256"} 0"#), "{}", metrics);
+        assert!(metrics.contains(r#"http_request_size_bytes_bucket{le="1024"} 1"#), "{}", metrics);
+    }
 }
@@ -0,0 +1,88 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+const DEFAULT_SHARDS: usize = 16;
+
+// A T split across several mutexes, so threads working on different keys
+// (clients, sessions, metric names) rarely wait on the same lock. Each key
+// always maps to the same shard; whole-collection reads visit them in turn.
+pub struct Shard<T> {
+    shards: Vec<Mutex<T>>,
+}
+
+impl<T: Default> Shard<T> {
+    pub fn new(n: usize) -> Self {
+        assert!(n > 0);
+        Self { shards: (0..n).map(|_| Mutex::default()).collect() }
+    }
+}
+
+impl<T: Default> Default for Shard<T> {
+    fn default() -> Self {
+        Self::new(DEFAULT_SHARDS)
+    }
+}
+
+impl<T> Shard<T> {
+    // Locks the shard holding |key|.
+    pub fn get<K: Hash + ?Sized>(&self, key: &K) -> MutexGuard<'_, T> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let i = hasher.finish() as usize % self.shards.len();
+        // a handler that panicked holding the lock doesn't lock everyone
+        // else out too, see diagnostics::catch
+        self.shards[i].lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Locks each shard in turn, e.g. to sum or render them.
+    pub fn shards(&self) -> impl Iterator<Item = MutexGuard<'_, T>> {
+        self.shards.iter().map(|shard| shard.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::Arc, thread};
+
+    use super::*;
+
+    #[test]
+    fn test_shard() {
+        let counts: Arc<Shard<HashMap<String, u32>>> = Arc::new(Shard::new(4));
+        let threads: Vec<_> = (0..8)
+            .map(|t| {
+                let counts = Arc::clone(&counts);
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        let key = format!("client-{}", (t + i) % 10);
+                        *counts.get(&key).entry(key.clone()).or_default() += 1;
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let total: u32 = counts.shards().map(|s| s.values().sum::<u32>()).sum();
+        assert_eq!(total, 800);
+        // every key lives in exactly one shard
+        let keys: usize = counts.shards().map(|s| s.len()).sum();
+        assert_eq!(keys, 10);
+        assert_eq!(counts.get("client-3")["client-3"], 80);
+
+        // a panic while holding a shard doesn't make it unusable
+        let _ = thread::spawn({
+            let counts = Arc::clone(&counts);
+            move || {
+                let _shard = counts.get("client-3");
+                panic!("poisoned");
+            }
+        })
+        .join();
+        assert_eq!(counts.get("client-3")["client-3"], 80);
+        assert_eq!(counts.shards().count(), 4);
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    fmt::Display,
+    fs::OpenOptions,
+    io::{self, Write},
+    path::Path,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{http_date, HttpStatus, Method, Redaction};
+
+// Durations of the consecutive phases of serving a request.
+pub struct Timings {
+    start: Instant,
+    last: Instant,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Timings {
+    pub fn start() -> Self {
+        let now = Instant::now();
+        Self { start: now, last: now, phases: Vec::new() }
+    }
+
+    // ends the current phase, attributing the time since the previous lap to
+    // |name|; repeated phases accumulate
+    pub fn lap(&mut self, name: &'static str) {
+        let now = Instant::now();
+        match self.phases.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, d)) => *d += now - self.last,
+            None => self.phases.push((name, now - self.last)),
+        }
+        self.last = now;
+    }
+
+    pub fn phases(&self) -> &[(&'static str, Duration)] {
+        &self.phases
+    }
+
+    pub fn total(&self) -> Duration {
+        self.last - self.start
+    }
+
+    // Server-Timing header value for the phases so far
+    pub fn server_timing(&self) -> String {
+        let phases = self
+            .phases
+            .iter()
+            .map(|(name, d)| format!("{};dur={:.3}", name, d.as_secs_f64() * 1000.0))
+            .collect::<Vec<_>>();
+        phases.join(", ")
+    }
+}
+
+impl Display for Timings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "total={:.3}ms", self.total().as_secs_f64() * 1000.0)?;
+        for (name, d) in &self.phases {
+            write!(f, " {}={:.3}ms", name, d.as_secs_f64() * 1000.0)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct SlowRequest<'t> {
+    pub addr: &'t str,
+    pub method: Method,
+    pub path: &'t str,
+    // the pattern of the route that served it, if a Router did
+    pub route: Option<&'t str>,
+    // the request's headers as received, logged through |redaction|
+    pub headers: &'t [(String, String)],
+    pub redaction: &'t Redaction,
+    pub status: HttpStatus,
+    pub request_bytes: u64,
+    pub response_bytes: Option<u64>,
+    pub timings: &'t Timings,
+}
+
+// Records requests slower than a threshold, separately from the access log.
+pub struct SlowLog {
+    threshold: Duration,
+    sink: Mutex<Box<dyn Write + Send>>,
+}
+
+impl SlowLog {
+    pub fn new(threshold: Duration, sink: Box<dyn Write + Send>) -> Self {
+        Self { threshold, sink: Mutex::new(sink) }
+    }
+
+    // appends to |path|, or writes to stderr if there isn't one
+    pub fn open(threshold: Duration, path: Option<&Path>) -> io::Result<Self> {
+        let sink: Box<dyn Write + Send> = match path {
+            Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+            None => Box::new(io::stderr()),
+        };
+        Ok(Self::new(threshold, sink))
+    }
+
+    pub fn record(&self, req: &SlowRequest) {
+        if req.timings.total() < self.threshold {
+            return;
+        }
+        let mut line = format!(
+            "[{}] slow request: {} {} {}: {} {}",
+            http_date(SystemTime::now()),
+            req.addr,
+            req.method,
+            req.path,
+            req.status,
+            req.timings
+        );
+        line.push_str(&format!(" route={}", req.route.unwrap_or("-")));
+        line.push_str(&format!(" request_bytes={}", req.request_bytes));
+        match req.response_bytes {
+            Some(n) => line.push_str(&format!(" response_bytes={}", n)),
+            None => line.push_str(" response_bytes=chunked"),
+        }
+        // quoted, as values may hold spaces and commas
+        let headers: Vec<_> = req
+            .headers
+            .iter()
+            .map(|(k, v)| format!("{:?}: {:?}", k, req.redaction.header(k, v)))
+            .collect();
+        line.push_str(&format!(" headers={{{}}}", headers.join(", ")));
+        let mut sink = self.sink.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(err) = writeln!(sink, "{}", line) {
+            eprintln!("failed to write slow log: {}", err);
+        }
+    }
+}
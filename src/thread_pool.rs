@@ -1,17 +1,104 @@
 use std::{
+    io,
     sync::{mpsc, Arc, Mutex},
-    thread::{self, JoinHandle},
+    thread::{self, JoinHandle, Scope, ScopedJoinHandle},
 };
 
 type Task = Box<dyn FnOnce() + Send + 'static>;
 
+// How worker threads are spawned, see the corresponding Config fields.
+#[derive(Debug, Clone, Default)]
+pub struct WorkerOptions {
+    pub stack_size: Option<usize>,
+    // cores to pin workers to round-robin, empty to leave them unpinned
+    pub cpus: Vec<usize>,
+}
+
+impl WorkerOptions {
+    // Spawns worker |id|, which pins itself to its core before running |f|.
+    pub fn spawn<F, T>(&self, id: usize, f: F) -> io::Result<JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let pin = self.pin(id);
+        self.builder(id).spawn(move || {
+            pin();
+            f()
+        })
+    }
+
+    // Like spawn, for a worker borrowing from its |scope|.
+    pub fn spawn_scoped<'scope, F, T>(
+        &self,
+        scope: &'scope Scope<'scope, '_>,
+        id: usize,
+        f: F,
+    ) -> io::Result<ScopedJoinHandle<'scope, T>>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        let pin = self.pin(id);
+        self.builder(id).spawn_scoped(scope, move || {
+            pin();
+            f()
+        })
+    }
+
+    fn builder(&self, id: usize) -> thread::Builder {
+        let builder = thread::Builder::new().name(format!("worker-{}", id));
+        match self.stack_size {
+            Some(size) => builder.stack_size(size),
+            None => builder,
+        }
+    }
+
+    // what worker |id| runs first to pin itself, if it's given cores
+    fn pin(&self, id: usize) -> impl FnOnce() + Send {
+        let cpu = (!self.cpus.is_empty()).then(|| self.cpus[id % self.cpus.len()]);
+        move || {
+            let Some(cpu) = cpu else { return };
+            // a worker that can't be pinned still serves requests
+            if let Err(err) = pin_to_cpu(cpu) {
+                eprintln!("worker {} failed to pin to cpu {}: {}", id, cpu, err);
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn pin_to_cpu(cpu: usize) -> io::Result<()> {
+    // SAFETY: cpu_set_t is plain data, and CPU_SET checks |cpu| against its size
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if cpu >= libc::CPU_SETSIZE as usize {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_to_cpu(_cpu: usize) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
 struct Worker {
     handle: Option<JoinHandle<()>>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Task>>>) -> Self {
-        let handle = thread::spawn(move || {
+    fn new(
+        id: usize,
+        receiver: Arc<Mutex<mpsc::Receiver<Task>>>,
+        options: &WorkerOptions,
+    ) -> io::Result<Self> {
+        let handle = options.spawn(id, move || {
             println!("worker {} starting", id);
             loop {
                 let task = receiver.lock().unwrap().recv();
@@ -24,8 +111,8 @@ impl Worker {
                 }
             }
             println!("worker {} stopping", id);
-        });
-        Worker { handle: Some(handle) }
+        })?;
+        Ok(Worker { handle: Some(handle) })
     }
 }
 
@@ -46,15 +133,15 @@ impl Drop for ThreadPool {
 }
 
 impl ThreadPool {
-    pub fn new(size: usize) -> Self {
+    pub fn new(size: usize, options: &WorkerOptions) -> io::Result<Self> {
         assert!(size > 0);
         let mut workers = Vec::with_capacity(size);
         let (sender, receiver) = mpsc::channel();
         let receiver = Arc::new(Mutex::new(receiver));
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            workers.push(Worker::new(id, Arc::clone(&receiver), options)?);
         }
-        Self { workers, sender: Some(sender) }
+        Ok(Self { workers, sender: Some(sender) })
     }
 
     pub fn execute(&mut self, task: Task) {
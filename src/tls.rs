@@ -0,0 +1,70 @@
+use std::{fs, io, path::PathBuf};
+
+use reqwest::{blocking::ClientBuilder, Certificate, Identity};
+
+// How outbound HTTPS requests, such as webhook deliveries, verify the
+// servers they reach and identify themselves to them.
+#[derive(Debug, Clone, Default)]
+pub struct ClientTls {
+    // PEM certificates to trust besides the system's roots
+    pub ca_bundle: Option<PathBuf>,
+    // accept any certificate, which defeats TLS; only for test environments
+    pub insecure_skip_verify: bool,
+    // PEM certificate chain and PKCS#8 key presented for mutual TLS
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+}
+
+fn invalid(err: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err.to_string())
+}
+
+impl ClientTls {
+    // A client builder configured with these options.
+    pub fn builder(&self) -> io::Result<ClientBuilder> {
+        let mut builder = reqwest::blocking::Client::builder();
+        if let Some(path) = &self.ca_bundle {
+            for cert in Certificate::from_pem_bundle(&fs::read(path)?).map_err(invalid)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => {
+                let identity = Identity::from_pkcs8_pem(&fs::read(cert)?, &fs::read(key)?);
+                builder = builder.identity(identity.map_err(invalid)?);
+            }
+            (None, None) => {}
+            _ => return Err(invalid("a client certificate and key must be given together")),
+        }
+        if self.insecure_skip_verify {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::TempDir;
+
+    #[test]
+    fn test_client_tls() {
+        assert!(ClientTls::default().builder().unwrap().build().is_ok());
+        let insecure = ClientTls { insecure_skip_verify: true, ..Default::default() };
+        assert!(insecure.builder().unwrap().build().is_ok());
+
+        let dir = TempDir::new("tls").unwrap();
+        fs::write(dir.join("bad.pem"), "not a certificate").unwrap();
+        let cert_only = ClientTls { client_cert: Some(dir.join("bad.pem")), ..Default::default() };
+        assert_eq!(cert_only.builder().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let missing = ClientTls { ca_bundle: Some(dir.join("missing.pem")), ..Default::default() };
+        assert_eq!(missing.builder().unwrap_err().kind(), io::ErrorKind::NotFound);
+        let bad = ClientTls {
+            client_cert: Some(dir.join("bad.pem")),
+            client_key: Some(dir.join("bad.pem")),
+            ..Default::default()
+        };
+        assert!(bad.builder().is_err());
+    }
+}
@@ -0,0 +1,242 @@
+use std::{cell::RefCell, sync::Mutex};
+
+use reqwest::blocking::Request as OutboundRequest;
+
+use crate::{
+    chaos::random, ClientMiddleware, Exchange, Middleware, MiddlewareError, MiddlewareFactory,
+    Request, Response,
+};
+
+// longest tracestate passed along, as the spec lets us drop longer ones
+const MAX_TRACESTATE: usize = 512;
+
+thread_local! {
+    // the trace of the request this thread is handling, see TraceContext::current
+    static CURRENT: RefCell<Option<TraceContext>> = const { RefCell::new(None) };
+}
+
+// This server's place in a W3C Trace Context (traceparent and tracestate
+// headers), so a request can be followed across the services it touches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    // this server's span, the parent of the calls it makes
+    pub span_id: u64,
+    // the caller's span, if the request carried one
+    pub parent_id: Option<u64>,
+    pub sampled: bool,
+    // vendor entries, passed along untouched
+    pub state: Option<String>,
+}
+
+// |len| lowercase hex digits, which mustn't all be zero
+fn hex_id(field: &str, len: usize) -> Option<u128> {
+    if field.len() != len || !field.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    u128::from_str_radix(field, 16).ok().filter(|&id| id != 0)
+}
+
+fn span_id() -> u64 {
+    // zero isn't a valid id
+    random().max(1)
+}
+
+impl TraceContext {
+    // A new, sampled trace for a request that didn't carry one.
+    pub fn root() -> Self {
+        let trace_id = (random() as u128) << 64 | random() as u128;
+        Self {
+            trace_id: trace_id.max(1),
+            span_id: span_id(),
+            parent_id: None,
+            sampled: true,
+            state: None,
+        }
+    }
+
+    // A span in the caller's trace, or None if |traceparent| is malformed.
+    pub fn child_of(traceparent: &str, tracestate: Option<&str>) -> Option<Self> {
+        let fields: Vec<&str> = traceparent.trim().split('-').collect();
+        let version = fields.first().filter(|v| v.len() == 2)?;
+        let version = u8::from_str_radix(version, 16).ok().filter(|&v| v != 0xff)?;
+        // later versions may append fields, which we can't interpret
+        if fields.len() < 4 || (version == 0 && fields.len() != 4) {
+            return None;
+        }
+        let trace_id = hex_id(fields[1], 32)?;
+        let parent_id = hex_id(fields[2], 16)? as u64;
+        let flags = fields[3];
+        if flags.len() != 2 {
+            return None;
+        }
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        let state = tracestate
+            .map(str::trim)
+            .filter(|state| !state.is_empty() && state.len() <= MAX_TRACESTATE)
+            .map(String::from);
+        Some(Self {
+            trace_id,
+            span_id: span_id(),
+            parent_id: Some(parent_id),
+            sampled: flags & 1 == 1,
+            state,
+        })
+    }
+
+    // The traceparent for calls made from this span.
+    pub fn traceparent(&self) -> String {
+        format!("00-{:032x}-{:016x}-{:02x}", self.trace_id, self.span_id, self.sampled as u8)
+    }
+
+    // Adds traceparent and tracestate headers to an outbound request.
+    pub fn inject(&self, req: &mut OutboundRequest) -> Result<(), MiddlewareError> {
+        let headers = req.headers_mut();
+        headers.insert("traceparent", self.traceparent().parse()?);
+        if let Some(state) = &self.state {
+            headers.insert("tracestate", state.parse()?);
+        }
+        Ok(())
+    }
+
+    // The trace of the request being handled on this thread, if any.
+    pub fn current() -> Option<Self> {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+
+    // Makes this the current trace on this thread until the guard is dropped.
+    pub fn enter(&self) -> CurrentTrace {
+        CurrentTrace(CURRENT.with(|current| current.replace(Some(self.clone()))))
+    }
+}
+
+// Restores the trace that was current before TraceContext::enter when
+// dropped, so a thread doesn't carry a finished request's trace into the
+// next one.
+pub struct CurrentTrace(Option<TraceContext>);
+
+impl Drop for CurrentTrace {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+    }
+}
+
+// Continues the caller's trace, or starts one, for each request. The
+// TraceContext is attached to the request's extensions, and is current on
+// the handling thread so outbound calls and webhooks carry it along.
+pub struct TraceFactory;
+
+impl MiddlewareFactory for TraceFactory {
+    fn new(&self, req: &Request) -> Option<Box<dyn Middleware>> {
+        let parent = req.get_header("traceparent");
+        let context = parent
+            .and_then(|parent| TraceContext::child_of(parent, req.get_header("tracestate")))
+            .unwrap_or_else(TraceContext::root);
+        Some(Box::new(Trace { context, current: Mutex::new(None) }))
+    }
+}
+
+// The trace stays current until the request is done with its middleware.
+pub struct Trace {
+    context: TraceContext,
+    current: Mutex<Option<CurrentTrace>>,
+}
+
+impl Middleware for Trace {
+    fn apply_before(&self, req: &mut Request) -> Result<(), MiddlewareError> {
+        *self.current.lock().unwrap() = Some(self.context.enter());
+        req.extensions.insert(self.context.clone());
+        Ok(())
+    }
+
+    fn apply_after(&self, _resp: &mut Response) -> Result<(), MiddlewareError> {
+        // do nothing
+        Ok(())
+    }
+}
+
+// Sends the current trace along with outbound requests made while handling
+// a request, or while a trace is entered, e.g. for a webhook delivery.
+pub struct TracePropagation;
+
+impl ClientMiddleware for TracePropagation {
+    fn apply_before(&self, req: &mut OutboundRequest) -> Result<(), MiddlewareError> {
+        match TraceContext::current() {
+            Some(context) => context.inject(req),
+            None => Ok(()),
+        }
+    }
+
+    fn apply_after(&self, _exchange: &Exchange) {}
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::parse_request;
+
+    const PARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn test_traceparent() {
+        let context = TraceContext::child_of(PARENT, Some("congo=t61rcWkgMzE")).unwrap();
+        assert_eq!(context.trace_id, 0x4bf92f3577b34da6a3ce929d0e0e4736);
+        assert_eq!(context.parent_id, Some(0x00f067aa0ba902b7));
+        assert!(context.sampled);
+        assert_eq!(context.state.as_deref(), Some("congo=t61rcWkgMzE"));
+        let traceparent = context.traceparent();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(traceparent.ends_with("-01"));
+        assert_ne!(traceparent, PARENT);
+
+        let future = "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00-extra";
+        assert!(!TraceContext::child_of(future, None).unwrap().sampled);
+        for bad in [
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "",
+        ] {
+            assert_eq!(TraceContext::child_of(bad, None), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_trace_middleware() {
+        let raw = format!("GET / HTTP/1.1\r\ntraceparent: {}\r\n\r\n", PARENT);
+        let mut reader = Cursor::new(raw.into_bytes());
+        let mut req = parse_request(&mut reader).unwrap();
+        let trace = TraceFactory.new(&req).unwrap();
+        trace.apply_before(&mut req).unwrap();
+        let context = req.extensions.get::<TraceContext>().unwrap().clone();
+        assert_eq!(context.trace_id, 0x4bf92f3577b34da6a3ce929d0e0e4736);
+        assert_eq!(TraceContext::current(), Some(context.clone()));
+
+        let mut outbound =
+            reqwest::blocking::Client::new().get("http://example.com").build().unwrap();
+        TracePropagation.apply_before(&mut outbound).unwrap();
+        assert_eq!(outbound.headers()["traceparent"], context.traceparent().as_str());
+
+        // once the request is done, the thread no longer carries its trace
+        drop(trace);
+        assert_eq!(TraceContext::current(), None);
+        let mut outbound =
+            reqwest::blocking::Client::new().get("http://example.com").build().unwrap();
+        TracePropagation.apply_before(&mut outbound).unwrap();
+        assert!(!outbound.headers().contains_key("traceparent"));
+
+        // without a traceparent, a new trace starts
+        let mut reader = Cursor::new(b"GET / HTTP/1.1\r\n\r\n".to_vec());
+        let mut req = parse_request(&mut reader).unwrap();
+        TraceFactory.new(&req).unwrap().apply_before(&mut req).unwrap();
+        let root = req.extensions.get::<TraceContext>().unwrap();
+        assert_eq!(root.parent_id, None);
+        assert_ne!(root.trace_id, context.trace_id);
+    }
+}
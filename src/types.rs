@@ -1,41 +1,79 @@
 use std::{
+    borrow::Cow,
     error::Error,
     fmt::Display,
-    io::{self, BufRead, Cursor, Read},
+    fs::File,
+    io::{self, BufRead, Read, Write},
+    net::SocketAddr,
+    path::{Component, Path},
     str::FromStr,
-    sync::OnceLock,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+use bytes::Bytes;
 use regex::Regex;
 
+use crate::{EntityTag, Extensions, MimeTypes};
+
+// Carries the status the unparseable request should be answered with.
 #[derive(Debug)]
-pub struct RequestParsingError;
+pub struct RequestParsingError(HttpStatus);
+
+impl RequestParsingError {
+    pub fn status(&self) -> HttpStatus {
+        self.0
+    }
+}
+
+fn invalid() -> RequestParsingError {
+    RequestParsingError(HttpStatus::BadRequest)
+}
 
 impl From<io::Error> for RequestParsingError {
-    fn from(_value: io::Error) -> Self {
-        Self
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Self(HttpStatus::RequestTimeout),
+            _ => invalid(),
+        }
     }
 }
 
 impl Display for RequestParsingError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "failed to parse request")
+        write!(f, "failed to parse request: {}", self.0)
     }
 }
 
 impl Error for RequestParsingError {}
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Method {
     Get,
+    Head,
     Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    // an extension method such as PROPFIND, as sent
+    Other(String),
 }
 
 impl Display for Method {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let s = match self {
             Self::Get => "GET",
+            Self::Head => "HEAD",
             Self::Post => "POST",
+            Self::Put => "PUT",
+            Self::Delete => "DELETE",
+            Self::Patch => "PATCH",
+            Self::Options => "OPTIONS",
+            Self::Other(method) => method,
         };
         write!(f, "{}", s)
     }
@@ -47,17 +85,341 @@ impl FromStr for Method {
         match s {
             "POST" => Ok(Self::Post),
             "GET" => Ok(Self::Get),
-            _ => Err(RequestParsingError),
+            "HEAD" => Ok(Self::Head),
+            "PUT" => Ok(Self::Put),
+            "DELETE" => Ok(Self::Delete),
+            "PATCH" => Ok(Self::Patch),
+            "OPTIONS" => Ok(Self::Options),
+            // methods are case-sensitive, so "get" is an extension method too
+            _ if !s.is_empty() && s.bytes().all(is_token) => Ok(Self::Other(s.to_owned())),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Http10 => "HTTP/1.0",
+            Self::Http11 => "HTTP/1.1",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for Version {
+    type Err = RequestParsingError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "HTTP/1.0" => Ok(Self::Http10),
+            "HTTP/1.1" => Ok(Self::Http11),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+// longest chunk-size or trailer line accepted in a chunked body
+const MAX_CHUNK_LINE: u64 = 4096;
+
+// most of a body left unread by the handler that's drained to reuse the
+// connection; the connection is closed instead of reading any more
+const MAX_DRAIN_BYTES: u64 = 256 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    // bytes left of a content-length body
+    Length(u64),
+    // bytes left in the current chunk, or None before the next chunk-size line
+    Chunked(Option<u64>),
+    Done,
+}
+
+// A request body, framed by Content-Length or chunked transfer coding so
+// handlers only ever see the bytes of this request. Whatever the handler
+// leaves unread is drained on drop, so the next request on a kept-alive
+// connection starts at the right offset. Up to MAX_DRAIN_BYTES are; a body
+// with more left, or that fails to drain, is abandoned and its connection
+// can't be reused.
+pub struct Body<'t> {
+    source: Source<'t>,
+    abandoned: Arc<AtomicBool>,
+    // see received
+    received: Arc<AtomicU64>,
+}
+
+enum Source<'t> {
+    // the bytes as sent, less the framing
+    Raw(Framed<'t>),
+    // what a decoder makes of them, see decode; the decoder holds the raw
+    // body, which drains itself when dropped without decoding the rest
+    Decoded(Box<dyn BufRead + 't>),
+}
+
+// Reads the bytes of one body out of a connection's stream.
+struct Framed<'t> {
+    reader: &'t mut dyn BufRead,
+    framing: Framing,
+    // what's left of ParseOptions::max_header_bytes for chunked trailers
+    trailer_bytes: usize,
+    // the body's bytes read so far, less the framing
+    received: Arc<AtomicU64>,
+}
+
+impl<'t> Body<'t> {
+    pub fn new(reader: &'t mut dyn BufRead, len: u64) -> Self {
+        Self::framed(reader, Framing::Length(len), ParseOptions::default().max_header_bytes)
+    }
+
+    pub fn chunked(reader: &'t mut dyn BufRead) -> Self {
+        Self::framed(reader, Framing::Chunked(None), ParseOptions::default().max_header_bytes)
+    }
+
+    fn framed(reader: &'t mut dyn BufRead, framing: Framing, trailer_bytes: usize) -> Self {
+        let received = Arc::<AtomicU64>::default();
+        let source =
+            Source::Raw(Framed { reader, framing, trailer_bytes, received: Arc::clone(&received) });
+        Self { source, abandoned: Arc::default(), received }
+    }
+
+    // Set once the body is dropped without being read or drained to its
+    // end, which leaves the connection at an unknown offset.
+    pub fn abandoned(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.abandoned)
+    }
+
+    // Counts the bytes of the body as sent, less its framing and before any
+    // decoding, as they're read or drained. Once the body is dropped, this is
+    // the size of a chunked body, which nothing declares up front.
+    pub fn received(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.received)
+    }
+
+    // Gives up on the rest of a body that hasn't been decoded without reading
+    // it, for when the connection is closing anyway and draining a large
+    // upload would be wasted.
+    pub(crate) fn discard(&mut self) {
+        if let Source::Raw(framed) = &mut self.source {
+            framed.framing = Framing::Done;
+        }
+        self.abandoned.store(true, Ordering::SeqCst);
+    }
+
+    // Replaces the body with what |decoder| makes of it, e.g. a gzip decoder.
+    // Reads fail once more than |limit| decoded bytes come out, so a small
+    // body can't inflate without bound. If |decoder| fails, the encoded body
+    // is drained and this one is left empty.
+    pub fn decode<D: Read + 't>(
+        &mut self,
+        decoder: impl FnOnce(Body<'t>) -> io::Result<D>,
+        limit: u64,
+    ) -> io::Result<()> {
+        // io::Empty is zero-sized, so boxing the stand-in doesn't allocate
+        let source = std::mem::replace(&mut self.source, Source::Decoded(Box::new(io::empty())));
+        let encoded = Body {
+            source,
+            abandoned: Arc::clone(&self.abandoned),
+            received: Arc::clone(&self.received),
+        };
+        let decoded = Limit { inner: decoder(encoded)?, left: limit };
+        self.source = Source::Decoded(Box::new(io::BufReader::new(decoded)));
+        Ok(())
+    }
+}
+
+impl Framed<'_> {
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        (&mut *self.reader).take(MAX_CHUNK_LINE).read_line(&mut line)?;
+        match line.strip_suffix("\r\n") {
+            Some(line) => Ok(line.to_owned()),
+            None if line.is_empty() => Err(truncated()),
+            None => Err(bad_chunk()),
+        }
+    }
+
+    // reads a chunk-size line, and the trailers after the last chunk
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let line = self.read_line()?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        if size.is_empty() || size.len() > 15 || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(bad_chunk());
+        }
+        let size = u64::from_str_radix(size, 16).map_err(|_| bad_chunk())?;
+        if size > 0 {
+            self.framing = Framing::Chunked(Some(size));
+            return Ok(());
+        }
+        // trailers are read and ignored, but count against the header limit
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                break;
+            }
+            self.trailer_bytes =
+                self.trailer_bytes.checked_sub(line.len() + 2).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "request trailers too large")
+                })?;
+        }
+        self.framing = Framing::Done;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct BodyTooLarge;
+
+impl Display for BodyTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "decoded request body too large")
+    }
+}
+
+impl Error for BodyTooLarge {}
+
+// Fails reads that would take more than |left| bytes from |inner|.
+struct Limit<R> {
+    inner: R,
+    left: u64,
+}
+
+impl<R: Read> Read for Limit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // one byte more than allowed shows whether there's too much
+        let max = buf.len().min(self.left.saturating_add(1).try_into().unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..max])?;
+        if n as u64 > self.left {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, BodyTooLarge));
+        }
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+// the connection closing before the whole body arrived
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "request body truncated")
+}
+
+fn bad_chunk() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid chunked encoding")
+}
+
+impl Read for Body<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let data = self.fill_buf()?;
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for Framed<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let left = loop {
+            match self.framing {
+                Framing::Done | Framing::Length(0) => return Ok(&[]),
+                Framing::Chunked(None) => self.next_chunk()?,
+                Framing::Chunked(Some(0)) => {
+                    // each chunk's data is followed by a line break
+                    if !self.read_line()?.is_empty() {
+                        return Err(bad_chunk());
+                    }
+                    self.framing = Framing::Chunked(None);
+                }
+                Framing::Length(n) | Framing::Chunked(Some(n)) => break n,
+            }
+        };
+        let buf = self.reader.fill_buf()?;
+        if buf.is_empty() {
+            return Err(truncated());
+        }
+        Ok(&buf[..buf.len().min(left as usize)])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt);
+        self.received.fetch_add(amt as u64, Ordering::Relaxed);
+        match &mut self.framing {
+            Framing::Length(n) | Framing::Chunked(Some(n)) => *n -= amt as u64,
+            _ => {}
+        }
+    }
+}
+
+impl Read for Framed<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.fill_buf()?;
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for Body<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        match &mut self.source {
+            Source::Raw(framed) => framed.fill_buf(),
+            Source::Decoded(decoded) => decoded.fill_buf(),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        match &mut self.source {
+            Source::Raw(framed) => framed.consume(amt),
+            Source::Decoded(decoded) => decoded.consume(amt),
+        }
+    }
+}
+
+impl Drop for Body<'_> {
+    fn drop(&mut self) {
+        // the encoded body drains itself without decoding the rest
+        if matches!(self.source, Source::Decoded(_)) {
+            return;
+        }
+        let drained = io::copy(&mut Read::take(&mut *self, MAX_DRAIN_BYTES), &mut io::sink());
+        if drained.is_err() || !matches!(self.fill_buf(), Ok([])) {
+            self.abandoned.store(true, Ordering::SeqCst);
         }
     }
 }
 
 pub struct Request<'t> {
     pub method: Method,
+    // the decoded, normalized path, see normalize_target
     pub path: String,
+    // the query string without the ?, still percent-encoded
+    pub query: Option<String>,
+    // the request target exactly as sent
+    pub raw_target: String,
+    pub version: Version,
     pub matches: Option<Vec<Option<String>>>,
+    params: Vec<(String, String)>,
     headers: Vec<(String, String)>,
-    pub body: &'t mut dyn BufRead,
+    // header names as the client spelled them, parallel to headers
+    names: Vec<String>,
+    // see ParseOptions::max_body_bytes
+    max_body: u64,
+    pub body: Body<'t>,
+    // the body, if it was read ahead of the handler, see buffer_body
+    buffered: Option<Bytes>,
+    // data attached by middleware for the handler
+    pub extensions: Extensions,
+    // the client's address, set by the server
+    pub peer_addr: Option<SocketAddr>,
+    // the Quirks it was accepted with, for the server to warn about
+    quirks: Vec<&'static str>,
 }
 
 impl Request<'_> {
@@ -66,62 +428,638 @@ impl Request<'_> {
         self
     }
 
+    pub fn with_params(mut self, params: Vec<(String, String)>) -> Self {
+        self.params = params;
+        self
+    }
+
+    // the route's named parameters, in pattern order
+    pub fn params(&self) -> &[(String, String)] {
+        &self.params
+    }
+
+    // the legacy quirks the request was parsed despite, see ParseOptions::lenient
+    pub fn quirks(&self) -> &[&'static str] {
+        &self.quirks
+    }
+
+    // named route parameter, e.g. "message" for the route /echo/:message
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+    }
+
     pub fn get_header(&self, key: &str) -> Option<&str> {
         self.headers
             .iter()
             .find(|(k, _)| k.to_lowercase() == key.to_lowercase())
             .map(|(_, v)| v.as_str())
     }
+
+    pub fn headers(&self) -> impl Iterator<Item = &(String, String)> {
+        self.headers.iter()
+    }
+
+    // Reads the whole body, answering 413 if it's larger than the configured
+    // maximum and 400 if it can't be read.
+    pub fn bytes(&mut self) -> Result<Bytes, HttpError> {
+        if let Some(data) = &self.buffered {
+            return Ok(data.clone());
+        }
+        let declared = self.get_header("content-length").and_then(|v| v.parse::<u64>().ok());
+        if declared.is_some_and(|len| len > self.max_body) {
+            return Err(HttpError(HttpStatus::ContentTooLarge));
+        }
+        let mut data = Vec::new();
+        (&mut self.body).take(self.max_body + 1).read_to_end(&mut data).map_err(|err| match err
+            .get_ref()
+            .is_some_and(|err| err.is::<BodyTooLarge>())
+        {
+            true => HttpError(HttpStatus::ContentTooLarge),
+            false => HttpError(HttpStatus::BadRequest),
+        })?;
+        if data.len() as u64 > self.max_body {
+            return Err(HttpError(HttpStatus::ContentTooLarge));
+        }
+        Ok(Bytes::from(data))
+    }
+
+    // Reads the body into memory now, see BodyPolicy::Buffer. Afterwards
+    // bytes() and friends return the buffered copy and |body| is empty.
+    pub fn buffer_body(&mut self) -> Result<(), HttpError> {
+        if self.buffered.is_none() {
+            self.buffered = Some(self.bytes()?);
+        }
+        Ok(())
+    }
+
+    pub fn buffered(&self) -> Option<&Bytes> {
+        self.buffered.as_ref()
+    }
+
+    // the whole body as UTF-8, see bytes
+    pub fn text(&mut self) -> Result<String, HttpError> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| HttpError(HttpStatus::BadRequest))
+    }
+
+    // whether the content-type is |mime|, ignoring parameters like charset
+    pub fn content_type_is(&self, mime: &str) -> bool {
+        self.content_type().is_some_and(|ct| ct.mime.eq_ignore_ascii_case(mime))
+    }
+
+    pub fn remove_header(&mut self, key: &str) {
+        // names only differ from the stored ones in case, so both stay parallel
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+        self.names.retain(|k| !k.eq_ignore_ascii_case(key));
+    }
+
+    // headers with their names as received, for logging
+    pub fn original_headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.names.iter().zip(&self.headers).map(|(k, (_, v))| (k.as_str(), v.as_str()))
+    }
+
+    // HTTP/1.1 connections are persistent unless either side says otherwise,
+    // HTTP/1.0 ones only if the client asks for it
+    pub fn keep_alive(&self) -> bool {
+        let has_token = |token: &str| {
+            self.get_header("connection")
+                .map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+                .unwrap_or(false)
+        };
+        match self.version {
+            Version::Http10 => has_token("keep-alive"),
+            Version::Http11 => !has_token("close"),
+        }
+    }
 }
 
-fn parse_request_line(line: String) -> Result<(Method, String), RequestParsingError> {
+fn parse_request_line(
+    line: String,
+    quirks: &mut Quirks,
+) -> Result<(Method, String, Version), RequestParsingError> {
     static PATH: OnceLock<Regex> = OnceLock::new();
-    let pat = PATH.get_or_init(|| Regex::new("^(GET|POST) (/[^ ]*) HTTP/1.1$").unwrap());
-    let caps = pat.captures(&line).ok_or(RequestParsingError)?;
+    let pat = PATH.get_or_init(|| {
+        Regex::new(
+            "^([!#$%&'*+.^_`|~0-9A-Za-z-]+) (/[!-~]*|\\*|[A-Za-z][0-9A-Za-z+.-]*:[!-~]*) \
+             (HTTP/1\\.[01])$",
+        )
+        .unwrap()
+    });
+    let line = match pat.is_match(&line) {
+        true => line,
+        false => escape_target_spaces(&line, quirks)?,
+    };
+    let caps = pat.captures(&line).ok_or_else(invalid)?;
     let method = caps[1].parse()?;
     let path = caps[2].to_string();
-    Ok((method, path))
+    let version = caps[3].parse()?;
+    Ok((method, path, version))
+}
+
+// The request line with spaces in its target's path percent-encoded, as some
+// old clients send them raw. Spaces in the query stay ambiguous.
+fn escape_target_spaces(line: &str, quirks: &mut Quirks) -> Result<String, RequestParsingError> {
+    let (method, rest) = line.split_once(' ').ok_or_else(invalid)?;
+    let (target, version) = rest.rsplit_once(' ').ok_or_else(invalid)?;
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None),
+    };
+    if !path.contains(' ') || query.is_some_and(|q| q.contains(' ')) {
+        return Err(invalid());
+    }
+    quirks.allow("spaces in the request target")?;
+    let mut line = format!("{} {}", method, path.replace(' ', "%20"));
+    if let Some(query) = query {
+        line.push('?');
+        line.push_str(query);
+    }
+    line.push(' ');
+    line.push_str(version);
+    Ok(line)
+}
+
+// The authority and origin-form rest of an absolute-form target such as
+// http://host/a?b, which clients send to proxies and servers must accept too
+// (RFC 9112 section 3.2.2). Only http and https URIs name resources this
+// server could have; any other scheme is refused with a 400 of its own, which
+// the log shows as Unsupported URI Scheme.
+fn absolute_form(target: &str) -> Result<(String, String), RequestParsingError> {
+    let (scheme, rest) = target.split_once(':').ok_or_else(invalid)?;
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return Err(RequestParsingError(HttpStatus::Custom(400, "Unsupported URI Scheme")));
+    }
+    let rest = rest.strip_prefix("//").ok_or_else(invalid)?;
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (authority, origin) = rest.split_at(end);
+    // userinfo is deprecated for http URIs, and an empty host is invalid
+    if authority.is_empty() || authority.contains('@') {
+        return Err(invalid());
+    }
+    let origin = match origin.starts_with('/') {
+        true => origin.to_string(),
+        false => format!("/{}", origin),
+    };
+    Ok((authority.to_string(), origin))
 }
 
+// Splits a request target into its path and raw query. The path is
+// percent-decoded, except for %2F which would change its segments and %25,
+// so what's left encoded can't be mistaken for an escape, and "." and ".."
+// segments are resolved as in RFC 3986 section 5.2.4, so handlers never see
+// a path climbing above the root. Paths must start with a /.
+fn normalize_target(target: &str) -> Result<(String, Option<String>), RequestParsingError> {
+    if target == "*" {
+        return Ok((target.to_owned(), None));
+    }
+    if !target.starts_with('/') {
+        return Err(invalid());
+    }
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_owned())),
+        None => (target, None),
+    };
+    let path = decode_path(path)?;
+    let parts: Vec<&str> = path.split('/').skip(1).collect();
+    let mut segments = Vec::new();
+    for (i, segment) in parts.iter().enumerate() {
+        let last = i == parts.len() - 1;
+        match *segment {
+            "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => {
+                segments.push(segment);
+                continue;
+            }
+        }
+        // a trailing dot segment leaves a directory path
+        if last {
+            segments.push("");
+        }
+    }
+    Ok((format!("/{}", segments.join("/")), query))
+}
+
+fn decode_path(path: &str) -> Result<String, RequestParsingError> {
+    let hex = |b: Option<&u8>| b.and_then(|b| (*b as char).to_digit(16));
+    let mut out = Vec::with_capacity(path.len());
+    let mut bytes = path.as_bytes().iter();
+    while let Some(&b) = bytes.next() {
+        if b != b'%' {
+            out.push(b);
+            continue;
+        }
+        let (Some(hi), Some(lo)) = (hex(bytes.next()), hex(bytes.next())) else {
+            return Err(invalid());
+        };
+        match (hi << 4 | lo) as u8 {
+            0 => return Err(invalid()),
+            b'/' => out.extend_from_slice(b"%2F"),
+            b'%' => out.extend_from_slice(b"%25"),
+            b => out.push(b),
+        }
+    }
+    String::from_utf8(out).map_err(|_| invalid())
+}
+
+// tchar from RFC 9110, which method and header names are made of
+pub(crate) fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+// Whether a header can be sent as is: the name must be a token, and the
+// value can't hold a CR, LF or NUL, which would end the header early and
+// let the rest pass for more headers or another response.
+pub(crate) fn is_valid_header(name: &str, value: &str) -> bool {
+    !name.is_empty()
+        && name.bytes().all(is_token)
+        && !value.bytes().any(|b| matches!(b, b'\r' | b'\n' | 0))
+}
+
+// field-name is a token; the value may not contain CR, LF or NUL, and obsolete
+// line folding (continuation lines starting with whitespace) is rejected
 fn parse_header(line: String) -> Result<(String, String), RequestParsingError> {
     static HEADER: OnceLock<Regex> = OnceLock::new();
-    let pat = HEADER.get_or_init(|| Regex::new("^([^ ]+): (.+)$").unwrap());
-    let caps = pat.captures(&line).ok_or(RequestParsingError)?;
+    let pat = HEADER.get_or_init(|| {
+        Regex::new("^([!#$%&'*+.^_`|~0-9A-Za-z-]+):[ \t]*([^\r\n\x00]*?)[ \t]*$").unwrap()
+    });
+    let caps = pat.captures(&line).ok_or_else(invalid)?;
     Ok((caps[1].to_owned(), caps[2].to_owned()))
 }
 
+// Determines how the body is framed, rejecting ambiguous framing that a proxy
+// in front of us might interpret differently.
+fn framing(headers: &[(String, String)]) -> Result<Framing, RequestParsingError> {
+    let values = |name: &'static str| {
+        headers.iter().filter(move |(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v)
+    };
+    if values("transfer-encoding").next().is_some() {
+        if values("content-length").next().is_some() {
+            return Err(invalid());
+        }
+        // chunked is the only coding we can decode
+        let mut codings = values("transfer-encoding").flat_map(|v| v.split(',')).map(str::trim);
+        return match (codings.next(), codings.next()) {
+            (Some(c), None) if c.eq_ignore_ascii_case("chunked") => Ok(Framing::Chunked(None)),
+            _ => Err(RequestParsingError(HttpStatus::NotImplemented)),
+        };
+    }
+    let mut len = None;
+    for value in values("content-length").flat_map(|v| v.split(',')) {
+        let value = value.trim();
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let n: u64 = value.parse().map_err(|_| invalid())?;
+        if len.is_some_and(|len| len != n) {
+            return Err(invalid());
+        }
+        len = Some(n);
+    }
+    Ok(Framing::Length(len.unwrap_or(0)))
+}
+
+type Headers = Vec<(String, String)>;
+
+// Options applied while parsing, see the corresponding Config fields.
+#[derive(Debug, Clone, Copy)]
+pub struct ParseOptions {
+    // store header names lowercased and merge repeated fields
+    pub normalize_headers: bool,
+    // largest body Request::bytes and friends will read
+    pub max_body_bytes: u64,
+    // largest header block, and request line, accepted
+    pub max_header_bytes: usize,
+    // largest single header value accepted
+    pub max_header_value_bytes: usize,
+    // accept the legacy quirks in Quirks, listing them in Request::quirks
+    pub lenient: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            normalize_headers: false,
+            max_body_bytes: 1 << 20,
+            max_header_bytes: 64 << 10,
+            max_header_value_bytes: 16 << 10,
+            lenient: false,
+        }
+    }
+}
+
+// fields that can't be combined into a list, so repeating them is an error
+const SINGLETON_HEADERS: &[&str] = &[
+    "authorization",
+    "content-type",
+    "from",
+    "host",
+    "if-modified-since",
+    "if-range",
+    "if-unmodified-since",
+    "max-forwards",
+    "proxy-authorization",
+    "range",
+    "referer",
+    "user-agent",
+];
+
+// Lowercases header names and folds repeated fields into one, comma-joined as
+// RFC 9110 allows for list-valued fields (cookies are joined with "; "). Also
+// returns the first spelling of each name.
+fn normalize(headers: Headers) -> Result<(Headers, Vec<String>), RequestParsingError> {
+    let mut merged: Headers = Vec::new();
+    let mut names = Vec::new();
+    for (name, value) in headers {
+        let lower = name.to_ascii_lowercase();
+        match merged.iter_mut().find(|(k, _)| *k == lower) {
+            Some(_) if SINGLETON_HEADERS.contains(&lower.as_str()) => return Err(invalid()),
+            Some((_, v)) => {
+                v.push_str(if lower == "cookie" { "; " } else { ", " });
+                v.push_str(&value);
+            }
+            None => {
+                names.push(name);
+                merged.push((lower, value));
+            }
+        }
+    }
+    Ok((merged, names))
+}
+
+// Deviations from the grammar that some old clients can't help, rejected
+// unless parsing leniently. Those seen are kept on the request, once each.
+struct Quirks {
+    lenient: bool,
+    seen: Vec<&'static str>,
+}
+
+impl Quirks {
+    fn allow(&mut self, quirk: &'static str) -> Result<(), RequestParsingError> {
+        if !self.lenient {
+            return Err(invalid());
+        }
+        if !self.seen.contains(&quirk) {
+            self.seen.push(quirk);
+        }
+        Ok(())
+    }
+}
+
+// Reads a line of at most |limit| bytes, not counting its terminator, and
+// answers |status| for longer ones.
+fn read_line(
+    reader: &mut dyn BufRead,
+    limit: usize,
+    status: HttpStatus,
+    quirks: &mut Quirks,
+) -> Result<String, RequestParsingError> {
+    let mut line = String::new();
+    let n = Read::take(reader, limit as u64 + 2).read_line(&mut line)?;
+    match line.strip_suffix('\n') {
+        Some(content) => {
+            let content = match content.strip_suffix('\r') {
+                Some(content) => content,
+                None => {
+                    quirks.allow("bare LF line endings")?;
+                    content
+                }
+            };
+            if content.len() > limit {
+                return Err(RequestParsingError(status));
+            }
+            line.truncate(content.len());
+            Ok(line)
+        }
+        // running out of input before the end of the line
+        None if n < limit + 2 => Err(invalid()),
+        None => Err(RequestParsingError(status)),
+    }
+}
+
 pub fn parse_request(reader: &mut dyn BufRead) -> Result<Request<'_>, RequestParsingError> {
-    let mut lines = reader.lines();
-    let (method, path) = parse_request_line(lines.next().ok_or(RequestParsingError)??)?;
-    let headers = lines
-        .take_while(|line| line.as_ref().map(|s| !s.is_empty()).unwrap_or(false))
-        .map(|line| line.map_err(|err| err.into()).and_then(parse_header))
-        .collect::<Result<Vec<(String, String)>, _>>()?;
-    Ok(Request { method, path, headers, body: reader, matches: None })
+    parse_request_with(reader, ParseOptions::default())
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum HttpStatus {
-    OK,
-    Created,
-    NotFound,
-    BadRequest,
-    ServerError,
+pub fn parse_request_with(
+    reader: &mut dyn BufRead,
+    options: ParseOptions,
+) -> Result<Request<'_>, RequestParsingError> {
+    let mut quirks = Quirks { lenient: options.lenient, seen: Vec::new() };
+    let line = read_line(reader, options.max_header_bytes, HttpStatus::UriTooLong, &mut quirks)?;
+    let (method, raw_target, version) = parse_request_line(line, &mut quirks)?;
+    let (authority, origin) = match raw_target.starts_with('/') || raw_target == "*" {
+        true => (None, raw_target.clone()),
+        false => absolute_form(&raw_target).map(|(authority, origin)| (Some(authority), origin))?,
+    };
+    let (path, query) = normalize_target(&origin)?;
+    let too_large = || RequestParsingError(HttpStatus::RequestHeaderFieldsTooLarge);
+    let mut headers = Vec::new();
+    let mut remaining = options.max_header_bytes;
+    loop {
+        // running out of input before the blank line is an error too
+        let line =
+            read_line(reader, remaining, HttpStatus::RequestHeaderFieldsTooLarge, &mut quirks)?;
+        if line.is_empty() {
+            break;
+        }
+        remaining = remaining.checked_sub(line.len() + 2).ok_or_else(too_large)?;
+        let (name, value) = parse_header(line)?;
+        if value.len() > options.max_header_value_bytes {
+            return Err(too_large());
+        }
+        headers.push((name, value));
+    }
+    let (mut headers, mut names) = if options.normalize_headers {
+        normalize(headers)?
+    } else {
+        let names = headers.iter().map(|(k, _)| k.clone()).collect();
+        (headers, names)
+    };
+    // an absolute-form target's host overrides the Host header
+    if let Some(authority) = authority {
+        match headers.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case("host")) {
+            Some((_, host)) => *host = authority,
+            None => {
+                headers.push(("host".to_string(), authority));
+                names.push("Host".to_string());
+            }
+        }
+    }
+    let framing = framing(&headers)?;
+    let body = Body::framed(reader, framing, remaining);
+    Ok(Request {
+        method,
+        path,
+        query,
+        raw_target,
+        version,
+        headers,
+        names,
+        max_body: options.max_body_bytes,
+        body,
+        buffered: None,
+        extensions: Extensions::default(),
+        peer_addr: None,
+        matches: None,
+        params: Vec::new(),
+        quirks: quirks.seen,
+    })
+}
+
+// Declares the HttpStatus variants together with their codes and reasons so
+// the three can't drift apart. Statuses are equal when their codes are, so
+// Custom(404, "Missing") is a NotFound with another reason phrase.
+macro_rules! http_statuses {
+    ($($name:ident = $code:literal $reason:literal,)*) => {
+        #[derive(Debug, Clone, Copy)]
+        pub enum HttpStatus {
+            $($name,)*
+            // any other code from 100 to 999, with its reason phrase
+            Custom(u16, &'static str),
+        }
+
+        impl HttpStatus {
+            pub fn code(&self) -> u16 {
+                match self {
+                    $(HttpStatus::$name => $code,)*
+                    HttpStatus::Custom(code, _) => *code,
+                }
+            }
+
+            pub fn reason(&self) -> &'static str {
+                match self {
+                    $(HttpStatus::$name => $reason,)*
+                    HttpStatus::Custom(_, reason) => reason,
+                }
+            }
+
+            // The named variant for a Custom status with a known code, and
+            // 500 for a code that can't be sent, being outside 100..=999. A
+            // reason that would break the status line is dropped.
+            pub fn normalized(self) -> Self {
+                match self {
+                    HttpStatus::Custom(code, _) if !(100..=999).contains(&code) => {
+                        HttpStatus::ServerError
+                    }
+                    HttpStatus::Custom(code, reason) if !is_valid_header("status", reason) => {
+                        HttpStatus::Custom(code, "").normalized()
+                    }
+                    HttpStatus::Custom(code, reason) => match code {
+                        $($code => HttpStatus::$name,)*
+                        _ => HttpStatus::Custom(code, reason),
+                    },
+                    status => status,
+                }
+            }
+        }
+
+        // Codes outside 100..=999 are 500s, see normalized.
+        impl From<u16> for HttpStatus {
+            fn from(code: u16) -> Self {
+                HttpStatus::Custom(code, "").normalized()
+            }
+        }
+    };
+}
+
+impl PartialEq for HttpStatus {
+    fn eq(&self, other: &Self) -> bool {
+        self.code() == other.code()
+    }
+}
+
+impl Eq for HttpStatus {}
+
+http_statuses! {
+    Continue = 100 "Continue",
+    SwitchingProtocols = 101 "Switching Protocols",
+    OK = 200 "OK",
+    Created = 201 "Created",
+    Accepted = 202 "Accepted",
+    NonAuthoritativeInformation = 203 "Non-Authoritative Information",
+    NoContent = 204 "No Content",
+    ResetContent = 205 "Reset Content",
+    PartialContent = 206 "Partial Content",
+    MultipleChoices = 300 "Multiple Choices",
+    MovedPermanently = 301 "Moved Permanently",
+    Found = 302 "Found",
+    SeeOther = 303 "See Other",
+    NotModified = 304 "Not Modified",
+    TemporaryRedirect = 307 "Temporary Redirect",
+    PermanentRedirect = 308 "Permanent Redirect",
+    BadRequest = 400 "Bad Request",
+    Unauthorized = 401 "Unauthorized",
+    PaymentRequired = 402 "Payment Required",
+    Forbidden = 403 "Forbidden",
+    NotFound = 404 "Not Found",
+    MethodNotAllowed = 405 "Method Not Allowed",
+    NotAcceptable = 406 "Not Acceptable",
+    ProxyAuthenticationRequired = 407 "Proxy Authentication Required",
+    RequestTimeout = 408 "Request Timeout",
+    Conflict = 409 "Conflict",
+    Gone = 410 "Gone",
+    LengthRequired = 411 "Length Required",
+    PreconditionFailed = 412 "Precondition Failed",
+    ContentTooLarge = 413 "Content Too Large",
+    UriTooLong = 414 "URI Too Long",
+    UnsupportedMediaType = 415 "Unsupported Media Type",
+    RangeNotSatisfiable = 416 "Range Not Satisfiable",
+    ExpectationFailed = 417 "Expectation Failed",
+    ImATeapot = 418 "I'm a teapot",
+    MisdirectedRequest = 421 "Misdirected Request",
+    UnprocessableContent = 422 "Unprocessable Content",
+    UpgradeRequired = 426 "Upgrade Required",
+    PreconditionRequired = 428 "Precondition Required",
+    TooManyRequests = 429 "Too Many Requests",
+    RequestHeaderFieldsTooLarge = 431 "Request Header Fields Too Large",
+    UnavailableForLegalReasons = 451 "Unavailable For Legal Reasons",
+    ServerError = 500 "Internal Server Error",
+    NotImplemented = 501 "Not Implemented",
+    BadGateway = 502 "Bad Gateway",
+    ServiceUnavailable = 503 "Service Unavailable",
+    GatewayTimeout = 504 "Gateway Timeout",
+    HttpVersionNotSupported = 505 "HTTP Version Not Supported",
 }
 
 impl Display for HttpStatus {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let message = match self {
-            HttpStatus::BadRequest => "400 Bad Request",
-            HttpStatus::NotFound => "404 Not Found",
-            HttpStatus::OK => "200 OK",
-            HttpStatus::Created => "201 Created",
-            HttpStatus::ServerError => "500 Internal Server Error",
-        };
-        write!(f, "{}", message)
+        write!(f, "{} {}", self.code(), self.reason())
     }
 }
 
+// Formats |t| as an IMF-fixdate, e.g. "Sun, 06 Nov 1994 08:49:37 GMT".
+pub fn http_date(t: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] =
+        ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let secs = t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
 #[derive(Debug)]
 pub struct HttpError(pub HttpStatus);
 
@@ -142,8 +1080,69 @@ impl Error for HttpError {}
 pub struct Response {
     pub status: HttpStatus,
     headers: Vec<(String, String)>,
-    // TODO: can we eliminate the box?
-    pub body: Option<Box<dyn Read>>,
+    pub body: Option<ResponseBody>,
+}
+
+// Where a Response's body comes from. A Writer callback writes straight into
+// the connection's buffer, e.g. with serde_json::to_writer, so the body
+// needn't be collected or adapted into a reader first.
+pub enum ResponseBody {
+    // in memory, so static pages and canned replies are sent without
+    // allocating: Bytes borrows &'static data and takes over Vecs and Strings
+    Bytes(Bytes),
+    // a file on disk, which ranges seek into rather than read through
+    File(File),
+    Reader(Box<dyn Read>),
+    Writer(BodyWriter),
+}
+
+pub type BodyWriter = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()>>;
+
+impl ResponseBody {
+    pub fn write_to(self, writer: &mut dyn Write) -> io::Result<()> {
+        match self {
+            Self::Bytes(data) => writer.write_all(&data),
+            Self::File(mut file) => io::copy(&mut file, writer).map(|_| ()),
+            Self::Reader(mut data) => io::copy(&mut data, writer).map(|_| ()),
+            Self::Writer(write) => write(writer),
+        }
+    }
+
+    pub fn into_bytes(self) -> io::Result<Vec<u8>> {
+        if let Self::Bytes(data) = self {
+            return Ok(data.into());
+        }
+        let mut buf = Vec::new();
+        self.write_to(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+impl From<Box<dyn Read>> for ResponseBody {
+    fn from(data: Box<dyn Read>) -> Self {
+        Self::Reader(data)
+    }
+}
+
+impl From<Bytes> for ResponseBody {
+    fn from(data: Bytes) -> Self {
+        Self::Bytes(data)
+    }
+}
+
+impl From<&'static [u8]> for ResponseBody {
+    fn from(data: &'static [u8]) -> Self {
+        Self::Bytes(Bytes::from_static(data))
+    }
+}
+
+impl From<Cow<'static, str>> for ResponseBody {
+    fn from(text: Cow<'static, str>) -> Self {
+        Self::Bytes(match text {
+            Cow::Borrowed(text) => Bytes::from_static(text.as_bytes()),
+            Cow::Owned(text) => Bytes::from(text),
+        })
+    }
 }
 
 impl Response {
@@ -151,6 +1150,13 @@ impl Response {
         self.headers.iter()
     }
 
+    pub fn get_header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.to_lowercase() == key.to_lowercase())
+            .map(|(_, v)| v.as_str())
+    }
+
     pub fn set_header(&mut self, new_k: String, new_v: String) {
         if let Some((_, v)) =
             self.headers.iter_mut().find(|(k, _)| k.to_lowercase() == new_k.to_lowercase())
@@ -161,28 +1167,677 @@ impl Response {
         }
     }
 
+    // Adds a header even if one of the same name is already set.
+    pub fn append_header(&mut self, key: String, value: String) {
+        self.headers.push((key, value));
+    }
+
+    pub fn remove_header(&mut self, key: &str) {
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+    }
+
     pub fn empty() -> Self {
         Response { status: HttpStatus::OK, body: None, headers: Vec::new() }
     }
 
+    // e.g. Response::builder().status(HttpStatus::Accepted).header("x-id", "1").body("ok")
+    pub fn builder() -> ResponseBuilder {
+        ResponseBuilder(Self::empty(), None)
+    }
+
     pub fn binary(data: Box<dyn Read>, size: u64) -> Self {
         let headers = vec![
             ("content-length".to_string(), size.to_string()),
             ("content-type".to_string(), "application/octet-stream".to_string()),
         ];
-        Response { status: HttpStatus::OK, body: Some(data), headers }
+        Response { status: HttpStatus::OK, body: Some(data.into()), headers }
+    }
+
+    // Types the response by |name|'s extension, see Context::mime_types, e.g.
+    // for a Response::binary of a file read from elsewhere than disk.
+    pub fn with_type_of(mut self, name: impl AsRef<Path>, types: &MimeTypes) -> Self {
+        self.set_header("content-type".to_string(), types.get(name.as_ref()).to_string());
+        self
+    }
+
+    // A download the browser should save as |filename| rather than display.
+    pub fn attachment(filename: &str, data: Box<dyn Read>, size: u64) -> Self {
+        let mut resp = Self::binary(data, size);
+        resp.set_header("content-disposition".to_string(), content_disposition(filename));
+        resp
+    }
+
+    // The file at |path|, typed by its extension, see Context::mime_types.
+    // Missing files and directories are 404s, unreadable ones 403s.
+    pub fn file(path: impl AsRef<Path>, types: &MimeTypes) -> Result<Self, HttpError> {
+        let path = path.as_ref();
+        let status = |err: io::Error| match err.kind() {
+            io::ErrorKind::PermissionDenied => HttpError(HttpStatus::Forbidden),
+            _ => HttpError(HttpStatus::NotFound),
+        };
+        let file = File::open(path).map_err(status)?;
+        let metadata = file.metadata().map_err(status)?;
+        if metadata.is_dir() {
+            return Err(HttpError(HttpStatus::NotFound));
+        }
+        let headers = vec![
+            ("content-length".to_string(), metadata.len().to_string()),
+            ("content-type".to_string(), types.get(path).to_string()),
+        ];
+        let mut resp =
+            Response { status: HttpStatus::OK, body: Some(ResponseBody::File(file)), headers };
+        // weak, since a file can change within the same second
+        if let Ok(modified) = metadata.modified() {
+            if let Ok(since_epoch) = modified.duration_since(UNIX_EPOCH) {
+                let tag = format!("{:x}-{:x}", metadata.len(), since_epoch.as_secs());
+                resp.set_header("etag".to_string(), EntityTag { weak: true, tag }.to_string());
+            }
+            resp.set_header("last-modified".to_string(), http_date(modified));
+        }
+        Ok(resp)
+    }
+
+    // Like Response::file for |path| relative to |root|, but a 404 for any
+    // path that would leave it, whether through .. or a symlink.
+    pub fn send_file(
+        root: &Path,
+        path: impl AsRef<Path>,
+        types: &MimeTypes,
+    ) -> Result<Self, HttpError> {
+        let not_found = |_| HttpError(HttpStatus::NotFound);
+        if !path.as_ref().components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(HttpError(HttpStatus::NotFound));
+        }
+        let root = root.canonicalize().map_err(not_found)?;
+        let resolved = root.join(path).canonicalize().map_err(not_found)?;
+        if !resolved.starts_with(&root) {
+            return Err(HttpError(HttpStatus::NotFound));
+        }
+        Self::file(resolved, types)
+    }
+
+    // body of unknown length, sent with chunked transfer encoding
+    pub fn chunked(data: Box<dyn Read>) -> Self {
+        let headers = vec![("content-type".to_string(), "application/octet-stream".to_string())];
+        Response { status: HttpStatus::OK, body: Some(data.into()), headers }
+    }
+
+    // A chunked body written by |write| once the head has gone out. Small
+    // writes are gathered into larger chunks; flush to send what's buffered.
+    pub fn from_writer<F>(write: F) -> Self
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()> + 'static,
+    {
+        Self::builder().header("content-type", "application/octet-stream").writer(write)
+    }
+
+    // A body produced piece by piece, e.g. a long export, sent with chunked
+    // encoding as each piece arrives. An error ends the response early.
+    pub fn stream<I>(chunks: I) -> Self
+    where
+        I: IntoIterator<Item = io::Result<Vec<u8>>>,
+        I::IntoIter: 'static,
+    {
+        let reader = ChunkReader { chunks: chunks.into_iter(), chunk: Vec::new(), pos: 0 };
+        Self::chunked(Box::new(reader))
+    }
+
+    // Like stream, with the pieces sent over a channel; the body ends when
+    // every sender is dropped.
+    pub fn from_channel(receiver: mpsc::Receiver<Vec<u8>>) -> Self {
+        Self::stream(receiver.into_iter().map(Ok))
+    }
+
+    pub fn bytes(data: Bytes) -> Self {
+        let headers = vec![
+            ("content-length".to_string(), data.len().to_string()),
+            ("content-type".to_string(), "application/octet-stream".to_string()),
+        ];
+        Response { status: HttpStatus::OK, body: Some(data.into()), headers }
+    }
+
+    // 302 Found, a temporary redirect to |location|
+    pub fn redirect(location: &str) -> Self {
+        Self::redirect_with(HttpStatus::Found, location)
+    }
+
+    // 301 Moved Permanently, which clients may cache
+    pub fn redirect_permanent(location: &str) -> Self {
+        Self::redirect_with(HttpStatus::MovedPermanently, location)
+    }
+
+    // 303 See Other, sending the client on with a GET, e.g. after a form POST
+    pub fn see_other(location: &str) -> Self {
+        Self::redirect_with(HttpStatus::SeeOther, location)
+    }
+
+    // Control bytes, spaces and non-ASCII in |location| are percent-encoded,
+    // so one built from the request can't end the header or add another.
+    fn redirect_with(status: HttpStatus, location: &str) -> Self {
+        let mut encoded = String::with_capacity(location.len());
+        for b in location.bytes() {
+            match b {
+                b'!'..=b'~' => encoded.push(b as char),
+                _ => encoded.push_str(&format!("%{:02X}", b)),
+            }
+        }
+        Self::builder().status(status).header("location", encoded).build()
     }
 
     pub fn created() -> Self {
         Response { status: HttpStatus::Created, body: None, headers: Vec::new() }
     }
 
-    pub fn plain_text(text: String) -> Self {
+    // Takes a String, or a &'static str that's sent without being copied.
+    pub fn plain_text(text: impl Into<Cow<'static, str>>) -> Self {
+        let text = text.into();
         let headers = vec![
             ("content-length".to_string(), text.len().to_string()),
             ("content-type".to_string(), "text/plain".to_string()),
         ];
-        let data = Box::new(Cursor::new(text.into_bytes()));
-        Response { status: HttpStatus::OK, body: Some(data), headers }
+        Response { status: HttpStatus::OK, body: Some(text.into()), headers }
+    }
+}
+
+// attachment; filename="..." with an ASCII stand-in for the name, plus the
+// exact name in RFC 5987 encoding when they differ.
+fn content_disposition(filename: &str) -> String {
+    let fallback: String =
+        filename
+            .chars()
+            .map(|c| {
+                if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+    let mut value = format!("attachment; filename=\"{}\"", fallback);
+    if fallback != filename {
+        value.push_str("; filename*=UTF-8''");
+        for b in filename.bytes() {
+            match b {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => value.push(b as char),
+                b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|'
+                | b'~' => value.push(b as char),
+                _ => value.push_str(&format!("%{:02X}", b)),
+            }
+        }
+    }
+    value
+}
+
+// Reads the pieces of a Response::stream body one after another.
+struct ChunkReader<I> {
+    chunks: I,
+    chunk: Vec<u8>,
+    // how much of |chunk| has been read
+    pos: usize,
+}
+
+impl<I: Iterator<Item = io::Result<Vec<u8>>>> Read for ChunkReader<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.chunks.next() {
+                Some(chunk) => (self.chunk, self.pos) = (chunk?, 0),
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+// Builds a Response with any status and headers, see Response::builder. A
+// header that can't be sent, like a value holding a line break, turns the
+// finished response into an empty 500, so it can't smuggle in other headers.
+pub struct ResponseBuilder(Response, Option<String>);
+
+impl ResponseBuilder {
+    pub fn status(mut self, status: HttpStatus) -> Self {
+        self.0.status = status;
+        self
+    }
+
+    // Adds a header, keeping earlier ones of the same name, as Set-Cookie needs.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        let (name, value) = (name.into(), value.into());
+        if !is_valid_header(&name, &value) {
+            self.1.get_or_insert(name);
+            return self;
+        }
+        self.0.append_header(name, value);
+        self
+    }
+
+    // Finishes the response with |data| as its body and a matching Content-Length.
+    pub fn body(mut self, data: impl Into<Bytes>) -> Response {
+        let data = data.into();
+        self.0.set_header("content-length".to_string(), data.len().to_string());
+        self.0.body = Some(data.into());
+        self.build()
+    }
+
+    // Finishes the response with a body of unknown length, sent chunked.
+    pub fn stream(mut self, data: Box<dyn Read>) -> Response {
+        self.0.remove_header("content-length");
+        self.0.body = Some(data.into());
+        self.build()
+    }
+
+    // Finishes the response with a body written by |write|, sent chunked.
+    pub fn writer<F>(mut self, write: F) -> Response
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()> + 'static,
+    {
+        self.0.remove_header("content-length");
+        self.0.body = Some(ResponseBody::Writer(Box::new(write)));
+        self.build()
+    }
+
+    // Finishes the response without a body.
+    pub fn build(self) -> Response {
+        match self.1 {
+            Some(name) => {
+                eprintln!("error: response header {:?} can't be sent, answering 500", name);
+                let mut resp = Response::empty();
+                resp.status = HttpStatus::ServerError;
+                resp
+            }
+            None => self.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::TempDir;
+
+    fn parse(raw: &str, normalize_headers: bool) -> Result<Vec<(String, String)>, HttpStatus> {
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+        let options = ParseOptions { normalize_headers, ..ParseOptions::default() };
+        let req = parse_request_with(&mut reader, options).map_err(|err| err.status())?;
+        Ok(req.headers().cloned().collect())
+    }
+
+    #[test]
+    fn test_normalize_headers() {
+        let raw = "GET / HTTP/1.1\r\nHost: a\r\nAccept: text/html\r\nACCEPT: text/plain\r\n\
+                   Cookie: a=1\r\ncookie: b=2\r\n\r\n";
+        let headers = parse(raw, true).unwrap();
+        let expected = [("host", "a"), ("accept", "text/html, text/plain"), ("cookie", "a=1; b=2")];
+        assert_eq!(headers, expected.map(|(k, v)| (k.to_string(), v.to_string())));
+        assert_eq!(parse(raw, false).unwrap().len(), 5);
+
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+        let options = ParseOptions { normalize_headers: true, ..ParseOptions::default() };
+        let req = parse_request_with(&mut reader, options).unwrap();
+        assert_eq!(req.original_headers().nth(1), Some(("Accept", "text/html, text/plain")));
+
+        let raw = "GET / HTTP/1.1\r\nHost: a\r\nhost: b\r\n\r\n";
+        assert_eq!(parse(raw, true), Err(HttpStatus::BadRequest));
+        assert!(parse(raw, false).is_ok());
+    }
+
+    #[test]
+    fn test_decode_body() {
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhellonext";
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+        let mut req = parse_request(&mut reader).unwrap();
+        req.body.decode(|body| Ok(body.take(3)), 3).unwrap();
+        assert_eq!(req.text().unwrap(), "hel");
+        let abandoned = req.body.abandoned();
+        drop(req);
+        // the encoded body was drained, decoded or not
+        assert!(!abandoned.load(Ordering::SeqCst));
+        assert_eq!(reader.position(), 43);
+
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+        let mut req = parse_request(&mut reader).unwrap();
+        req.body.decode(|body| Ok(body.take(3)), 2).unwrap();
+        assert!(req.body.read(&mut [0; 8]).is_err());
+
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+        let mut req = parse_request(&mut reader).unwrap();
+        let failed: io::Result<()> =
+            req.body.decode(|_| Err::<io::Empty, _>(io::Error::other("no decoder")), 1 << 20);
+        assert!(failed.is_err());
+        assert_eq!(req.text().unwrap(), "");
+        drop(req);
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "next");
+    }
+
+    #[test]
+    fn test_chunked_body() {
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                   5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nX-Trailer: 1\r\n\r\nnext";
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+        let mut req = parse_request(&mut reader).unwrap();
+        assert_eq!(req.text().unwrap(), "hello, world");
+        drop(req);
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "next");
+
+        for raw in [
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\nhello\r\n0\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhelloX\r\n0\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel",
+        ] {
+            let mut reader = Cursor::new(raw.as_bytes().to_vec());
+            let mut req = parse_request(&mut reader).unwrap();
+            assert_eq!(req.bytes().unwrap_err().0, HttpStatus::BadRequest);
+        }
+
+        // a body too long to drain is left, and the connection with it
+        let body = " ".repeat(300000);
+        let raw = format!("POST / HTTP/1.1\r\nContent-Length: 300000\r\n\r\n{}", body);
+        let mut reader = Cursor::new(raw.into_bytes());
+        let req = parse_request(&mut reader).unwrap();
+        let abandoned = req.body.abandoned();
+        drop(req);
+        assert!(abandoned.load(Ordering::SeqCst));
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+        let req = parse_request(&mut reader).unwrap();
+        let abandoned = req.body.abandoned();
+        drop(req);
+        assert!(!abandoned.load(Ordering::SeqCst));
+
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n";
+        let mut reader = Cursor::new(raw.as_bytes().to_vec());
+        let status = parse_request(&mut reader).err().unwrap().status();
+        assert_eq!(status, HttpStatus::NotImplemented);
+    }
+
+    #[test]
+    fn test_max_body() {
+        let options = ParseOptions { max_body_bytes: 4, ..ParseOptions::default() };
+        let body = |raw: &str| {
+            let mut reader = Cursor::new(raw.as_bytes().to_vec());
+            let body = parse_request_with(&mut reader, options).unwrap().bytes();
+            body.map_err(|e| e.0)
+        };
+        assert_eq!(body("POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nabcd").unwrap(), "abcd");
+        let too_large = Err(HttpStatus::ContentTooLarge);
+        assert_eq!(body("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nabcde"), too_large);
+        let chunked =
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nabcde\r\n0\r\n\r\n";
+        assert_eq!(body(chunked), too_large);
+
+        // trailers share the header block's limit
+        let options = ParseOptions { max_header_bytes: 64, ..ParseOptions::default() };
+        let trailers = |n: usize| {
+            let raw = format!(
+                "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n{}\r\n",
+                "X-Trailer: 1\r\n".repeat(n)
+            );
+            let mut reader = Cursor::new(raw.into_bytes());
+            let body = parse_request_with(&mut reader, options).unwrap().bytes();
+            body.map_err(|e| e.0)
+        };
+        assert!(trailers(1).is_ok());
+        assert_eq!(trailers(10), Err(HttpStatus::BadRequest));
+    }
+
+    #[test]
+    fn test_normalize_target() {
+        let normalize = |target: &str| normalize_target(target).map_err(|e| e.status());
+        let ok = |path: &str, query: Option<&str>| Ok((path.to_string(), query.map(String::from)));
+        assert_eq!(normalize("/echo/hello%20world"), ok("/echo/hello world", None));
+        assert_eq!(normalize("/files/../etc/passwd"), ok("/etc/passwd", None));
+        assert_eq!(normalize("/../../a"), ok("/a", None));
+        assert_eq!(normalize("/a/./b/.."), ok("/a/", None));
+        assert_eq!(normalize("/a/%2e%2E/b"), ok("/b", None));
+        assert_eq!(normalize("/a%2Fb?x=%20&y"), ok("/a%2Fb", Some("x=%20&y")));
+        assert_eq!(normalize("/"), ok("/", None));
+        assert_eq!(normalize("*"), ok("*", None));
+        assert_eq!(normalize("/%zz"), Err(HttpStatus::BadRequest));
+        assert_eq!(normalize("/%00"), Err(HttpStatus::BadRequest));
+        assert_eq!(normalize("/%ff"), Err(HttpStatus::BadRequest));
+        assert_eq!(normalize("/100%25%2F"), ok("/100%25%2F", None));
+        assert_eq!(normalize("/%252F"), ok("/%252F", None));
+        assert_eq!(normalize("a/b"), Err(HttpStatus::BadRequest));
+        assert_eq!(normalize(""), Err(HttpStatus::BadRequest));
+
+        let mut reader = Cursor::new(b"GET /a/../b%21?q=1 HTTP/1.1\r\n\r\n".to_vec());
+        let req = parse_request(&mut reader).unwrap();
+        assert_eq!((req.path.as_str(), req.raw_target.as_str()), ("/b!", "/a/../b%21?q=1"));
+    }
+
+    #[test]
+    fn test_absolute_form() {
+        let parse = |raw: &str| {
+            let mut reader = Cursor::new(raw.as_bytes().to_vec());
+            let req = parse_request(&mut reader).map_err(|e| e.status())?;
+            let host = req.get_header("host").map(String::from);
+            Ok::<_, HttpStatus>((req.path.clone(), req.query.clone(), host, req.raw_target.clone()))
+        };
+        let (path, query, host, raw) =
+            parse("GET http://example.com:8080/a/../b?x=1 HTTP/1.1\r\nHost: other\r\n\r\n")
+                .unwrap();
+        assert_eq!((path.as_str(), query.as_deref()), ("/b", Some("x=1")));
+        assert_eq!(host.as_deref(), Some("example.com:8080"));
+        assert_eq!(raw, "http://example.com:8080/a/../b?x=1");
+        let (path, query, host, _) = parse("GET HTTPS://example.com?q HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!((path.as_str(), query.as_deref()), ("/", Some("q")));
+        assert_eq!(host.as_deref(), Some("example.com"));
+
+        for bad in ["http:/x", "http:///x", "http://user@host/x", "http//host/x"] {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", bad);
+            assert_eq!(parse(&raw), Err(HttpStatus::BadRequest), "{}", bad);
+        }
+        // other schemes are refused on purpose, and say so
+        for scheme in ["ftp://host/x", "urn:isbn:0451450523", "git+ssh://host/x"] {
+            let raw = format!("GET {} HTTP/1.1\r\n\r\n", scheme);
+            let status = parse(&raw).unwrap_err();
+            assert_eq!(
+                (status.code(), status.reason()),
+                (400, "Unsupported URI Scheme"),
+                "{}",
+                scheme
+            );
+        }
+    }
+
+    #[test]
+    fn test_lenient_parsing() {
+        let parse = |raw: &str, lenient: bool| {
+            let mut reader = Cursor::new(raw.as_bytes().to_vec());
+            let options = ParseOptions { lenient, ..ParseOptions::default() };
+            let req = parse_request_with(&mut reader, options).map_err(|e| e.status())?;
+            assert_eq!(req.quirks().is_empty(), !lenient);
+            Ok::<_, HttpStatus>((
+                req.path.clone(),
+                req.query.clone(),
+                req.get_header("host").map(String::from),
+            ))
+        };
+        let bare_lf = "GET /a HTTP/1.1\nHost: x\n\n";
+        assert_eq!(parse(bare_lf, false), Err(HttpStatus::BadRequest));
+        assert_eq!(parse(bare_lf, true), Ok(("/a".into(), None, Some("x".into()))));
+        let mixed = "GET /a HTTP/1.1\r\nHost: x\n\r\n";
+        assert_eq!(parse(mixed, true), Ok(("/a".into(), None, Some("x".into()))));
+
+        let spaces = "GET /my file.txt?v=1 HTTP/1.1\r\n\r\n";
+        assert_eq!(parse(spaces, false), Err(HttpStatus::BadRequest));
+        assert_eq!(parse(spaces, true), Ok(("/my file.txt".into(), Some("v=1".into()), None)));
+        for bad in ["GET /a?b c HTTP/1.1\r\n\r\n", "GET a b HTTP/1.1\r\n\r\n", "GET /a\r\n\r\n"] {
+            assert_eq!(parse(bad, true), Err(HttpStatus::BadRequest), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_header_limits() {
+        let options = ParseOptions {
+            max_header_bytes: 64,
+            max_header_value_bytes: 16,
+            ..ParseOptions::default()
+        };
+        let parse = |raw: String| {
+            let mut reader = Cursor::new(raw.into_bytes());
+            parse_request_with(&mut reader, options).map(|_| ()).map_err(|e| e.status())
+        };
+        let cookie = |len: usize| format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "a".repeat(len));
+        assert_eq!(parse(cookie(16)), Ok(()));
+        assert_eq!(parse(cookie(17)), Err(HttpStatus::RequestHeaderFieldsTooLarge));
+        // each header takes 13 bytes with its CRLF, so 4 fit in 64 bytes and 5 don't
+        let many = |n: usize| format!("GET / HTTP/1.1\r\n{}\r\n", "X-Header: 1\r\n".repeat(n));
+        assert_eq!(parse(many(4)), Ok(()));
+        assert_eq!(parse(many(5)), Err(HttpStatus::RequestHeaderFieldsTooLarge));
+        let target = |len: usize| format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(len));
+        assert_eq!(parse(target(50)), Ok(()));
+        assert_eq!(parse(target(51)), Err(HttpStatus::UriTooLong));
+        // cut off mid-line is still malformed rather than too large
+        assert_eq!(parse("GET / HTTP/1.1\r\nCookie: a".to_string()), Err(HttpStatus::BadRequest));
+    }
+
+    #[test]
+    fn test_response_builder() {
+        let mut resp = Response::builder()
+            .status(HttpStatus::Accepted)
+            .header("Set-Cookie", "a=1")
+            .header("set-cookie", "b=2".to_string())
+            .header("Content-Type", "application/json")
+            .body("{}");
+        assert_eq!(resp.status, HttpStatus::Accepted);
+        let cookies: Vec<_> =
+            resp.headers().filter(|(k, _)| k == "set-cookie" || k == "Set-Cookie").collect();
+        assert_eq!(cookies.len(), 2);
+        assert_eq!(resp.get_header("content-length"), Some("2"));
+        assert_eq!(resp.body.take().unwrap().into_bytes().unwrap(), b"{}");
+        let resp = Response::builder().status(HttpStatus::NoContent).build();
+        assert!(resp.body.is_none() && resp.headers().next().is_none());
+    }
+
+    #[test]
+    fn test_redirects() {
+        let cases = [
+            (Response::redirect("/new"), HttpStatus::Found),
+            (Response::redirect_permanent("/new"), HttpStatus::MovedPermanently),
+            (Response::see_other("/new"), HttpStatus::SeeOther),
+        ];
+        for (resp, status) in cases {
+            assert_eq!(resp.status, status);
+            assert_eq!(resp.get_header("location"), Some("/new"));
+            assert!(resp.body.is_none());
+        }
+        let resp = Response::redirect("/a b\r\nSet-Cookie: x=1/é?q=%41");
+        assert_eq!(resp.get_header("location"), Some("/a%20b%0D%0ASet-Cookie:%20x=1/%C3%A9?q=%41"));
+        assert_eq!(resp.headers().count(), 1);
+    }
+
+    #[test]
+    fn test_builder_invalid_header() {
+        let resp = Response::builder().header("x-a", "1\r\nx-b: 2").body("hello");
+        assert_eq!(resp.status, HttpStatus::ServerError);
+        assert_eq!(resp.headers().count(), 0);
+        assert!(resp.body.is_none());
+        let resp = Response::builder().header("x a", "1").stream(Box::new(io::empty()));
+        assert_eq!(resp.status, HttpStatus::ServerError);
+        let resp = Response::builder().header("x-a", "1\t2").build();
+        assert_eq!(resp.get_header("x-a"), Some("1\t2"));
+    }
+
+    #[test]
+    fn test_static_body() {
+        static PAGE: &[u8] = b"<h1>Not Found</h1>";
+        let resp = Response::builder().body(PAGE);
+        let Some(ResponseBody::Bytes(body)) = &resp.body else { panic!("not in memory") };
+        // sent from the static itself, not a copy
+        assert_eq!(body.as_ptr(), PAGE.as_ptr());
+        assert_eq!(resp.get_header("content-length"), Some("18"));
+
+        let resp = Response::plain_text("canned");
+        let Some(ResponseBody::Bytes(body)) = &resp.body else { panic!("not in memory") };
+        assert_eq!(&body[..], b"canned");
+        let text = Cow::Owned("owned".to_string());
+        assert_eq!(Response::plain_text(text).body.unwrap().into_bytes().unwrap(), b"owned");
+        let body = ResponseBody::from(Cow::Borrowed("borrowed"));
+        assert_eq!(body.into_bytes().unwrap(), b"borrowed");
+    }
+
+    #[test]
+    fn test_writer_body() {
+        let resp = Response::from_writer(|w| write!(w, "a-{}", 1));
+        assert_eq!(resp.get_header("content-length"), None);
+        assert!(matches!(resp.body, Some(ResponseBody::Writer(_))));
+        assert_eq!(resp.body.unwrap().into_bytes().unwrap(), b"a-1");
+        let failing = Response::from_writer(|_| Err(io::ErrorKind::BrokenPipe.into()));
+        assert!(failing.body.unwrap().into_bytes().is_err());
+    }
+
+    #[test]
+    fn test_file_response() {
+        let dir = TempDir::new("file-response").unwrap();
+        std::fs::write(dir.join("page.html"), "<p>hi</p>").unwrap();
+
+        let types = MimeTypes::default();
+        let resp = Response::file(dir.join("page.html"), &types).unwrap();
+        assert_eq!(resp.get_header("content-type"), Some("text/html"));
+        assert_eq!(resp.get_header("content-length"), Some("9"));
+        assert_eq!(resp.body.unwrap().into_bytes().unwrap(), b"<p>hi</p>");
+        let status = |path: std::path::PathBuf| match Response::file(path, &types) {
+            Err(HttpError(status)) => status,
+            Ok(_) => HttpStatus::OK,
+        };
+        assert_eq!(status(dir.join("missing.txt")), HttpStatus::NotFound);
+        assert_eq!(status(dir.to_path_buf()), HttpStatus::NotFound);
+        assert_eq!(status(dir.join("page.html/x")), HttpStatus::NotFound);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_send_file() {
+        let base = TempDir::new("send-file").unwrap();
+        let root = base.join("root");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub/a.css"), "p{}").unwrap();
+        std::fs::write(base.join("secret.txt"), "no").unwrap();
+        std::os::unix::fs::symlink(base.join("secret.txt"), root.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink(root.join("sub/a.css"), root.join("inside.css")).unwrap();
+
+        let types = MimeTypes::default();
+        let resp = Response::send_file(&root, "sub/a.css", &types).unwrap();
+        assert_eq!(resp.get_header("content-type"), Some("text/css"));
+        assert_eq!(resp.get_header("content-length"), Some("3"));
+        assert!(resp.get_header("etag").is_some_and(|tag| tag.starts_with("W/\"3-")));
+        assert!(resp.get_header("last-modified").is_some_and(|date| date.ends_with(" GMT")));
+        assert!(Response::send_file(&root, "inside.css", &types).is_ok());
+        let status = |path: &str| match Response::send_file(&root, path, &types) {
+            Err(HttpError(status)) => status,
+            Ok(_) => HttpStatus::OK,
+        };
+        for path in ["../secret.txt", "sub/../../secret.txt", "link.txt", "/etc/passwd", "sub"] {
+            assert_eq!(status(path), HttpStatus::NotFound, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_attachment() {
+        let resp = Response::attachment("report.csv", Box::new(Cursor::new("a,b")), 3);
+        assert_eq!(
+            resp.get_header("content-disposition"),
+            Some(r#"attachment; filename="report.csv""#)
+        );
+        assert_eq!(resp.get_header("content-length"), Some("3"));
+        assert_eq!(resp.get_header("content-type"), Some("application/octet-stream"));
+        let types = MimeTypes::new(&[("csv".to_string(), "text/csv; charset=utf-8".to_string())]);
+        let resp = resp.with_type_of("report.csv", &types);
+        assert_eq!(resp.get_header("content-type"), Some("text/csv; charset=utf-8"));
+        let resp = Response::binary(Box::new(Cursor::new("{}")), 2).with_type_of("a.json", &types);
+        assert_eq!(resp.get_header("content-type"), Some("application/json"));
+        assert_eq!(
+            content_disposition("naïve \"plan\".txt"),
+            r#"attachment; filename="na_ve _plan_.txt"; filename*=UTF-8''na%C3%AFve%20%22plan%22.txt"#
+        );
     }
 }
@@ -0,0 +1,267 @@
+use std::{
+    fmt::Write,
+    sync::mpsc::{self, TrySendError},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use reqwest::blocking::Request;
+use serde_json::json;
+use sha2::Sha256;
+
+use crate::{
+    audit, Client, ClientError, ClientMiddleware, Context, Exchange, MiddlewareError, TraceContext,
+    TracePropagation,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileAction {
+    Uploaded,
+    Overwritten,
+    Deleted,
+}
+
+impl FileAction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Uploaded => "file.uploaded",
+            Self::Overwritten => "file.overwritten",
+            Self::Deleted => "file.deleted",
+        }
+    }
+}
+
+// A change made through the file routes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEvent {
+    pub action: FileAction,
+    // the request path, e.g. /files/a.txt
+    pub path: String,
+    pub size: Option<u64>,
+    // the trace of the request that made the change, sent as traceparent
+    pub trace: Option<TraceContext>,
+}
+
+impl FileEvent {
+    // |time| is when the change was made, however long delivery took
+    fn to_json(&self, time: SystemTime) -> String {
+        let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut json = json!({"event": self.action.as_str(), "path": self.path, "time": secs});
+        if let Some(size) = self.size {
+            json["size"] = size.into();
+        }
+        json.to_string()
+    }
+}
+
+// Posts FileEvents as JSON to a URL from a background thread, so uploads
+// don't wait on the receiver. With a secret, each body is signed with
+// HMAC-SHA256 in an X-Webhook-Signature: sha256=<hex> header. Failed
+// deliveries are retried with exponential backoff, then dropped. At most
+// |queue| events wait for delivery; more are dropped, so a receiver that's
+// down can't make the server hold on to events without bound. Deliveries go
+// through |client|'s middleware before being signed.
+pub struct Webhook {
+    sender: mpsc::SyncSender<(FileEvent, SystemTime)>,
+}
+
+impl Webhook {
+    pub fn start(
+        url: String,
+        secret: Option<String>,
+        retries: u32,
+        backoff: Duration,
+        queue: usize,
+        mut client: Client,
+    ) -> Self {
+        client = client.middleware(TracePropagation);
+        if let Some(secret) = secret {
+            client = client.middleware(Signature(secret));
+        }
+        let (sender, receiver) = mpsc::sync_channel::<(FileEvent, SystemTime)>(queue);
+        thread::spawn(move || {
+            // ends once the Webhook, and so the sender, is dropped
+            for (event, time) in receiver {
+                let body = event.to_json(time);
+                let mut delay = backoff;
+                for attempt in 0..=retries {
+                    let req = client
+                        .inner()
+                        .post(&url)
+                        .header("content-type", "application/json")
+                        .body(body.clone())
+                        .build();
+                    // TracePropagation sends the trace of the request that
+                    // made the change
+                    let _current = event.trace.as_ref().map(TraceContext::enter);
+                    let sent = req.map_err(ClientError::Send).and_then(|req| client.send(req));
+                    match sent {
+                        Ok(resp) if resp.status().is_success() => break,
+                        Ok(resp) => eprintln!("webhook {} answered {}", url, resp.status()),
+                        Err(err) => eprintln!("webhook {} failed: {}", url, err),
+                    }
+                    if attempt == retries {
+                        eprintln!("error: giving up on webhook for {}", event.path);
+                    } else {
+                        thread::sleep(delay);
+                        delay *= 2;
+                    }
+                }
+            }
+        });
+        Self { sender }
+    }
+
+    pub fn notify(&self, event: FileEvent) {
+        match self.sender.try_send((event, SystemTime::now())) {
+            Ok(()) => {}
+            Err(TrySendError::Full((event, _))) => {
+                eprintln!("error: webhook queue full, dropping event for {}", event.path)
+            }
+            // the delivery thread only exits once we're dropped
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+// Signs each request's body with HMAC-SHA256 in X-Webhook-Signature.
+struct Signature(String);
+
+impl ClientMiddleware for Signature {
+    fn apply_before(&self, req: &mut Request) -> Result<(), MiddlewareError> {
+        let body = req.body().and_then(|body| body.as_bytes()).unwrap_or_default();
+        let signature = hex(&sign(self.0.as_bytes(), body));
+        req.headers_mut().insert("x-webhook-signature", format!("sha256={}", signature).parse()?);
+        Ok(())
+    }
+
+    fn apply_after(&self, _exchange: &Exchange) {}
+}
+
+// HMAC-SHA256 of |data| under |key|
+fn sign(key: &[u8], data: &[u8]) -> Vec<u8> {
+    // HMAC takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().fold(String::new(), |mut s, b| {
+        write!(s, "{:02x}", b).unwrap();
+        s
+    })
+}
+
+impl Context {
+    // Reports a file change to the webhook, if one is configured, and to the
+    // audit log if the request is audited.
+    pub fn file_changed(&self, action: FileAction, path: &str, size: Option<u64>) {
+        audit::file_changed(path, size);
+        if let Some(webhook) = &self.webhook {
+            let trace = TraceContext::current();
+            webhook.notify(FileEvent { action, path: path.to_owned(), size, trace });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{HttpStatus, MockUpstream, RecordedRequest, Reply};
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            hex(&sign(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    fn event(path: &str) -> FileEvent {
+        FileEvent {
+            action: FileAction::Uploaded,
+            path: path.to_string(),
+            size: Some(5),
+            trace: None,
+        }
+    }
+
+    // the requests |upstream| has received once there are |n| of them
+    fn wait_for(upstream: &MockUpstream, n: usize) -> Vec<RecordedRequest> {
+        let mut requests = Vec::new();
+        for _ in 0..200 {
+            requests = upstream.requests();
+            if requests.len() >= n {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        requests
+    }
+
+    #[test]
+    fn test_webhook() {
+        let upstream =
+            MockUpstream::start(vec![Reply::status(HttpStatus::ServerError), Reply::ok("")]);
+        let webhook = Webhook::start(
+            upstream.url("/hook"),
+            Some("s3cret".to_string()),
+            2,
+            Duration::from_millis(10),
+            16,
+            Client::new(reqwest::blocking::Client::new()),
+        );
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        webhook.notify(FileEvent {
+            action: FileAction::Uploaded,
+            path: "/files/\"a\".txt".to_string(),
+            size: Some(5),
+            trace: TraceContext::child_of(
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+                None,
+            ),
+        });
+        let requests = wait_for(&upstream, 2);
+        // the first delivery failed and was retried
+        assert_eq!(requests.len(), 2);
+        let req = &requests[1];
+        let body: serde_json::Value = serde_json::from_slice(&req.body).unwrap();
+        assert_eq!(body["event"], "file.uploaded");
+        assert_eq!(body["path"], "/files/\"a\".txt");
+        assert_eq!(body["size"], 5);
+        // when the file changed, not when the retry went out
+        assert!((before..before + 2).contains(&body["time"].as_u64().unwrap()));
+        let signature = format!("sha256={}", hex(&sign(b"s3cret", &req.body)));
+        assert_eq!(req.get_header("x-webhook-signature"), Some(signature.as_str()));
+        assert_eq!(requests[0].body, req.body);
+        let traceparent = req.get_header("traceparent").unwrap();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+    }
+
+    #[test]
+    fn test_webhook_queue_full() {
+        let slow = Reply::ok("").delayed(Duration::from_millis(200));
+        let upstream = MockUpstream::start(vec![slow, Reply::ok(""), Reply::ok("")]);
+        let webhook = Webhook::start(
+            upstream.url("/hook"),
+            None,
+            0,
+            Duration::ZERO,
+            1,
+            Client::new(reqwest::blocking::Client::new()),
+        );
+        webhook.notify(event("/files/a"));
+        // a is being delivered, b waits its turn and c doesn't fit
+        assert_eq!(wait_for(&upstream, 1).len(), 1);
+        webhook.notify(event("/files/b"));
+        webhook.notify(event("/files/c"));
+        let requests = wait_for(&upstream, 2);
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(upstream.requests().len(), 2);
+        let body: serde_json::Value = serde_json::from_slice(&requests[1].body).unwrap();
+        assert_eq!(body["path"], "/files/b");
+    }
+}
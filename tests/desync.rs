@@ -0,0 +1,191 @@
+// Hand-crafted byte sequences sent over raw sockets, checking that the server
+// answers malformed or ambiguous framing without hanging or misattributing
+// bodies to the wrong request.
+
+use std::{
+    io::{Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use codecrafters_http_server::{
+    Config, Context, HttpStatus, Method, Request, Response, Router, Server,
+};
+
+fn start() -> Arc<Server> {
+    let router = Router::default()
+        .get("/", |_ctx: &Context, _req: Request| Ok(Response::plain_text("root".to_string())))
+        .post("/echo", |_ctx: &Context, mut req: Request| {
+            let mut body = String::new();
+            req.body.read_to_string(&mut body).map_err(|_| HttpStatus::BadRequest)?;
+            Ok(Response::plain_text(body))
+        })
+        .route(Method::Other("PROPFIND".to_string()), "/", |_ctx: &Context, _req: Request| {
+            Ok(Response::plain_text("props".to_string()))
+        });
+    let config = Config { read_timeout_ms: 2000, ..Config::default() };
+    let server = Arc::new(Server::start(config, router));
+    let server2 = Arc::clone(&server);
+    thread::spawn(move || server2.listen_forever());
+    server
+}
+
+fn connect(server: &Server) -> TcpStream {
+    let stream = TcpStream::connect(server.addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream
+}
+
+// reads until the server closes the connection; a timeout means it hung
+fn read_all(mut stream: TcpStream) -> String {
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).expect("server did not close the connection");
+    String::from_utf8_lossy(&resp).into_owned()
+}
+
+fn send(server: &Server, bytes: &[u8]) -> String {
+    let mut stream = connect(server);
+    stream.write_all(bytes).unwrap();
+    read_all(stream)
+}
+
+fn statuses(resp: &str) -> Vec<&str> {
+    resp.match_indices("HTTP/1.1 ").map(|(i, _)| &resp[i + 9..i + 12]).collect()
+}
+
+#[test]
+fn test_conflicting_framing() {
+    let server = start();
+    let resp = send(
+        &server,
+        b"POST /echo HTTP/1.1\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+    );
+    assert_eq!(statuses(&resp), ["400"]);
+    assert!(resp.contains("connection: close\r\n"));
+
+    let resp = send(
+        &server,
+        b"POST /echo HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 5\r\n\r\nhello",
+    );
+    assert_eq!(statuses(&resp), ["400"]);
+
+    let resp = send(&server, b"POST /echo HTTP/1.1\r\nContent-Length: +3\r\n\r\nabc");
+    assert_eq!(statuses(&resp), ["400"]);
+
+    // repeating the same length is allowed
+    let resp = send(
+        &server,
+        b"POST /echo HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 3\r\nConnection: close\r\n\r\nabc",
+    );
+    assert_eq!(statuses(&resp), ["200"]);
+    assert!(resp.ends_with("\r\n\r\nabc"));
+}
+
+#[test]
+fn test_malformed_headers() {
+    let server = start();
+    let resp = send(&server, b"GET / HTTP/1.1\r\nX-Folded: a\r\n b\r\n\r\n");
+    assert_eq!(statuses(&resp), ["400"]);
+
+    let resp = send(&server, b"GET / HTTP/1.1\r\nBad Name: a\r\n\r\n");
+    assert_eq!(statuses(&resp), ["400"]);
+
+    let resp = send(&server, b"GET /a\rb HTTP/1.1\r\n\r\n");
+    assert_eq!(statuses(&resp), ["400"]);
+
+    // optional whitespace around the value is fine
+    let resp = send(&server, b"GET / HTTP/1.1\r\nHost:example.com\r\nConnection: close \r\n\r\n");
+    assert_eq!(statuses(&resp), ["200"]);
+}
+
+#[test]
+fn test_pipelined_garbage() {
+    let server = start();
+    let resp = send(&server, b"GET / HTTP/1.1\r\n\r\nGARBAGE\r\n\r\n");
+    assert_eq!(statuses(&resp), ["200", "400"]);
+}
+
+#[test]
+fn test_pipelined_bodies() {
+    let server = start();
+    let resp = send(
+        &server,
+        b"POST /echo HTTP/1.1\r\nContent-Length: 18\r\n\r\nGET / HTTP/1.1\r\n\r\n\
+          GET / HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(statuses(&resp), ["200", "200"]);
+    assert!(resp.contains("\r\n\r\nGET / HTTP/1.1\r\n\r\n"));
+    assert!(resp.ends_with("\r\n\r\nroot"));
+}
+
+#[test]
+fn test_split_crlf() {
+    let server = start();
+    let mut stream = connect(&server);
+    stream.set_nodelay(true).unwrap();
+    for chunk in [&b"GET / HTTP/1.1\r"[..], b"\nConnection: close\r", b"\n\r", b"\n"] {
+        stream.write_all(chunk).unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+    let resp = read_all(stream);
+    assert_eq!(statuses(&resp), ["200"]);
+}
+
+#[test]
+fn test_premature_close() {
+    let server = start();
+
+    // headers cut off mid-line
+    let mut stream = connect(&server);
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: exa").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(statuses(&read_all(stream)), ["400"]);
+
+    // body shorter than its content-length
+    let mut stream = connect(&server);
+    stream.write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let resp = read_all(stream);
+    assert!(!resp.contains("200"));
+
+    // the server is still serving
+    let resp = send(&server, b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n");
+    assert_eq!(statuses(&resp), ["200"]);
+}
+
+#[test]
+fn test_pipelined_chunked_body() {
+    let server = start();
+    let resp = send(
+        &server,
+        b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+          3\r\nabc\r\n0\r\n\r\n\
+          GET / HTTP/1.1\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(statuses(&resp), ["200", "200"]);
+    assert!(resp.contains("\r\n\r\nabc"));
+    assert!(resp.ends_with("\r\n\r\nroot"));
+}
+
+#[test]
+fn test_oversized_cookie() {
+    let server = start();
+    let cookie = format!("a={}", "x".repeat(20 << 10));
+    let req = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\nGET / HTTP/1.1\r\n\r\n", cookie);
+    // the connection is closed, the pipelined request never runs
+    assert_eq!(statuses(&send(&server, req.as_bytes())), ["431"]);
+}
+
+#[test]
+fn test_extension_methods() {
+    let server = start();
+    let status = |req: &str| statuses(&send(&server, req.as_bytes()))[0].to_string();
+    assert_eq!(status("BREW / HTTP/1.1\r\nConnection: close\r\n\r\n"), "501");
+    // methods are case-sensitive
+    assert_eq!(status("get / HTTP/1.1\r\nConnection: close\r\n\r\n"), "501");
+    assert_eq!(status("M-SEARCH * HTTP/1.1\r\nConnection: close\r\n\r\n"), "501");
+    assert_eq!(status("PROPFIND / HTTP/1.1\r\nConnection: close\r\n\r\n"), "200");
+    assert_eq!(status("GE(T / HTTP/1.1\r\n\r\n"), "400");
+}
@@ -0,0 +1,158 @@
+// Property tests for route dispatch over generated route sets and paths. The
+// seed is fixed so failures reproduce across runs and machines.
+
+use codecrafters_http_server::{Context, HttpError, Method, Request, Response, Router};
+use proptest::{prelude::*, test_runner::RngSeed};
+
+fn ok(_ctx: &Context, _req: Request) -> Result<Response, HttpError> {
+    Ok(Response::empty())
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    Param,
+    Wildcard,
+}
+
+// a route pattern, with parameters named after their position
+fn pattern(segments: &[Segment]) -> String {
+    let mut pat = String::new();
+    for (i, segment) in segments.iter().enumerate() {
+        pat.push('/');
+        match segment {
+            Segment::Literal(s) => pat.push_str(s),
+            Segment::Param => pat.push_str(&format!(":p{}", i)),
+            Segment::Wildcard => pat.push_str(&format!("*p{}", i)),
+        }
+    }
+    pat
+}
+
+fn segments() -> impl Strategy<Value = Vec<Segment>> {
+    let segment = prop_oneof![
+        "[ab]{1,2}".prop_map(Segment::Literal),
+        Just(Segment::Param),
+        Just(Segment::Wildcard),
+    ];
+    prop::collection::vec(segment, 1..4).prop_map(|mut segments| {
+        // wildcards may only come last
+        let last = segments.len() - 1;
+        for segment in &mut segments[..last] {
+            if let Segment::Wildcard = segment {
+                *segment = Segment::Param;
+            }
+        }
+        segments
+    })
+}
+
+fn paths() -> impl Strategy<Value = String> {
+    prop::collection::vec("[ab]{1,2}", 1..5).prop_map(|segments| format!("/{}", segments.join("/")))
+}
+
+fn router(patterns: &[String]) -> Router {
+    let mut router = Router::default();
+    for pat in patterns {
+        router = router.get(pat, ok);
+    }
+    router
+}
+
+// the pattern |path| dispatches to among |patterns|
+fn dispatch(patterns: &[&str], path: &str) -> Option<String> {
+    let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+    router(&patterns).lookup(Method::Get, path).map(|(pat, _)| pat.to_string())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig {
+        rng_seed: RngSeed::Fixed(0x5eed),
+        failure_persistence: None,
+        ..ProptestConfig::default()
+    })]
+
+    #[test]
+    fn dispatch_is_deterministic(
+        routes in prop::collection::vec(segments(), 1..8),
+        path in paths(),
+    ) {
+        let patterns: Vec<String> = routes.iter().map(|s| pattern(s)).collect();
+        let (a, b) = (router(&patterns), router(&patterns));
+        let found = a.lookup(Method::Get, &path);
+        prop_assert_eq!(&found, &a.lookup(Method::Get, &path));
+        prop_assert_eq!(&found, &b.lookup(Method::Get, &path));
+    }
+
+    #[test]
+    fn most_specific_match_wins(
+        routes in prop::collection::vec(segments(), 1..8),
+        path in paths(),
+    ) {
+        let patterns: Vec<String> = routes.iter().map(|s| pattern(s)).collect();
+        let router = router(&patterns);
+        let matching: Vec<&str> = patterns
+            .iter()
+            .map(String::as_str)
+            .filter(|p| dispatch(&[p], &path).is_some())
+            .collect();
+        let Some((winner, _)) = router.lookup(Method::Get, &path) else {
+            prop_assert!(matching.is_empty());
+            return Ok(());
+        };
+        // the winner beats each other match on its own too, whichever was
+        // registered first
+        for other in matching {
+            prop_assert_eq!(dispatch(&[winner, other], &path), Some(winner.to_string()));
+            prop_assert_eq!(dispatch(&[other, winner], &path), Some(winner.to_string()));
+        }
+    }
+
+    #[test]
+    fn literals_beat_params_beat_wildcards(
+        routes in prop::collection::vec(segments(), 1..8),
+        path in paths(),
+    ) {
+        let mut patterns: Vec<String> = routes.iter().map(|s| pattern(s)).collect();
+        let depth = path.matches('/').count();
+        let params: Vec<Segment> = (0..depth).map(|_| Segment::Param).collect();
+        patterns.push(pattern(&params));
+        patterns.push(pattern(&[Segment::Wildcard]));
+        let found = |patterns: &[String]| {
+            let patterns: Vec<&str> = patterns.iter().map(String::as_str).collect();
+            dispatch(&patterns, &path).unwrap()
+        };
+
+        // all params beat a single wildcard
+        prop_assert_eq!(found(&patterns[patterns.len() - 2..]), pattern(&params));
+        // the path itself beats everything
+        patterns.insert(0, path.clone());
+        prop_assert_eq!(found(&patterns), path);
+    }
+
+    #[test]
+    fn params_round_trip(
+        segments in segments(),
+        values in prop::collection::vec("[A-Za-z0-9._~-]{1,8}", 4),
+        tail in prop::collection::vec("[a-z]{1,3}", 1..3),
+    ) {
+        let pat = pattern(&segments);
+        let router = Router::default().get(&pat, ok).name("route");
+        let mut params = Vec::new();
+        for (i, segment) in segments.iter().enumerate() {
+            match segment {
+                Segment::Literal(_) => {}
+                Segment::Param => params.push((format!("p{}", i), values[i].clone())),
+                Segment::Wildcard => params.push((format!("p{}", i), tail.join("/"))),
+            }
+        }
+        let borrowed: Vec<(&str, &str)> =
+            params.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        let url = router.url_for("route", &borrowed).unwrap();
+        let (found, mut seen) = router.lookup(Method::Get, &url).unwrap();
+        prop_assert_eq!(found, pat.as_str());
+        seen.sort();
+        params.sort();
+        prop_assert_eq!(seen, params);
+    }
+}